@@ -1,10 +1,15 @@
 mod x86_64;
 pub use x86_64::*;
 
+use crate::memory_manager;
+
 pub fn init() {
     gdt::init();
     interrupt::init();
+    paging::pat::init();
+    memory_manager::init();
     pic::init();
     interrupt::enable();
     pci::init();
+    ata::init();
 }
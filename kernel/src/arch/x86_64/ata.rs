@@ -1,158 +1,1050 @@
-//! ATA PIO driver to communicate with hard disk
-//! Read, write and get stoage space on master drive
-//! Limited to 28bit LBA
+//! ATA PIO driver to communicate with hard disks
+//! Read, write and get stoage space on the drives of the primary and secondary buses
+//! Uses 48bit LBA when the drive supports it, 28bit LBA otherwise
+//! Transfers sleep until the drive raises its bus' IRQ, interrupts must be enabled
+//! Transfers use bus master DMA when the IDE controller and the drive support it, PIO otherwise
+//! ATAPI drives, such as CD-ROM drives, are read with PACKET commands in PIO
 
+use super::interrupt::{self, TIMER_FREQUENCY};
+use super::pci::{self, Bar, Device, DeviceClass, Function};
 use super::port;
+use crate::block_device::{self, check_blocks, partitions, BlockDevice, BlockError, Disk};
+use crate::memory_manager::{self, ALLOCATOR, PAGE_SIZE};
+use crate::serial_println;
+use crate::utils::lazy_static::LazyStatic;
+use alloc::string::String;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-const ATA_MASTER: u8 = 0xE0;
-#[allow(dead_code)]
-const ATA_SLAVE: u8 = 0xF0;
+pub const DEVICE_TYPE: DeviceClass = DeviceClass::IdeController;
 
-const DATA_PORT: u16 = 0x1F0;
-const COMMAND_PORT: u16 = 0x1F7;
-const SECTOR_COUNT: u16 = 0x1F2;
-const LBA_LOW: u16 = 0x1F3;
-const LBA_MID: u16 = 0x1F4;
-const LBA_HIGH: u16 = 0x1F5;
-const DEVICE_SELECT: u16 = 0x1F6;
+// Registers, as offsets from the I/O base of the bus
+const DATA: u16 = 0;
+/// Error register when read, features register when written
+const ERROR: u16 = 1;
+const FEATURES: u16 = 1;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DEVICE_SELECT: u16 = 6;
+const COMMAND: u16 = 7;
 
+const READ_DMA: u8 = 0xC8;
+const READ_DMA_EXT: u8 = 0x25;
+const WRITE_DMA: u8 = 0xCA;
+const WRITE_DMA_EXT: u8 = 0x35;
 const READ_PIO: u8 = 0x20;
+const READ_PIO_EXT: u8 = 0x24;
 const WRITE_PIO: u8 = 0x30;
+const WRITE_PIO_EXT: u8 = 0x34;
 const CACHE_FLUSH: u8 = 0xE7;
+const CACHE_FLUSH_EXT: u8 = 0xEA;
 const IDENTIFY: u8 = 0xEC;
+const PACKET: u8 = 0xA0;
+const IDENTIFY_PACKET: u8 = 0xA1;
+
+// SCSI commands sent in the packet of a PACKET command
+const REQUEST_SENSE: u8 = 0x03;
+const READ_CAPACITY: u8 = 0x25;
+const READ_10: u8 = 0x28;
 
 const STATUS_BUSY: u8 = 0x80;
 const STATUS_READY: u8 = 0x40;
+const STATUS_DEVICE_FAULT: u8 = 0x20;
+const STATUS_DATA_REQUEST: u8 = 0x08;
+const STATUS_ERROR: u8 = 0x01;
+/// Status read when no controller answers on the bus
+const STATUS_FLOATING: u8 = 0xFF;
 
-/// Read `sectors` sectors starting at `lba`
-/// and writes contents in `dst`
-pub fn read_sectors(lba: usize, sectors: u8, dst: &mut [u8]) {
-    assert!(sectors > 0);
-    set_up_drive(lba, sectors, ATA_MASTER);
-    // SAFETY: Drive is ready and has lba and sectors given
-    // COMMAND_PORT is a valid port
-    // READ_PIO is a valid value
-    unsafe {
-        port::outb(COMMAND_PORT, READ_PIO);
+/// Most sectors a single command transfers
+const MAX_SECTORS: usize = 256;
+const MAX_SECTORS_EXT: usize = 65536;
+
+/// Size of the sectors of ATAPI drives
+pub const ATAPI_SECTOR_SIZE: usize = 2048;
+/// Sectors a single READ(10) transfers
+const ATAPI_SECTORS: usize = 32;
+/// Most bytes an ATAPI drive transfers before it requests an interrupt
+const BYTE_COUNT_LIMIT: u16 = 16 * ATAPI_SECTOR_SIZE as u16;
+/// Sense key reported once after the medium changed, the command is then retried
+const UNIT_ATTENTION: u8 = 6;
+
+/// Bit of IDENTIFY word 83 set when the drive supports 48bit LBA
+const LBA48_SUPPORTED: u16 = 1 << 10;
+/// Bit of IDENTIFY word 49 set when the drive supports DMA
+const DMA_SUPPORTED: u16 = 1 << 8;
+
+// Bus master registers, as offsets from the bus' bus master base
+const BM_COMMAND: u16 = 0;
+const BM_STATUS: u16 = 2;
+const BM_PRD_TABLE: u16 = 4;
+
+const BM_START: u8 = 1;
+/// Direction of the transfer, set when the drive writes to memory
+const BM_READ: u8 = 1 << 3;
+const BM_STATUS_ERROR: u8 = 1 << 1;
+const BM_STATUS_INTERRUPT: u8 = 1 << 2;
+
+/// Bits of the PCI command register enabling I/O ports and bus mastering
+const PCI_IO_SPACE: u16 = 1;
+const PCI_BUS_MASTER: u16 = 1 << 2;
+
+/// Marks the last entry of a PRD table
+const PRD_END: u16 = 1 << 15;
+/// Sectors of a DMA command, their PRD entries always fit in a single page
+const DMA_SECTORS: usize = 256;
+
+/// Device control value leaving the drives' interrupts enabled
+const CONTROL_INTERRUPTS: u8 = 0;
+
+/// Ticks a drive has to complete a command
+const TIMEOUT: u64 = 5 * TIMER_FREQUENCY;
+
+/// What IDENTIFY reported for every position, indexed by `AtaDrive::index`
+static DRIVES: LazyStatic<[Option<Identity>; 4]> = LazyStatic::new(probe);
+
+/// What IDENTIFY PACKET DEVICE reported for every position, indexed by `AtaDrive::index`
+static PACKET_DRIVES: LazyStatic<[Option<Identity>; 4]> = LazyStatic::new(probe_packet);
+
+/// Set by the interrupt handler of each bus, cleared once the interrupt is waited for
+static PENDING: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+
+/// Bus master of each bus, set if an IDE controller was found
+static BUS_MASTERS: LazyStatic<[Option<BusMaster>; 2]> = LazyStatic::new(|| [None, None]);
+
+static DMA_ENABLED: AtomicBool = AtomicBool::new(true);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaError {
+    /// The drive aborted the command, holds its error register
+    Device(u8),
+    /// The drive reported a fault
+    DeviceFault,
+    /// The drive did not complete the command in time
+    Timeout,
+    /// There is no ATA drive at this position
+    NoDrive,
+    /// The IDE controller could not access memory
+    Dma,
+    /// The sectors are past what the drive can address
+    OutOfRange,
+    /// The buffer is smaller than the sectors to transfer
+    BufferTooSmall,
+}
+
+impl From<AtaError> for BlockError {
+    fn from(err: AtaError) -> BlockError {
+        match err {
+            AtaError::Device(error) => BlockError::Device(error),
+            AtaError::Timeout => BlockError::Timeout,
+            AtaError::OutOfRange => BlockError::OutOfRange,
+            AtaError::BufferTooSmall => BlockError::BufferTooSmall,
+            AtaError::DeviceFault | AtaError::NoDrive | AtaError::Dma => BlockError::Io,
+        }
     }
+}
 
-    for sector in 0..sectors as usize {
-        wait_drive_ready();
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    Primary,
+    Secondary,
+}
 
-        for byte in (0..512).step_by(2) {
-            // SAFETY: DATA_PORT is a valid port
-            // Drive has data data ready to be read
-            let pair = unsafe { port::inw(DATA_PORT) };
-            dst[sector * 512 + byte] = pair as u8;
-            dst[sector * 512 + byte + 1] = (pair >> 8) as u8;
+impl Bus {
+    fn io_base(self) -> u16 {
+        match self {
+            Bus::Primary => 0x1F0,
+            Bus::Secondary => 0x170,
+        }
+    }
+
+    /// Port of the alternate status register when read, device control when written
+    fn control_port(self) -> u16 {
+        match self {
+            Bus::Primary => 0x3F6,
+            Bus::Secondary => 0x376,
         }
     }
 }
 
-/// writes `sectors` sectors starting at `lba` from `src`
-pub fn write_sectors(lba: usize, sectors: u8, src: &[u8]) {
-    assert!(sectors > 0);
-    set_up_drive(lba, sectors, ATA_MASTER);
-    // SAFETY: Drive is ready and has lba and sectors given
-    // COMMAND_PORT is a valid port
-    // WRITE_PIO is a valid value
-    unsafe {
-        port::outb(COMMAND_PORT, WRITE_PIO);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drive {
+    Master,
+    Slave,
+}
+
+/// A drive attached to one of the two ATA buses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AtaDrive {
+    pub bus: Bus,
+    pub drive: Drive,
+}
+
+/// What a drive reports about itself
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub model: String,
+    pub serial: String,
+    /// Number of sectors, those past 28bit LBA included when the drive supports 48bit LBA
+    pub sectors: u64,
+    pub lba48: bool,
+    dma: bool,
+}
+
+impl Identity {
+    /// Parses the data returned by IDENTIFY
+    pub fn new(data: &[u16; 256]) -> Identity {
+        let lba48 = data[83] & LBA48_SUPPORTED != 0;
+        let sectors = if lba48 {
+            data[100..104]
+                .iter()
+                .rev()
+                .fold(0, |sectors, &word| sectors << 16 | word as u64)
+        } else {
+            data[60] as u64 | (data[61] as u64) << 16
+        };
+
+        Identity {
+            model: ata_string(&data[27..47]),
+            serial: ata_string(&data[10..20]),
+            sectors,
+            lba48,
+            dma: data[49] & DMA_SUPPORTED != 0,
+        }
     }
+}
 
-    for j in 0..sectors as usize {
-        wait_drive_ready();
+impl AtaDrive {
+    /// Every position a drive can be attached to
+    pub const ALL: [AtaDrive; 4] = [
+        AtaDrive::new(Bus::Primary, Drive::Master),
+        AtaDrive::new(Bus::Primary, Drive::Slave),
+        AtaDrive::new(Bus::Secondary, Drive::Master),
+        AtaDrive::new(Bus::Secondary, Drive::Slave),
+    ];
 
-        for i in (0..512).step_by(2) {
-            let value = to_word(src, j * 512 + i);
-            // SAFETY: DATA_PORT is a valid port
-            // Drive is writing data and has sectors left to write
-            unsafe {
-                port::outw(DATA_PORT, value);
+    pub const fn new(bus: Bus, drive: Drive) -> AtaDrive {
+        AtaDrive { bus, drive }
+    }
+
+    /// Returns what the drive reported at boot, None if there is no ATA drive at this position
+    pub fn identity(&self) -> Option<Identity> {
+        DRIVES.obtain()[self.index()].clone()
+    }
+
+    /// Returns number of sectors of the drive, 0 if there is none
+    pub fn get_storage(&self) -> u64 {
+        self.identity().map_or(0, |identity| identity.sectors)
+    }
+
+    /// Read `sectors` sectors starting at `lba`
+    /// and writes contents in `dst`
+    pub fn read_sectors(&self, lba: usize, sectors: usize, dst: &mut [u8]) -> Result<(), AtaError> {
+        if dst.len() < sectors * 512 {
+            return Err(AtaError::BufferTooSmall);
+        }
+        match self.bus_master() {
+            Some(bus_master) if is_dma_capable(dst, sectors) => {
+                for start in (0..sectors).step_by(DMA_SECTORS) {
+                    let count = DMA_SECTORS.min(sectors - start);
+                    let buffer = dst[start * 512..].as_mut_ptr() as usize;
+                    self.transfer_dma(&bus_master, lba + start, count, buffer, true)?;
+                }
+                Ok(())
+            }
+            _ => {
+                let max_sectors = self.max_sectors()?;
+                for start in (0..sectors).step_by(max_sectors) {
+                    let count = max_sectors.min(sectors - start);
+                    self.read_sectors_pio(lba + start, count, &mut dst[start * 512..])?;
+                }
+                Ok(())
             }
         }
     }
 
-    // SAFETY: COMMAND_PORT is a valid port
-    // CACHE_FLUSH is a valid value
-    unsafe {
-        port::outb(COMMAND_PORT, CACHE_FLUSH);
+    /// writes `sectors` sectors starting at `lba` from `src`
+    /// They may stay in the drive's cache until `flush`
+    pub fn write_sectors(&self, lba: usize, sectors: usize, src: &[u8]) -> Result<(), AtaError> {
+        if src.len() < sectors * 512 {
+            return Err(AtaError::BufferTooSmall);
+        }
+        match self.bus_master() {
+            Some(bus_master) if is_dma_capable(src, sectors) => {
+                for start in (0..sectors).step_by(DMA_SECTORS) {
+                    let count = DMA_SECTORS.min(sectors - start);
+                    let buffer = src[start * 512..].as_ptr() as usize;
+                    self.transfer_dma(&bus_master, lba + start, count, buffer, false)?;
+                }
+                Ok(())
+            }
+            _ => {
+                let max_sectors = self.max_sectors()?;
+                for start in (0..sectors).step_by(max_sectors) {
+                    let count = max_sectors.min(sectors - start);
+                    self.write_sectors_pio(lba + start, count, &src[start * 512..])?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn read_sectors_pio(&self, lba: usize, sectors: usize, dst: &mut [u8]) -> Result<(), AtaError> {
+        let lba48 = self.set_up_drive(lba, sectors)?;
+        let command = if lba48 { READ_PIO_EXT } else { READ_PIO };
+        self.send_command(command);
+
+        for sector in 0..sectors {
+            // The drive interrupts once each sector is ready to be read
+            self.wait_interrupt()?;
+
+            for byte in (0..512).step_by(2) {
+                // SAFETY: DATA is a valid port
+                // Drive has data data ready to be read
+                let pair = unsafe { port::inw(self.port(DATA)) };
+                dst[sector * 512 + byte] = pair as u8;
+                dst[sector * 512 + byte + 1] = (pair >> 8) as u8;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors_pio(&self, lba: usize, sectors: usize, src: &[u8]) -> Result<(), AtaError> {
+        let lba48 = self.set_up_drive(lba, sectors)?;
+        let command = if lba48 { WRITE_PIO_EXT } else { WRITE_PIO };
+        self.send_command(command);
+
+        for j in 0..sectors {
+            // The drive only interrupts once a sector is written
+            if j == 0 {
+                self.wait_data_request()?;
+            } else {
+                self.wait_interrupt()?;
+            }
+
+            for i in (0..512).step_by(2) {
+                let value = to_word(src, j * 512 + i);
+                // SAFETY: DATA is a valid port
+                // Drive is writing data and has sectors left to write
+                unsafe {
+                    port::outw(self.port(DATA), value);
+                }
+            }
+        }
+        self.wait_interrupt()
+    }
+
+    /// Transfers `sectors` sectors starting at `lba` from or to `buffer`, the drive writes to it if `read`
+    fn transfer_dma(
+        &self,
+        bus_master: &BusMaster,
+        lba: usize,
+        sectors: usize,
+        buffer: usize,
+        read: bool,
+    ) -> Result<(), AtaError> {
+        let direction = if read { BM_READ } else { 0 };
+        bus_master.fill_prd_table(buffer, sectors * 512);
+        // SAFETY: Bus master ports are valid, the transfer is stopped
+        // The PRD table describes the buffer, it is below 4GiB
+        unsafe {
+            port::outb(bus_master.port + BM_COMMAND, direction);
+            port::outd(bus_master.port + BM_PRD_TABLE, bus_master.prd_table as u32);
+            port::outb(
+                bus_master.port + BM_STATUS,
+                BM_STATUS_ERROR | BM_STATUS_INTERRUPT,
+            );
+        }
+
+        let lba48 = self.set_up_drive(lba, sectors)?;
+        let command = match (read, lba48) {
+            (true, true) => READ_DMA_EXT,
+            (true, false) => READ_DMA,
+            (false, true) => WRITE_DMA_EXT,
+            (false, false) => WRITE_DMA,
+        };
+        self.send_command(command);
+        // SAFETY: BM_COMMAND is a valid port, the drive waits for the transfer to start
+        unsafe {
+            port::outb(bus_master.port + BM_COMMAND, direction | BM_START);
+        }
+
+        let res = self.wait_interrupt();
+        // SAFETY: Bus master ports are valid
+        // Stopping the transfer and clearing the status bits has no other side effect
+        let status = unsafe {
+            port::outb(bus_master.port + BM_COMMAND, direction);
+            let status = port::inb(bus_master.port + BM_STATUS);
+            port::outb(bus_master.port + BM_STATUS, status);
+            status
+        };
+        res?;
+        if status & BM_STATUS_ERROR != 0 {
+            return Err(AtaError::Dma);
+        }
+        Ok(())
+    }
+
+    /// Writes the drive's cache to the disk
+    pub fn flush(&self) -> Result<(), AtaError> {
+        let lba48 = self.lba48()?;
+        // SAFETY: DEVICE_SELECT is a valid port, select() is a valid value
+        unsafe {
+            port::outb(self.port(DEVICE_SELECT), self.select());
+        }
+        self.wait_ready()?;
+        self.flush_cache(lba48)
+    }
+
+    /// Flushes the cache of the selected drive
+    fn flush_cache(&self, lba48: bool) -> Result<(), AtaError> {
+        let command = if lba48 { CACHE_FLUSH_EXT } else { CACHE_FLUSH };
+        self.send_command(command);
+        self.wait_interrupt()
+    }
+
+    /// Returns the bus master to transfer with, None if PIO must be used
+    fn bus_master(&self) -> Option<BusMaster> {
+        let dma = matches!(&DRIVES.obtain()[self.index()], Some(identity) if identity.dma);
+        if !dma || !DMA_ENABLED.load(Ordering::SeqCst) {
+            return None;
+        }
+        BUS_MASTERS.obtain()[self.bus as usize]
+    }
+
+    /// Returns the position of the drive in `DRIVES`
+    fn index(&self) -> usize {
+        self.bus as usize * 2 + self.drive as usize
+    }
+
+    fn port(&self, register: u16) -> u16 {
+        self.bus.io_base() + register
+    }
+
+    /// Value of the device register selecting the drive in LBA mode, for every command
+    fn select(&self) -> u8 {
+        match self.drive {
+            Drive::Master => 0xE0,
+            Drive::Slave => 0xF0,
+        }
+    }
+
+    /// Sends `command` once its parameters are loaded, its interrupt is waited for with `wait_interrupt`
+    fn send_command(&self, command: u8) {
+        PENDING[self.bus as usize].store(false, Ordering::SeqCst);
+        // SAFETY: COMMAND is a valid port
+        // Drive is ready and has the parameters of command
+        unsafe {
+            port::outb(self.port(COMMAND), command);
+        }
+    }
+
+    /// Sleeps until the drive interrupts, then checks the status of the command
+    fn wait_interrupt(&self) -> Result<(), AtaError> {
+        assert!(interrupt::are_enabled(), "ATA drives need interrupts");
+        let pending = &PENDING[self.bus as usize];
+        let deadline = interrupt::ticks() + TIMEOUT;
+
+        loop {
+            interrupt::disable();
+            if pending.swap(false, Ordering::SeqCst) {
+                interrupt::enable();
+                return self.check_status(self.read_alternate_status());
+            }
+            if interrupt::ticks() >= deadline {
+                interrupt::enable();
+                return Err(AtaError::Timeout);
+            }
+            interrupt::enable_and_halt();
+        }
+    }
+
+    /// Polls until the drive is done working and requests data
+    fn wait_data_request(&self) -> Result<(), AtaError> {
+        let status = self.poll()?;
+        if status & STATUS_DATA_REQUEST == 0 {
+            return Err(AtaError::Device(self.read_error()));
+        }
+        Ok(())
+    }
+
+    /// Polls until the drive is done working, returns its status
+    fn poll(&self) -> Result<u8, AtaError> {
+        let deadline = interrupt::ticks() + TIMEOUT;
+        self.delay();
+        loop {
+            let status = self.read_alternate_status();
+            if status & STATUS_BUSY == 0 {
+                return self.check_status(status).map(|_| status);
+            }
+            if interrupt::ticks() >= deadline {
+                return Err(AtaError::Timeout);
+            }
+        }
+    }
+
+    /// Returns the error reported in `status`, if any
+    fn check_status(&self, status: u8) -> Result<(), AtaError> {
+        if status & STATUS_ERROR != 0 {
+            Err(AtaError::Device(self.read_error()))
+        } else if status & STATUS_DEVICE_FAULT != 0 {
+            Err(AtaError::DeviceFault)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sends IDENTIFY, returns None if there is no ATA drive at this position
+    fn identify(&self) -> Option<Identity> {
+        // SAFETY: DEVICE_SELECT is a valid port, select() is a valid value
+        unsafe {
+            port::outb(self.port(DEVICE_SELECT), self.select());
+        }
+        // Check if a controller drives the bus
+        self.delay();
+        if self.read_status() == STATUS_FLOATING {
+            return None;
+        }
+
+        // SAFETY: Drive is selected
+        // Ports are valid, registers are cleared as IDENTIFY expects
+        unsafe {
+            port::outb(self.port(SECTOR_COUNT), 0);
+            port::outb(self.port(LBA_LOW), 0);
+            port::outb(self.port(LBA_MID), 0);
+            port::outb(self.port(LBA_HIGH), 0);
+            port::outb(self.port(COMMAND), IDENTIFY);
+        }
+
+        // Check if drive exists
+        if self.read_status() == 0 {
+            return None;
+        }
+
+        // Wait for drive to be done working
+        // Aborting, as ATAPI and SATA drives do, does not mean the position is empty
+        let done = self.poll();
+
+        // Check if it is an ATA Drive, ATAPI and SATA drives leave a signature
+        // SAFETY: LBA_MID and LBA_HIGH are both valid ports
+        unsafe {
+            if port::inb(self.port(LBA_MID)) != 0 || port::inb(self.port(LBA_HIGH)) != 0 {
+                return None;
+            }
+        }
+        done.ok()?;
+        self.wait_data_request().ok()?;
+
+        // Retrieve data generated
+        let mut buffer = [0; 256];
+        for word in &mut buffer {
+            // SAFETY: there are 256 16bits values generated
+            // Retrievable on DATA
+            *word = unsafe { port::inw(self.port(DATA)) };
+        }
+        Some(Identity::new(&buffer))
+    }
+
+    /// Sends IDENTIFY PACKET DEVICE, returns None if there is no ATAPI drive at this position
+    fn identify_packet(&self) -> Option<Identity> {
+        // SAFETY: DEVICE_SELECT is a valid port, select() is a valid value
+        unsafe {
+            port::outb(self.port(DEVICE_SELECT), self.select());
+        }
+        self.delay();
+        if self.read_status() == STATUS_FLOATING {
+            return None;
+        }
+
+        // SAFETY: Drive is selected, COMMAND is a valid port
+        // ATA drives abort the command
+        unsafe {
+            port::outb(self.port(COMMAND), IDENTIFY_PACKET);
+        }
+        if self.read_status() == 0 {
+            return None;
+        }
+        self.poll().ok()?;
+        self.wait_data_request().ok()?;
+
+        let mut buffer = [0; 256];
+        for word in &mut buffer {
+            // SAFETY: there are 256 16bits values generated
+            // Retrievable on DATA
+            *word = unsafe { port::inw(self.port(DATA)) };
+        }
+        Some(Identity::new(&buffer))
+    }
+
+    /// Returns true if the drive supports 48bit LBA
+    fn lba48(&self) -> Result<bool, AtaError> {
+        match &DRIVES.obtain()[self.index()] {
+            Some(identity) => Ok(identity.lba48),
+            None => Err(AtaError::NoDrive),
+        }
+    }
+
+    /// Returns the most sectors a single command transfers
+    fn max_sectors(&self) -> Result<usize, AtaError> {
+        match self.lba48()? {
+            true => Ok(MAX_SECTORS_EXT),
+            false => Ok(MAX_SECTORS),
+        }
+    }
+
+    /// Loads `lba` and `sectors` in the drive's registers
+    /// Returns true if the 48bit LBA commands must be sent
+    fn set_up_drive(&self, lba: usize, sectors: usize) -> Result<bool, AtaError> {
+        let lba48 = self.lba48()?;
+        let max_lba = if lba48 { 1 << 48 } else { 1 << 28 };
+        if sectors == 0 || sectors > self.max_sectors()? || lba + sectors > max_lba {
+            return Err(AtaError::OutOfRange);
+        }
+
+        let select = if lba48 {
+            self.select()
+        } else {
+            (lba >> 24) as u8 | self.select()
+        };
+        // SAFETY: DEVICE_SELECT is a valid port
+        // LBA fits in 28bits => value is valid
+        unsafe {
+            port::outb(self.port(DEVICE_SELECT), select);
+        }
+        self.wait_ready()?;
+
+        // SAFETY: Drive is ready to receive commands
+        // Ports are valid
+        // LBA and sectors fit in the registers => values are valid
+        // A count of 0 stands for the maximum count
+        unsafe {
+            if lba48 {
+                // High bytes go first, each register holds two bytes
+                port::outb(self.port(SECTOR_COUNT), (sectors >> 8) as u8);
+                port::outb(self.port(LBA_LOW), (lba >> 24) as u8);
+                port::outb(self.port(LBA_MID), (lba >> 32) as u8);
+                port::outb(self.port(LBA_HIGH), (lba >> 40) as u8);
+            }
+            port::outb(self.port(SECTOR_COUNT), sectors as u8);
+            port::outb(self.port(LBA_LOW), lba as u8);
+            port::outb(self.port(LBA_MID), (lba >> 8) as u8);
+            port::outb(self.port(LBA_HIGH), (lba >> 16) as u8);
+        }
+        Ok(lba48)
+    }
+
+    /// Polls until the drive is ready to receive a command
+    fn wait_ready(&self) -> Result<(), AtaError> {
+        let deadline = interrupt::ticks() + TIMEOUT;
+        while !self.is_ready() {
+            if interrupt::ticks() >= deadline {
+                return Err(AtaError::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_ready(&self) -> bool {
+        self.delay();
+
+        // Check if drive is not busy and is ready
+        let status = self.read_alternate_status();
+        status & STATUS_BUSY == 0 && status & STATUS_READY != 0
+    }
+
+    /// Poll 400ns, the time the drive takes to update its status
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.read_alternate_status();
+        }
+    }
+
+    /// Reads the status, acknowledging the drive's interrupt
+    fn read_status(&self) -> u8 {
+        // SAFETY: COMMAND is a valid port
+        unsafe { port::inb(self.port(COMMAND)) }
+    }
+
+    /// Reads the status without acknowledging the drive's interrupt
+    fn read_alternate_status(&self) -> u8 {
+        // SAFETY: The control port is a valid port
+        unsafe { port::inb(self.bus.control_port()) }
+    }
+
+    fn read_error(&self) -> u8 {
+        // SAFETY: ERROR is a valid port
+        unsafe { port::inb(self.port(ERROR)) }
     }
 }
 
-/// Returns number of sectors accessible by 28LBA
-pub fn get_storage() -> u32 {
-    // send IDENTIFY command
-    set_up_drive(0, 0, 0xA0);
-    // SAFETY: Drive is ready and has lba and sectors set to 0
-    // COMMAND_PORT is a valid port
-    // IDENTIFY is a valid value
-    unsafe {
-        port::outb(COMMAND_PORT, IDENTIFY);
+impl BlockDevice for AtaDrive {
+    fn block_size(&self) -> usize {
+        512
     }
 
-    // Check if drive exists
-    if read_drive_status() == 0 {
-        return 0;
+    fn block_count(&self) -> u64 {
+        self.get_storage()
     }
 
-    // Wait for drive to be done working
-    while read_drive_status() & STATUS_BUSY != 0 {}
+    fn read_blocks(&self, lba: usize, count: usize, dst: &mut [u8]) -> Result<(), BlockError> {
+        check_blocks(self, lba, count, dst.len())?;
+        Ok(self.read_sectors(lba, count, dst)?)
+    }
 
-    // Check if it is an ATA Drive
-    // SAFETY: LBA_LOW and LBA_MID are both valid ports
-    unsafe {
-        if port::inb(LBA_LOW) != 0 || port::inb(LBA_MID) != 0 {
-            return 0;
+    fn write_blocks(&self, lba: usize, count: usize, src: &[u8]) -> Result<(), BlockError> {
+        check_blocks(self, lba, count, src.len())?;
+        Ok(self.write_sectors(lba, count, src)?)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(AtaDrive::flush(self)?)
+    }
+}
+
+impl fmt::Display for AtaDrive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bus = match self.bus {
+            Bus::Primary => "primary",
+            Bus::Secondary => "secondary",
+        };
+        let drive = match self.drive {
+            Drive::Master => "master",
+            Drive::Slave => "slave",
+        };
+        write!(f, "{} {}", bus, drive)
+    }
+}
+
+/// An ATAPI drive, such as a CD-ROM drive, attached to one of the two ATA buses
+/// Its medium is read-only, only reading and querying its capacity are supported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AtapiDrive {
+    pub bus: Bus,
+    pub drive: Drive,
+}
+
+impl AtapiDrive {
+    pub const fn new(bus: Bus, drive: Drive) -> AtapiDrive {
+        AtapiDrive { bus, drive }
+    }
+
+    /// Returns what the drive reported at boot, None if there is no ATAPI drive at this position
+    pub fn identity(&self) -> Option<Identity> {
+        PACKET_DRIVES.obtain()[self.position().index()].clone()
+    }
+
+    /// Returns the number of sectors of the medium and their size in bytes
+    pub fn capacity(&self) -> Result<(u64, usize), AtaError> {
+        let mut data = [0; 8];
+        let packet = [READ_CAPACITY, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        self.send_packet(&packet, &mut data)?;
+
+        let last_lba = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        let sector_size = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+        Ok((last_lba as u64 + 1, sector_size as usize))
+    }
+
+    /// Read `sectors` sectors of `ATAPI_SECTOR_SIZE` bytes starting at `lba` in `dst`
+    pub fn read_sectors(&self, lba: usize, sectors: usize, dst: &mut [u8]) -> Result<(), AtaError> {
+        for start in (0..sectors).step_by(ATAPI_SECTORS) {
+            let count = ATAPI_SECTORS.min(sectors - start);
+            let lba = ((lba + start) as u32).to_be_bytes();
+            let packet = [
+                READ_10,
+                0,
+                lba[0],
+                lba[1],
+                lba[2],
+                lba[3],
+                0,
+                (count >> 8) as u8,
+                count as u8,
+                0,
+                0,
+                0,
+            ];
+            let dst = &mut dst[start * ATAPI_SECTOR_SIZE..(start + count) * ATAPI_SECTOR_SIZE];
+            self.send_packet(&packet, dst)?;
         }
+        Ok(())
     }
 
-    // Retrieve data generated
-    let mut buffer = [0; 256];
-    for word in &mut buffer {
-        // SAFETY: there are 256 16bits values generated
-        // Retrievable on COMMAND_PORT
-        *word = unsafe { port::inw(COMMAND_PORT) };
+    fn position(&self) -> AtaDrive {
+        AtaDrive::new(self.bus, self.drive)
     }
 
-    // Get available storage for 28bit LBA
-    buffer[60] as u32 | (buffer[61] as u32) << 16
+    /// Sends `packet`, the data the drive returns goes to `dst`
+    /// The command is retried once if the drive reports the medium changed
+    fn send_packet(&self, packet: &[u8; 12], dst: &mut [u8]) -> Result<(), AtaError> {
+        match self.transfer_packet(packet, dst) {
+            Err(AtaError::Device(error)) if error >> 4 == UNIT_ATTENTION => {
+                // Requesting the sense data clears the condition
+                let mut sense = [0; 18];
+                let request_sense = [REQUEST_SENSE, 0, 0, 0, 18, 0, 0, 0, 0, 0, 0, 0];
+                self.transfer_packet(&request_sense, &mut sense)?;
+                self.transfer_packet(packet, dst)
+            }
+            res => res,
+        }
+    }
+
+    /// Sends PACKET followed by `packet`, then reads the data the drive returns in `dst`
+    /// Data that does not fit in `dst` is discarded
+    fn transfer_packet(&self, packet: &[u8; 12], dst: &mut [u8]) -> Result<(), AtaError> {
+        if self.identity().is_none() {
+            return Err(AtaError::NoDrive);
+        }
+        let drive = self.position();
+        // SAFETY: DEVICE_SELECT is a valid port, select() is a valid value
+        unsafe {
+            port::outb(drive.port(DEVICE_SELECT), drive.select());
+        }
+        drive.wait_ready()?;
+
+        // SAFETY: Drive is ready to receive commands, ports are valid
+        // The data is transferred with PIO, at most BYTE_COUNT_LIMIT bytes at a time
+        unsafe {
+            port::outb(drive.port(FEATURES), 0);
+            port::outb(drive.port(LBA_MID), BYTE_COUNT_LIMIT as u8);
+            port::outb(drive.port(LBA_HIGH), (BYTE_COUNT_LIMIT >> 8) as u8);
+        }
+        drive.send_command(PACKET);
+        drive.wait_data_request()?;
+        for index in (0..packet.len()).step_by(2) {
+            // SAFETY: DATA is a valid port, the drive waits for the packet
+            unsafe {
+                port::outw(drive.port(DATA), to_word(packet, index));
+            }
+        }
+
+        // The drive interrupts before each block of data and once the command completes
+        let mut index = 0;
+        loop {
+            drive.wait_interrupt()?;
+            if drive.read_alternate_status() & STATUS_DATA_REQUEST == 0 {
+                return Ok(());
+            }
+
+            // SAFETY: LBA_MID, LBA_HIGH and DATA are valid ports
+            // The drive has `count` bytes ready to be read
+            unsafe {
+                let count = port::inb(drive.port(LBA_MID)) as usize
+                    | (port::inb(drive.port(LBA_HIGH)) as usize) << 8;
+                for _ in (0..count).step_by(2) {
+                    let pair = port::inw(drive.port(DATA));
+                    for &byte in pair.to_le_bytes().iter() {
+                        if let Some(dst) = dst.get_mut(index) {
+                            *dst = byte;
+                        }
+                        index += 1;
+                    }
+                }
+            }
+        }
+    }
 }
 
-fn set_up_drive(lba: usize, sectors: u8, ata_drive: u8) {
-    assert!(lba < 0x1000000); // lba must fit in 28bits
-    wait_drive_ready();
+impl BlockDevice for AtapiDrive {
+    fn block_size(&self) -> usize {
+        ATAPI_SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.capacity().map_or(0, |(sectors, _)| sectors)
+    }
+
+    fn read_blocks(&self, lba: usize, count: usize, dst: &mut [u8]) -> Result<(), BlockError> {
+        check_blocks(self, lba, count, dst.len())?;
+        Ok(self.read_sectors(lba, count, dst)?)
+    }
 
-    // SAFETY: Drive is ready to receive commands
-    // Ports are valid
-    // LBA fits in 28bits => values are valid
+    fn write_blocks(&self, _lba: usize, _count: usize, _src: &[u8]) -> Result<(), BlockError> {
+        Err(BlockError::ReadOnly)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+impl fmt::Display for AtapiDrive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.position())
+    }
+}
+
+/// Enables the drives' interrupts, probes every position, registers and reports the drives found
+pub fn init() {
+    for bus in &[Bus::Primary, Bus::Secondary] {
+        // SAFETY: The control port is a valid port
+        // CONTROL_INTERRUPTS is a valid value
+        unsafe {
+            port::outb(bus.control_port(), CONTROL_INTERRUPTS);
+        }
+    }
+    for (drive, identity) in drives() {
+        block_device::register(Disk::new("ATA", drive));
+        serial_println!(
+            "ATA {}: {} (serial {}), {} sectors",
+            drive,
+            identity.model,
+            identity.serial,
+            identity.sectors
+        );
+        for (index, partition) in partitions(&drive).unwrap_or_default().iter().enumerate() {
+            serial_println!("  Partition {}: {}", index + 1, partition);
+        }
+    }
+    for (drive, identity) in atapi_drives() {
+        serial_println!(
+            "ATAPI {}: {} (serial {})",
+            drive,
+            identity.model,
+            identity.serial
+        );
+    }
+}
+
+/// Bus master IDE registers of a bus and the PRD table its transfers use
+#[derive(Clone, Copy)]
+struct BusMaster {
+    port: u16,
+    /// Physical address of a page below 4GiB
+    prd_table: usize,
+}
+
+/// Physical region descriptor, a memory range the controller transfers to or from
+#[repr(C)]
+struct PrdEntry {
+    addr: u32,
+    /// Number of bytes, 0 stands for 64KiB
+    size: u16,
+    flags: u16,
+}
+
+impl BusMaster {
+    /// Describes [buffer, buffer + size[ in the PRD table, one entry per page
+    fn fill_prd_table(&self, buffer: usize, size: usize) {
+        let table = memory_manager::phys_to_virt(self.prd_table) as *mut PrdEntry;
+        let end = buffer + size;
+        let mut addr = buffer;
+        let mut index = 0;
+
+        while addr < end {
+            let len = (PAGE_SIZE - addr % PAGE_SIZE).min(end - addr);
+            let phys_addr = memory_manager::virt_to_phys(addr).expect("DMA buffer is not mapped");
+            // SAFETY: The table is a page, DMA_SECTORS sectors span fewer entries than it holds
+            unsafe {
+                table.add(index).write(PrdEntry {
+                    addr: phys_addr as u32,
+                    size: len as u16,
+                    flags: if addr + len == end { PRD_END } else { 0 },
+                });
+            }
+            addr += len;
+            index += 1;
+        }
+    }
+}
+
+/// Returns true if the controller can transfer `sectors` sectors to or from `buffer`
+/// Regions must be word aligned, mapped and below 4GiB
+fn is_dma_capable(buffer: &[u8], sectors: usize) -> bool {
+    let start = buffer.as_ptr() as usize;
+    let end = start + sectors * 512;
+    let is_below_4gib = |page| match memory_manager::virt_to_phys(page) {
+        Some(phys_addr) => phys_addr + PAGE_SIZE <= 1 << 32,
+        None => false,
+    };
+
+    buffer.len() >= sectors * 512
+        && start % 2 == 0
+        && (start / PAGE_SIZE * PAGE_SIZE..end)
+            .step_by(PAGE_SIZE)
+            .all(is_below_4gib)
+}
+
+/// Enables bus mastering on the IDE controller `device`, transfers then use DMA
+pub fn init_dma(device: &Device, func: Function) {
+    let port = match device.bar(func, 4) {
+        Some(Bar::IO { port }) if port != 0 => port as u16,
+        _ => return,
+    };
+    let command = device.read_u16(func, pci::COMMAND);
+    // SAFETY: COMMAND is the command register, enabling bus mastering has no other side effect
     unsafe {
-        port::outb(DEVICE_SELECT, (lba >> 24) as u8 | ata_drive);
-        port::outb(SECTOR_COUNT, sectors);
-        port::outb(LBA_LOW, lba as u8);
-        port::outb(LBA_MID, (lba >> 8) as u8);
-        port::outb(LBA_HIGH, (lba >> 16) as u8);
+        device.write_u16(func, pci::COMMAND, command | PCI_IO_SPACE | PCI_BUS_MASTER);
+    }
+
+    let mut bus_masters = BUS_MASTERS.obtain();
+    for bus in &[Bus::Primary, Bus::Secondary] {
+        let frame = ALLOCATOR
+            .obtain()
+            .allocate_frame()
+            .expect("No frame left for PRD table");
+        if frame.base_addr + PAGE_SIZE > 1 << 32 {
+            ALLOCATOR.obtain().deallocate_frame(frame);
+            continue;
+        }
+        bus_masters[*bus as usize] = Some(BusMaster {
+            port: port + *bus as u16 * 8,
+            prd_table: frame.base_addr,
+        });
     }
 }
 
-fn wait_drive_ready() {
-    while !is_drive_ready() {}
+/// Enables or disables DMA, PIO is used while it is disabled
+/// DMA is enabled by default, disabling it lets benchmarks compare both
+pub fn set_dma_enabled(enabled: bool) {
+    DMA_ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// Acknowledges the interrupt of the selected drive of `bus` and wakes up whoever waits for it
+pub fn handle_interrupt(bus: Bus) {
+    AtaDrive::new(bus, Drive::Master).read_status();
+    PENDING[bus as usize].store(true, Ordering::SeqCst);
 }
 
-fn is_drive_ready() -> bool {
-    // Poll 400ns
-    for _ in 0..4 {
-        read_drive_status();
+/// Returns the drives found at boot
+pub fn drives() -> impl Iterator<Item = (AtaDrive, Identity)> {
+    AtaDrive::ALL
+        .iter()
+        .filter_map(|drive| drive.identity().map(|identity| (*drive, identity)))
+}
+
+/// Returns the ATAPI drives found at boot
+pub fn atapi_drives() -> impl Iterator<Item = (AtapiDrive, Identity)> {
+    AtaDrive::ALL.iter().filter_map(|position| {
+        let drive = AtapiDrive::new(position.bus, position.drive);
+        drive.identity().map(|identity| (drive, identity))
+    })
+}
+
+fn probe() -> [Option<Identity>; 4] {
+    let mut drives = [None, None, None, None];
+    for drive in &AtaDrive::ALL {
+        drives[drive.index()] = drive.identify();
     }
+    drives
+}
 
-    // Check if drive is not busy and is ready
-    let status = read_drive_status();
-    status & STATUS_BUSY == 0 && status & STATUS_READY != 0
+fn probe_packet() -> [Option<Identity>; 4] {
+    let mut drives = [None, None, None, None];
+    for drive in &AtaDrive::ALL {
+        drives[drive.index()] = drive.identify_packet();
+    }
+    drives
 }
 
-fn read_drive_status() -> u8 {
-    // SAFETY: COMMAND_PORT is a valid port
-    unsafe { port::inb(COMMAND_PORT) }
+/// Decodes a string of IDENTIFY data, each word holds two characters, the first in the high byte
+fn ata_string(words: &[u16]) -> String {
+    let mut string = String::new();
+    for word in words {
+        string.push((word >> 8) as u8 as char);
+        string.push(*word as u8 as char);
+    }
+    string.trim().into()
 }
 
 /// Translates two u8 in a slice into a u16
@@ -168,8 +1060,101 @@ fn to_word(src: &[u8], index: usize) -> u16 {
 mod test {
     use super::*;
 
+    const DISK: AtaDrive = AtaDrive::new(Bus::Primary, Drive::Master);
+
     #[test_case]
     fn non_empty_ata() {
-        assert!(get_storage() != 0);
+        assert!(DISK.get_storage() != 0);
+    }
+
+    #[test_case]
+    fn identify_boot_disk() {
+        let identity = DISK.identity().unwrap();
+        assert!(!identity.model.is_empty());
+        assert!(!identity.serial.is_empty());
+        assert_eq!(identity.sectors, DISK.get_storage());
+    }
+
+    #[test_case]
+    fn large_transfer() {
+        // More sectors than a 28bit LBA command can transfer
+        let sectors = MAX_SECTORS + 1;
+        let mut buffer = alloc::vec![0; sectors * 512];
+        DISK.read_sectors(0, sectors, &mut buffer).unwrap();
+        assert_eq!(buffer[510..512], [0x55, 0xAA]);
+    }
+
+    #[test_case]
+    fn dma_matches_pio() {
+        let mut dma = alloc::vec![0; 8 * 512];
+        let mut pio = alloc::vec![0; 8 * 512];
+        DISK.read_sectors(0, 8, &mut dma).unwrap();
+        set_dma_enabled(false);
+        let res = DISK.read_sectors(0, 8, &mut pio);
+        set_dma_enabled(true);
+        res.unwrap();
+        assert_eq!(dma, pio);
+    }
+
+    #[test_case]
+    fn large_pio_transfer() {
+        let sectors = MAX_SECTORS + 1;
+        let mut dma = alloc::vec![0; sectors * 512];
+        let mut pio = alloc::vec![0; sectors * 512];
+        DISK.read_sectors(0, sectors, &mut dma).unwrap();
+        set_dma_enabled(false);
+        let res = DISK.read_sectors(0, sectors, &mut pio);
+        set_dma_enabled(true);
+        res.unwrap();
+        assert_eq!(dma, pio);
+    }
+
+    #[test_case]
+    fn buffer_too_small() {
+        let mut buffer = [0; 512];
+        assert_eq!(
+            DISK.read_sectors(0, 2, &mut buffer),
+            Err(AtaError::BufferTooSmall)
+        );
+    }
+
+    #[test_case]
+    fn read_past_end() {
+        let mut buffer = [0; 512];
+        let end = DISK.get_storage() as usize;
+        assert!(matches!(
+            DISK.read_sectors(end, 1, &mut buffer),
+            Err(AtaError::Device(_))
+        ));
+        // The drive recovers from the error
+        DISK.read_sectors(end - 1, 1, &mut buffer).unwrap();
+    }
+
+    #[test_case]
+    fn blocks_past_end() {
+        let mut buffer = [0; 2 * 512];
+        let end = DISK.block_count() as usize;
+        assert_eq!(
+            DISK.read_blocks(end - 1, 2, &mut buffer),
+            Err(BlockError::OutOfRange)
+        );
+        assert_eq!(
+            DISK.write_blocks(end, 1, &buffer),
+            Err(BlockError::OutOfRange)
+        );
+    }
+
+    #[test_case]
+    fn missing_drive() {
+        let missing = AtaDrive::ALL
+            .iter()
+            .find(|drive| drive.identity().is_none());
+        if let Some(drive) = missing {
+            let mut buffer = [0; 512];
+            assert_eq!(
+                drive.read_sectors(0, 1, &mut buffer),
+                Err(AtaError::NoDrive)
+            );
+        }
     }
 }
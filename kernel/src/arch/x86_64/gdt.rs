@@ -7,19 +7,39 @@ pub const KERNEL_CODE_SEG: Segment = Segment::kernel(1);
 const KERNEL_DATA_SEG: Segment = Segment::kernel(2);
 const USER_CODE_SEG: Segment = Segment::userland(3);
 const USER_DATA_SEG: Segment = Segment::userland(4);
+/// The TSS descriptor takes two entries
+const TSS_SEG: Segment = Segment::kernel(5);
 
-const MAX_ENTRIES: usize = 5;
+const MAX_ENTRIES: usize = 7;
+
+/// Interrupt stack table index of the double fault handler's stack
+pub const DOUBLE_FAULT_IST: u8 = 1;
+const IST_STACK_SIZE: usize = 4096 * 4;
 
 pub fn init() {
+    static mut DOUBLE_FAULT_STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+    static mut TSS: MaybeUninit<Tss> = MaybeUninit::uninit();
+
+    // SAFETY: The GDT is only initialized once, nothing else accesses these statics
+    let tss = unsafe {
+        let mut tss = Tss::new();
+        tss.interrupt_stacks[DOUBLE_FAULT_IST as usize - 1] =
+            DOUBLE_FAULT_STACK.as_ptr() as u64 + IST_STACK_SIZE as u64;
+        TSS = MaybeUninit::new(tss);
+        TSS.assume_init_ref()
+    };
+
     let gdt = Gdt::new()
         .insert(KERNEL_CODE_SEG, GdtEntry::new(0, true, KERNEL_RING))
         .insert(KERNEL_DATA_SEG, GdtEntry::new(0, false, KERNEL_RING))
         .insert(USER_CODE_SEG, GdtEntry::new(0, true, USERLAND_RING))
-        .insert(USER_DATA_SEG, GdtEntry::new(0, false, USERLAND_RING));
+        .insert(USER_DATA_SEG, GdtEntry::new(0, false, USERLAND_RING))
+        .insert_tss(TSS_SEG, tss);
 
     // SAFETY: Gdt and Segments are valid
     unsafe {
         gdt.load(KERNEL_CODE_SEG, KERNEL_DATA_SEG);
+        load_tss(TSS_SEG);
     }
 }
 
@@ -76,6 +96,7 @@ struct GdtEntry {
     base_addr_high: u8,
 }
 
+const TSS_AVAILABLE: u8 = 0b1001;
 const WRITEABLE: u8 = 1 << 1;
 const EXECUTABLE: u8 = 1 << 3;
 const DESC_TYPE: u8 = 1 << 4;
@@ -102,6 +123,27 @@ impl GdtEntry {
         }
     }
 
+    /// Returns the two entries of a TSS descriptor
+    fn tss(tss: &'static Tss) -> [GdtEntry; 2] {
+        let base_addr = tss as *const Tss as u64;
+        let limit = mem::size_of::<Tss>() as u64 - 1;
+        let low = GdtEntry {
+            seg_lim_low: limit as u16,
+            base_addr_low: base_addr as u16,
+            base_addr_mid: (base_addr >> 16) as u8,
+            access: TSS_AVAILABLE | SEG_PRESENT,
+            flags: (limit >> 16) as u8 & 0xF,
+            base_addr_high: (base_addr >> 24) as u8,
+        };
+        // The upper half of the descriptor holds the upper 32 bits of the base
+        let high = GdtEntry {
+            seg_lim_low: (base_addr >> 32) as u16,
+            base_addr_low: (base_addr >> 48) as u16,
+            ..Default::default()
+        };
+        [low, high]
+    }
+
     pub fn is_code_segment(&self) -> bool {
         self.access & (SEG_PRESENT | EXECUTABLE) == (SEG_PRESENT | EXECUTABLE)
             && self.flags & CS_SIZE == CS_SIZE
@@ -112,6 +154,34 @@ impl GdtEntry {
     }
 }
 
+/// 64-bit Task State Segment, only used for its interrupt stack table
+#[repr(C, packed)]
+struct Tss {
+    reserved_1: u32,
+    privilege_stacks: [u64; 3],
+    reserved_2: u64,
+    /// Stacks selected by the IST field of interrupt gates, starting at index 1
+    interrupt_stacks: [u64; 7],
+    reserved_3: u64,
+    reserved_4: u16,
+    iomap_base: u16,
+}
+
+impl Tss {
+    const fn new() -> Tss {
+        Tss {
+            reserved_1: 0,
+            privilege_stacks: [0; 3],
+            reserved_2: 0,
+            interrupt_stacks: [0; 7],
+            reserved_3: 0,
+            reserved_4: 0,
+            // No I/O permission bitmap
+            iomap_base: mem::size_of::<Tss>() as u16,
+        }
+    }
+}
+
 #[repr(C)]
 struct Gdt {
     entries: [GdtEntry; MAX_ENTRIES],
@@ -135,6 +205,13 @@ impl Gdt {
         self
     }
 
+    fn insert_tss(mut self, seg: Segment, tss: &'static Tss) -> Gdt {
+        let [low, high] = GdtEntry::tss(tss);
+        self.entries[seg.get_offset()] = low;
+        self.entries[seg.get_offset() + 1] = high;
+        self
+    }
+
     /// Loads the Gdt and reloads segments
     /// Is unsafe if GDT is not properly filled
     /// Panics if code_seg and data_seg don't point to their respective entries
@@ -172,6 +249,11 @@ unsafe fn reload_data_seg(seg: Segment) {
          in("ax") u16::from(seg));
 }
 
+/// Loads the task register, `seg` must point to a TSS descriptor
+unsafe fn load_tss(seg: Segment) {
+    asm!("ltr {:x}", in(reg) u16::from(seg));
+}
+
 unsafe fn reload_code_seg(seg: Segment) {
     asm!("push {:r}",             // Push seg
          "lea {tmp}, [rip + 1f]", // Push rip
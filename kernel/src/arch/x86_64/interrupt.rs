@@ -1,14 +1,32 @@
-use super::gdt::KERNEL_CODE_SEG;
+use super::ata::{self, Bus};
+use super::gdt::{DOUBLE_FAULT_IST, KERNEL_CODE_SEG};
 use super::pic::{PICS, PIC_1_OFFSET};
 use super::port;
 use crate::driver::ps2_keyboard;
+use crate::memory_manager::address_space::{self, FaultError};
+use crate::memory_manager::stack;
 use crate::println;
 use core::mem::{self, MaybeUninit};
+use core::sync::atomic::{AtomicU64, Ordering};
 
 const MAX_ENTRIES: usize = 256;
 
+/// Timer interrupts per second, the PIT is left at its default divisor of 65536
+pub const TIMER_FREQUENCY: u64 = 18;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
 const KEYBOARD_PORT: u16 = 0x60;
 
+/// Bits of the error code pushed on page faults
+#[repr(u64)]
+enum PageFaultError {
+    /// The page was present, the access violated its protection
+    ProtectionViolation = 1,
+    /// The access was a write
+    Write = 1 << 1,
+}
+
 #[derive(Debug)]
 #[repr(C)]
 struct InterruptFrame {
@@ -19,7 +37,26 @@ struct InterruptFrame {
     ss: u64,
 }
 
+/// Returns the address whose access caused the last page fault
+fn fault_address() -> usize {
+    let address: usize;
+    // SAFETY: Reading cr2 has no side effect
+    unsafe {
+        asm!("mov {}, cr2", out(reg) address);
+    }
+    address
+}
+
+/// Runs on its own stack, a page fault on a guard page cannot push its frame
+/// so stack overflows end up here
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptFrame, _error_code: u64) -> ! {
+    let address = fault_address();
+    if stack::is_guard_page(address) {
+        panic!(
+            "EXCEPTION: stack overflow, accessed guard page at {:#x}\n{:#?}",
+            address, stack_frame
+        );
+    }
     panic!(
         "EXCEPTION: DOUBLE FAULT ERR:{}\n{:#?}",
         _error_code, stack_frame
@@ -31,6 +68,7 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptFrame) {
 }
 
 extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptFrame) {
+    TICKS.fetch_add(1, Ordering::Relaxed);
     PICS.obtain()
         .notify_end_of_interrupt(InterruptIndex::Timer as u8);
 }
@@ -43,10 +81,30 @@ extern "x86-interrupt" fn keyboard_handler(_stack_frame: InterruptFrame) {
         .notify_end_of_interrupt(InterruptIndex::Keyboard as u8);
 }
 extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptFrame, error_code: u64) {
-    let address: usize;
-    // SAFETY: cr2 contains invalid_access when triggering a page fault
-    unsafe {
-        asm!("mov {}, cr2", out(reg) address);
+    let address = fault_address();
+
+    // Accesses to pages reserved but not yet backed are retried once backed,
+    // writes to copy-on-write pages once copied
+    let res = if error_code & PageFaultError::ProtectionViolation as u64 == 0 {
+        address_space::handle_page_fault(address)
+    } else if error_code & PageFaultError::Write as u64 != 0 {
+        address_space::handle_write_fault(address)
+    } else {
+        Err(FaultError::Invalid)
+    };
+    match res {
+        Ok(()) => return,
+        Err(FaultError::Locked) => panic!(
+            "EXCEPTION: PAGE FAULT at {:#x} while the memory manager was locked\nError Code: {:?}\n{:#?}",
+            address, error_code, stack_frame
+        ),
+        Err(FaultError::Invalid) => {}
+    }
+    if stack::is_guard_page(address) {
+        panic!(
+            "EXCEPTION: stack overflow, accessed guard page at {:#x}\n{:#?}",
+            address, stack_frame
+        );
     }
 
     println!("EXCEPTION: PAGE FAULT");
@@ -57,11 +115,15 @@ extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptFrame, error_
 }
 
 extern "x86-interrupt" fn ata1_handler(_stack_frame: InterruptFrame) {
-    PICS.obtain().end_all_interrupts();
+    ata::handle_interrupt(Bus::Primary);
+    PICS.obtain()
+        .notify_end_of_interrupt(InterruptIndex::PrimaryATA as u8);
 }
 
 extern "x86-interrupt" fn ata2_handler(_stack_frame: InterruptFrame) {
-    PICS.obtain().end_all_interrupts();
+    ata::handle_interrupt(Bus::Secondary);
+    PICS.obtain()
+        .notify_end_of_interrupt(InterruptIndex::SecondaryATA as u8);
 }
 
 #[derive(Default, Copy, Clone)]
@@ -69,7 +131,8 @@ extern "x86-interrupt" fn ata2_handler(_stack_frame: InterruptFrame) {
 struct InterruptGate {
     offset_low: u16,
     segment: u16,
-    reserved: u8,
+    /// Interrupt stack table index, 0 keeps the current stack
+    ist: u8,
     /// type : 5;
     /// privilege_level : 2;
     /// segment_present : 1;
@@ -94,7 +157,7 @@ impl InterruptGate {
         InterruptGate {
             offset_low: offset as u16,
             segment: KERNEL_CODE_SEG.into(),
-            reserved: 0,
+            ist: 0,
             flags: gate_type as u8 | (KERNEL_CODE_SEG.get_privilege() << 5) | SEG_PRESENT,
             offset_mid: (offset >> 16) as u16,
             offset_high: (offset >> 32) as u32,
@@ -127,6 +190,19 @@ impl Idt {
         self
     }
 
+    /// Inserts a handler running on the stack `ist` of the TSS
+    pub fn insert_with_stack(
+        mut self,
+        index: usize,
+        interrupt_handler: usize,
+        gate_type: GateType,
+        ist: u8,
+    ) -> Idt {
+        self = self.insert(index, interrupt_handler, gate_type);
+        self.entries[index].ist = ist;
+        self
+    }
+
     /// IDT must be valid
     pub unsafe fn load(self) {
         static mut IDT: MaybeUninit<Idt> = MaybeUninit::uninit();
@@ -168,7 +244,12 @@ pub fn init() {
     use InterruptIndex::*;
     let idt = Idt::new()
         .insert(Breakpoint.into(), breakpoint_handler as usize, TrapGate)
-        .insert(DoubleFault.into(), double_fault_handler as usize, TrapGate)
+        .insert_with_stack(
+            DoubleFault.into(),
+            double_fault_handler as usize,
+            TrapGate,
+            DOUBLE_FAULT_IST,
+        )
         .insert(PageFault.into(), page_fault_handler as usize, TrapGate)
         .insert(Timer.into(), timer_handler as usize, IntGate)
         .insert(Keyboard.into(), keyboard_handler as usize, IntGate)
@@ -187,3 +268,46 @@ pub fn enable() {
         asm!("sti");
     }
 }
+
+pub fn disable() {
+    // SAFETY: This operation cannot fail
+    unsafe {
+        asm!("cli");
+    }
+}
+
+/// Enables interrupts and halts until the next one
+/// An interrupt cannot slip in between, sti only takes effect after hlt
+pub fn enable_and_halt() {
+    // SAFETY: Operation halts until next external interrupt
+    unsafe {
+        asm!("sti", "hlt");
+    }
+}
+
+/// Returns the number of timer interrupts since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns true if interrupts are enabled
+pub fn are_enabled() -> bool {
+    const INTERRUPT_FLAG: u64 = 1 << 9;
+    let rflags: u64;
+    // SAFETY: Reading rflags has no side effect
+    unsafe {
+        asm!("pushfq", "pop {}", out(reg) rflags);
+    }
+    rflags & INTERRUPT_FLAG != 0
+}
+
+/// Runs `f` with interrupts disabled, restoring them afterwards
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let were_enabled = are_enabled();
+    disable();
+    let res = f();
+    if were_enabled {
+        enable();
+    }
+    res
+}
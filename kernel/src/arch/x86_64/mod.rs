@@ -3,12 +3,16 @@
 pub mod ata;
 pub mod gdt;
 pub mod interrupt;
+pub mod msr;
 pub mod paging;
 pub mod pci;
 pub mod pic;
 pub mod port;
 pub mod serial;
 
+use crate::memory_manager::stack::KernelStack;
+use core::mem;
+
 #[allow(dead_code)]
 #[repr(u32)]
 pub enum QemuExitCode {
@@ -26,6 +30,21 @@ pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
     halt()
 }
 
+/// Runs `entry` on `stack`, which is never freed
+pub fn switch_stack(stack: KernelStack, entry: fn() -> !) -> ! {
+    let top = stack.top();
+    mem::forget(stack);
+    // SAFETY: The stack is mapped for good and entry never returns
+    unsafe {
+        asm!("mov rsp, {}",
+             "xor rbp, rbp",
+             "call {}",
+             in(reg) top,
+             in(reg) entry,
+             options(noreturn));
+    }
+}
+
 pub fn halt() -> ! {
     loop {
         // SAFETY: Operation halts until next external interrupt
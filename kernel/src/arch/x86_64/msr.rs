@@ -0,0 +1,21 @@
+//! Model specific registers
+
+/// Reads the model specific register `msr`
+///
+/// # Safety
+///
+/// `msr` must exist on this CPU
+pub unsafe fn read(msr: u32) -> u64 {
+    let (high, low): (u32, u32);
+    asm!("rdmsr", in("ecx") msr, out("edx") high, out("eax") low);
+    (high as u64) << 32 | low as u64
+}
+
+/// Writes `value` to the model specific register `msr`
+///
+/// # Safety
+///
+/// `msr` must exist on this CPU and `value` must be valid for it
+pub unsafe fn write(msr: u32, value: u64) {
+    asm!("wrmsr", in("ecx") msr, in("edx") (value >> 32) as u32, in("eax") value as u32);
+}
@@ -1,11 +1,23 @@
 //! The memory map has been pre-filled by our bootloader
 //! This module allows us to access this memory map
 
+use crate::memory_manager::layout::low_memory;
 use core::ptr::{read_unaligned, read_volatile};
 
 const REGION_LENGTH: usize = 0x500;
 const REGION_MAP: usize = 0x504;
 
+/// E820 region types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionType {
+    Usable,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    BadMemory,
+    Unknown(u32),
+}
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct Region {
@@ -19,15 +31,31 @@ impl Region {
     pub fn end(&self) -> usize {
         self.base_addr + self.length - 1
     }
+
+    pub fn region_type(&self) -> RegionType {
+        match self.region_type {
+            1 => RegionType::Usable,
+            2 => RegionType::Reserved,
+            3 => RegionType::AcpiReclaimable,
+            4 => RegionType::AcpiNvs,
+            5 => RegionType::BadMemory,
+            region_type => RegionType::Unknown(region_type),
+        }
+    }
 }
 
 /// Returns the memory map's region count
 pub fn region_count() -> u32 {
-    unsafe { read_volatile(REGION_LENGTH as *const u32) }
+    unsafe { read_volatile(low_memory(REGION_LENGTH) as *const u32) }
 }
 
 /// Returns a specific region
 pub fn get_region(index: u32) -> Region {
     let offset = 24 * index as usize;
-    unsafe { read_unaligned((REGION_MAP + offset) as *const Region) }
+    unsafe { read_unaligned(low_memory(REGION_MAP + offset) as *const Region) }
+}
+
+/// Returns an iterator over every region of the memory map
+pub fn regions() -> impl Iterator<Item = Region> {
+    (0..region_count()).map(get_region)
 }
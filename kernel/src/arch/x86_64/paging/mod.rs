@@ -1,2 +1,3 @@
 pub mod memory_map;
+pub mod pat;
 pub mod tables;
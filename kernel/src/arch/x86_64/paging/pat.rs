@@ -0,0 +1,135 @@
+//! Page Attribute Table, it selects the caching policy of every page
+//! The PAT, PCD and PWT bits of an entry form an index in the table
+
+use super::super::{interrupt, msr};
+use super::tables::EntryFlag;
+
+const IA32_PAT: u32 = 0x277;
+
+/// Memory types of the PAT
+const UNCACHEABLE: u64 = 0x00;
+const WRITE_COMBINING: u64 = 0x01;
+const WRITE_THROUGH: u64 = 0x04;
+const WRITE_BACK: u64 = 0x06;
+const UNCACHED: u64 = 0x07;
+
+/// Power-on layout for indices 0 to 3, write-combining at index 4
+const LAYOUT: [u64; 8] = [
+    WRITE_BACK,
+    WRITE_THROUGH,
+    UNCACHED,
+    UNCACHEABLE,
+    WRITE_COMBINING,
+    WRITE_THROUGH,
+    UNCACHED,
+    UNCACHEABLE,
+];
+
+/// PAT bit of 4KiB entries, level 3 and 2 entries use this bit to mark huge pages
+pub const PAT_4KIB: u64 = 1 << 7;
+/// Flags of a 4KiB entry that select its cache type
+pub const CACHE_FLAGS_4KIB: u64 =
+    EntryFlag::WriteThrough as u64 | EntryFlag::NoCache as u64 | PAT_4KIB;
+
+/// Caching policy of a mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    WriteBack,
+    WriteThrough,
+    Uncached,
+    /// Writes are buffered and merged, suited to framebuffers
+    WriteCombining,
+}
+
+impl CacheType {
+    /// Returns the flags selecting this cache type in a 4KiB entry
+    pub fn flags(self) -> u64 {
+        match self {
+            CacheType::WriteBack => 0,
+            CacheType::WriteThrough => EntryFlag::WriteThrough as u64,
+            CacheType::Uncached => EntryFlag::WriteThrough as u64 | EntryFlag::NoCache as u64,
+            CacheType::WriteCombining => PAT_4KIB,
+        }
+    }
+}
+
+/// Programs the PAT, pages using indices 0 to 3 keep their memory type
+/// As the SDM requires, caches and TLBs are flushed with caching disabled around the write
+pub fn init() {
+    let pat = LAYOUT
+        .iter()
+        .enumerate()
+        .fold(0, |pat, (index, memory_type)| {
+            pat | memory_type << (index * 8)
+        });
+
+    interrupt::without_interrupts(|| {
+        // SAFETY: Every x86_64 CPU has a PAT, only index 4 changes and no page uses it yet,
+        // caching is enabled again right after
+        unsafe {
+            let cr0 = disable_caches();
+            msr::write(IA32_PAT, pat);
+            restore_caches(cr0);
+        }
+    });
+}
+
+/// Enters no-fill cache mode and flushes the caches and TLBs
+/// Returns cr0 as it was before
+unsafe fn disable_caches() -> u64 {
+    const CACHE_DISABLE: u64 = 1 << 30;
+    const NOT_WRITE_THROUGH: u64 = 1 << 29;
+
+    let cr0: u64;
+    asm!("mov {}, cr0", out(reg) cr0);
+    asm!("mov cr0, {}",
+         "wbinvd",
+         in(reg) (cr0 | CACHE_DISABLE) & !NOT_WRITE_THROUGH);
+    flush_tlbs();
+    cr0
+}
+
+/// Flushes the caches and TLBs filled with the old PAT, then restores `cr0`
+unsafe fn restore_caches(cr0: u64) {
+    asm!("wbinvd");
+    flush_tlbs();
+    asm!("mov cr0, {}", in(reg) cr0);
+}
+
+/// Flushes every TLB entry, global ones included, by toggling CR4.PGE
+unsafe fn flush_tlbs() {
+    const PAGE_GLOBAL_ENABLE: u64 = 1 << 7;
+    asm!("mov {0}, cr4",
+         "xor {0}, {1}",
+         "mov cr4, {0}",
+         "xor {0}, {1}",
+         "mov cr4, {0}",
+         out(reg) _,
+         in(reg) PAGE_GLOBAL_ENABLE);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn pat_layout() {
+        let pat = unsafe { msr::read(IA32_PAT) };
+        assert_eq!(pat, 0x0007_0401_0007_0406);
+    }
+
+    #[test_case]
+    fn cache_type_index() {
+        // Index = PAT * 4 + PCD * 2 + PWT
+        let index = |cache: CacheType| {
+            let flags = cache.flags();
+            (flags & PAT_4KIB != 0) as usize * 4
+                + (flags & EntryFlag::NoCache as u64 != 0) as usize * 2
+                + (flags & EntryFlag::WriteThrough as u64 != 0) as usize
+        };
+        assert_eq!(LAYOUT[index(CacheType::WriteBack)], WRITE_BACK);
+        assert_eq!(LAYOUT[index(CacheType::WriteThrough)], WRITE_THROUGH);
+        assert_eq!(LAYOUT[index(CacheType::Uncached)], UNCACHEABLE);
+        assert_eq!(LAYOUT[index(CacheType::WriteCombining)], WRITE_COMBINING);
+    }
+}
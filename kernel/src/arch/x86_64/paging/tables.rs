@@ -1,10 +1,18 @@
+use super::pat;
 use crate::memory_manager::frame::Frame;
 use crate::memory_manager::frame_allocator::FrameAllocator;
 use crate::memory_manager::PAGE_SIZE;
+use core::arch::x86_64::__cpuid;
 use core::marker::PhantomData;
 use core::ops::{Index, IndexMut};
 
-const ENTRY_COUNT: usize = 512;
+pub const ENTRY_COUNT: usize = 512;
+const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+/// Level 4 entry pointing to the level 4 table itself
+pub const RECURSIVE_INDEX: usize = 510;
+
+/// Number of 4KiB pages in the 48bit virtual address space
+const PAGE_COUNT: usize = 1 << 36;
 
 #[allow(dead_code)]
 #[repr(u64)]
@@ -18,28 +26,82 @@ pub enum EntryFlag {
     Dirty = 1 << 6,
     HugePage = 1 << 7,
     Global = 1 << 8,
+    /// Available bit, the page is shared read-only until written to
+    CopyOnWrite = 1 << 9,
     NoExecute = 1 << 63,
 }
 
+/// Size of the page mapped by a leaf entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl PageSize {
+    /// Returns the page size in bytes
+    pub const fn bytes(self) -> usize {
+        self.pages() * PAGE_SIZE
+    }
+
+    /// Returns the number of 4KiB pages covered
+    pub const fn pages(self) -> usize {
+        match self {
+            PageSize::Size4KiB => 1,
+            PageSize::Size2MiB => ENTRY_COUNT,
+            PageSize::Size1GiB => ENTRY_COUNT * ENTRY_COUNT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// The page is already mapped
+    AlreadyMapped(usize),
+    /// The page is not mapped
+    NotMapped(usize),
+    /// The page lies inside a huge page, or a huge page would cover existing tables
+    HugePageConflict(usize),
+    /// An address or size is not aligned to the requested page size
+    Unaligned(usize),
+    /// No frame was available to create a table
+    OutOfFrames,
+}
+
 #[repr(transparent)]
 pub struct Entry(u64);
 
 impl Entry {
     /// Frees entry
-    #[allow(dead_code)]
     pub fn set_unused(&mut self) {
         self.0 = 0;
     }
 
+    /// Returns true if entry is free
+    pub fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+
     /// Returns true if entry contains given flags
     pub fn contains(&self, flags: u64) -> bool {
         self.0 & flags != 0
     }
 
+    /// Returns true if entry maps a huge page instead of pointing to a table
+    pub fn is_huge(&self) -> bool {
+        self.contains(EntryFlag::Present as u64) && self.contains(EntryFlag::HugePage as u64)
+    }
+
+    /// Returns the flags of the entry
+    pub fn flags(&self) -> u64 {
+        self.0 & !ADDRESS_MASK
+    }
+
     /// Returns Frame containing address of entry
     pub fn address(&self) -> Option<Frame> {
         if self.contains(EntryFlag::Present as u64) {
-            Some(Frame::from_address(self.0 as usize & 0x000F_FFFF_FFFF_F000))
+            Some(Frame::from_address((self.0 & ADDRESS_MASK) as usize))
         } else {
             None
         }
@@ -49,6 +111,23 @@ impl Entry {
     pub fn set(&mut self, frame: Frame, flags: u64) {
         self.0 = frame.base_addr as u64 | flags;
     }
+
+    /// Replaces the flags of the entry, keeping its address
+    pub fn set_flags(&mut self, flags: u64) {
+        self.0 = (self.0 & ADDRESS_MASK) | flags;
+    }
+
+    /// Returns true if entry is a copy-on-write page
+    pub fn is_copy_on_write(&self) -> bool {
+        self.contains(EntryFlag::CopyOnWrite as u64)
+    }
+
+    /// Makes a writable page read-only until written to
+    pub fn set_copy_on_write(&mut self) {
+        if self.contains(EntryFlag::Writable as u64) {
+            self.0 = (self.0 & !(EntryFlag::Writable as u64)) | EntryFlag::CopyOnWrite as u64;
+        }
+    }
 }
 
 /// Table Level
@@ -111,7 +190,6 @@ where
     L: TableLevel,
 {
     /// Empties table
-    #[allow(dead_code)]
     pub fn zero(&mut self) {
         for entry in self.entries.iter_mut() {
             entry.set_unused();
@@ -121,7 +199,7 @@ where
 
 // Returns the level 4 table by doing 4 recursion on level 4 table
 pub fn get_level4() -> &'static mut Table<Level4> {
-    unsafe { &mut *(0xFFFF_FFFF_FFFF_F000 as *mut Table<Level4>) }
+    unsafe { &mut *(0xFFFF_FF7F_BFDF_E000 as *mut Table<Level4>) }
 }
 
 /// Methods that only apply to tables that point to other tables
@@ -131,9 +209,10 @@ where
 {
     /// Get the next table address
     fn next_table_addr(&self, index: usize) -> Option<usize> {
-        if self[index].contains(EntryFlag::Present as u64) {
+        let entry = &self[index];
+        if entry.contains(EntryFlag::Present as u64) && !entry.is_huge() {
             let table_addr = (self as *const _) as usize;
-            Some((table_addr << 9) | (index << 12))
+            Some(canonical((table_addr << 9) | (index << 12)))
         } else {
             None
         }
@@ -149,22 +228,26 @@ where
             .map(|address| unsafe { &mut *(address as *mut Table<L::NextLevel>) })
     }
 
+    /// Returns the next table, allocating it if needed
+    /// `flags` are added to the entry pointing to the table
+    /// The entry at `index` must not map a huge page
     fn create_table(
         &mut self,
         index: usize,
+        flags: u64,
         allocator: &mut FrameAllocator,
-    ) -> &mut Table<L::NextLevel> {
+    ) -> Result<&mut Table<L::NextLevel>, MapError> {
+        debug_assert!(!self[index].is_huge());
         if self.next_table(index).is_none() {
-            let frame = allocator
-                .allocate_frame()
-                .expect("Could not allocate any frame");
-            self[index].set(
-                frame,
-                (EntryFlag::Present as u64) | (EntryFlag::Writable as u64),
-            );
+            let frame = allocator.allocate_frame().ok_or(MapError::OutOfFrames)?;
+            self[index].set(frame, flags);
+            self.next_table_mut(index).unwrap().zero();
+        } else {
+            let flags = self[index].flags() | flags;
+            self[index].set_flags(flags);
         }
 
-        self.next_table_mut(index).unwrap()
+        Ok(self.next_table_mut(index).unwrap())
     }
 }
 
@@ -188,29 +271,478 @@ where
     }
 }
 
-#[allow(dead_code)]
+/// Walks the page tables down to the leaf entry of `page`
+/// On a missing table, returns the number of pages that table would have covered
+fn walk(page: usize) -> Result<(&'static mut Entry, PageSize), usize> {
+    const P3_PAGES: usize = ENTRY_COUNT * ENTRY_COUNT * ENTRY_COUNT;
+
+    if Level4::index(page) == RECURSIVE_INDEX {
+        return Err(P3_PAGES);
+    }
+    let p3 = get_level4()
+        .next_table_mut(Level4::index(page))
+        .ok_or(P3_PAGES)?;
+
+    let index = Level3::index(page);
+    if p3[index].is_huge() {
+        return Ok((&mut p3[index], PageSize::Size1GiB));
+    }
+    let p2 = p3.next_table_mut(index).ok_or(PageSize::Size1GiB.pages())?;
+
+    let index = Level2::index(page);
+    if p2[index].is_huge() {
+        return Ok((&mut p2[index], PageSize::Size2MiB));
+    }
+    let p1 = p2.next_table_mut(index).ok_or(PageSize::Size2MiB.pages())?;
+
+    let entry = &mut p1[Level1::index(page)];
+    match entry.contains(EntryFlag::Present as u64) {
+        true => Ok((entry, PageSize::Size4KiB)),
+        false => Err(1),
+    }
+}
+
+/// Returns true if the CPU can map 1GiB pages
+pub fn supports_1gib_pages() -> bool {
+    const PDPE1GB: u32 = 1 << 26;
+    // SAFETY: Extended function 0x80000001 is available on every x86_64 CPU
+    unsafe { __cpuid(0x8000_0001).edx & PDPE1GB != 0 }
+}
+
+/// Sign extends bit 47 of `addr` to make it canonical
+fn canonical(addr: usize) -> usize {
+    (((addr << 16) as isize) >> 16) as usize
+}
+
+/// Invalidates the TLB entry of the page containing `virt_addr`
+fn flush(virt_addr: usize) {
+    // SAFETY: invlpg only drops a cached translation
+    unsafe {
+        asm!("invlpg [{}]", in(reg) virt_addr);
+    }
+}
+
+/// Returns the frame of the active level 4 table
+pub fn active_level4() -> Frame {
+    let cr3: usize;
+    // SAFETY: Reading cr3 has no side effect
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3);
+    }
+    Frame::from_address(cr3 & ADDRESS_MASK as usize)
+}
+
+/// Makes `frame` the active level 4 table
+///
+/// # Safety
+///
+/// `frame` must hold a level 4 table mapping the kernel
+pub unsafe fn set_active_level4(frame: Frame) {
+    asm!("mov cr3, {}", in(reg) frame.base_addr);
+}
+
+/// Invalidates every non global TLB entry
+pub fn flush_all() {
+    // SAFETY: Reloading cr3 with its own value only drops cached translations
+    unsafe {
+        asm!("mov {0}, cr3", "mov cr3, {0}", out(reg) _);
+    }
+}
+
+/// Translates a virtual address to the physical address it is mapped to
 pub fn translate_addr(virt_addr: usize) -> Option<usize> {
-    let offset = virt_addr % PAGE_SIZE;
-    let page = virt_addr / PAGE_SIZE;
+    let (entry, size) = walk(virt_addr / PAGE_SIZE).ok()?;
+    let base = entry.address()?.base_addr & !(size.bytes() - 1);
+    Some(base + virt_addr % size.bytes())
+}
 
-    let p4 = get_level4();
-    p4.next_table(Level4::index(page))
-        .and_then(|p3| p3.next_table(Level3::index(page)))
-        .and_then(|p2| p2.next_table(Level2::index(page)))
-        .and_then(|p1| p1[Level1::index(page)].address())
-        .map(|frame| frame.base_addr + offset)
+/// Maps a single 4KiB page at `virt_addr` to `phys_addr`
+pub fn map_to(
+    virt_addr: usize,
+    phys_addr: usize,
+    flags: u64,
+    allocator: &mut FrameAllocator,
+) -> Result<(), MapError> {
+    map_page(virt_addr, phys_addr, PageSize::Size4KiB, flags, allocator)
 }
 
-pub fn map_to(virt_addr: usize, phys_addr: usize, flags: u64, allocator: &mut FrameAllocator) {
+/// Maps `size` bytes starting at `virt_addr` to `phys_addr` using `page_size` pages
+/// Addresses and size must be aligned to `page_size`
+/// 1GiB pages are only available if `supports_1gib_pages` returns true
+/// Nothing is left mapped if an error occurs
+pub fn map_range(
+    virt_addr: usize,
+    phys_addr: usize,
+    size: usize,
+    page_size: PageSize,
+    flags: u64,
+    allocator: &mut FrameAllocator,
+) -> Result<(), MapError> {
+    let align = page_size.bytes();
+    for addr in [virt_addr, phys_addr, size] {
+        if addr % align != 0 {
+            return Err(MapError::Unaligned(addr));
+        }
+    }
+
+    for offset in (0..size).step_by(align) {
+        let res = map_page(
+            virt_addr + offset,
+            phys_addr + offset,
+            page_size,
+            flags,
+            allocator,
+        );
+        if let Err(err) = res {
+            for mapped in (0..offset).step_by(align) {
+                unmap(virt_addr + mapped).expect("Page mapped by map_range disappeared");
+            }
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+fn map_page(
+    virt_addr: usize,
+    phys_addr: usize,
+    page_size: PageSize,
+    flags: u64,
+    allocator: &mut FrameAllocator,
+) -> Result<(), MapError> {
     let page = virt_addr / PAGE_SIZE;
+    let flags = flags | EntryFlag::Present as u64;
+    let frame = Frame::from_address(phys_addr);
+    // Tables leading to user pages have to be user accessible too
+    let table_flags = EntryFlag::Present as u64
+        | EntryFlag::Writable as u64
+        | (flags & EntryFlag::UserAccessible as u64);
 
-    get_level4()
-        .create_table(Level4::index(page), allocator)
-        .create_table(Level3::index(page), allocator)
-        .create_table(Level2::index(page), allocator)
-        .index_mut(Level1::index(page))
-        .set(
-            Frame::from_address(phys_addr),
-            flags | EntryFlag::Present as u64,
+    let p3 = get_level4().create_table(Level4::index(page), table_flags, allocator)?;
+    let index = Level3::index(page);
+    let entry = if page_size == PageSize::Size1GiB {
+        &mut p3[index]
+    } else if p3[index].is_huge() {
+        return Err(MapError::HugePageConflict(virt_addr));
+    } else {
+        let p2 = p3.create_table(index, table_flags, allocator)?;
+        let index = Level2::index(page);
+        if page_size == PageSize::Size2MiB {
+            &mut p2[index]
+        } else if p2[index].is_huge() {
+            return Err(MapError::HugePageConflict(virt_addr));
+        } else {
+            &mut p2.create_table(index, table_flags, allocator)?[Level1::index(page)]
+        }
+    };
+
+    if !entry.is_unused() {
+        return Err(match page_size {
+            PageSize::Size4KiB => MapError::AlreadyMapped(virt_addr),
+            _ => MapError::HugePageConflict(virt_addr),
+        });
+    }
+
+    match page_size {
+        PageSize::Size4KiB => entry.set(frame, flags),
+        _ => entry.set(frame, flags | EntryFlag::HugePage as u64),
+    }
+    Ok(())
+}
+
+/// Unmaps the page containing `virt_addr`
+/// Returns the frame it was mapped to, tables are kept even if they become empty
+pub fn unmap(virt_addr: usize) -> Result<(Frame, PageSize), MapError> {
+    let (entry, size) = walk(virt_addr / PAGE_SIZE).map_err(|_| MapError::NotMapped(virt_addr))?;
+    let frame = entry.address().ok_or(MapError::NotMapped(virt_addr))?;
+
+    entry.set_unused();
+    flush(virt_addr);
+    Ok((frame, size))
+}
+
+/// Replaces the flags of every page in [virt_addr, virt_addr + size[
+/// Pages keep their frame, size and cache type, fails if any page is not mapped
+/// or is a huge page the range does not fully cover, in which case no page is changed
+pub fn protect(virt_addr: usize, size: usize, flags: u64) -> Result<(), MapError> {
+    let start = virt_addr / PAGE_SIZE * PAGE_SIZE;
+    let end = (virt_addr + size + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+    let mut addr = start;
+    while addr < end {
+        let (_, page_size) = walk(addr / PAGE_SIZE).map_err(|_| MapError::NotMapped(addr))?;
+        let page = addr & !(page_size.bytes() - 1);
+        if page < start || page + page_size.bytes() > end {
+            return Err(MapError::HugePageConflict(addr));
+        }
+        addr = page + page_size.bytes();
+    }
+
+    let mut addr = start;
+    while addr < end {
+        let (entry, page_size) = walk(addr / PAGE_SIZE).map_err(|_| MapError::NotMapped(addr))?;
+
+        // Bit 7 selects the PAT in 4KiB entries, it marks huge pages in level 3 and 2 entries
+        // The PAT bit of huge pages sits among the address bits, which are kept
+        let kept = match page_size {
+            PageSize::Size4KiB => pat::CACHE_FLAGS_4KIB,
+            _ => {
+                EntryFlag::HugePage as u64
+                    | EntryFlag::WriteThrough as u64
+                    | EntryFlag::NoCache as u64
+            }
+        };
+        entry.set_flags(flags & !kept | entry.flags() & kept | EntryFlag::Present as u64);
+        flush(addr);
+
+        addr += page_size.bytes();
+    }
+    Ok(())
+}
+
+/// Returns the frame of the copy-on-write page containing `virt_addr`
+pub fn copy_on_write_frame(virt_addr: usize) -> Option<Frame> {
+    match walk(virt_addr / PAGE_SIZE) {
+        Ok((entry, PageSize::Size4KiB)) if entry.is_copy_on_write() => entry.address(),
+        _ => None,
+    }
+}
+
+/// Makes the copy-on-write page containing `virt_addr` writable again, mapped to `frame`
+pub fn break_copy_on_write(virt_addr: usize, frame: Frame) -> Result<(), MapError> {
+    match walk(virt_addr / PAGE_SIZE) {
+        Ok((entry, PageSize::Size4KiB)) if entry.is_copy_on_write() => {
+            let flags = entry.flags() & !(EntryFlag::CopyOnWrite as u64);
+            entry.set(frame, flags | EntryFlag::Writable as u64);
+            flush(virt_addr);
+            Ok(())
+        }
+        _ => Err(MapError::NotMapped(virt_addr)),
+    }
+}
+
+/// A contiguous range of virtual memory mapped to contiguous physical memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRegion {
+    pub virt_addr: usize,
+    pub phys_addr: usize,
+    pub size: usize,
+    pub flags: u64,
+}
+
+/// Returns an iterator over every mapped region of the current address space
+/// Adjacent pages sharing flags are merged, the recursive mapping is skipped
+pub fn mapped_regions() -> MappedRegions {
+    MappedRegions {
+        page: 0,
+        pending: None,
+    }
+}
+
+pub struct MappedRegions {
+    page: usize,
+    pending: Option<MappedRegion>,
+}
+
+impl MappedRegions {
+    /// Returns the next mapped leaf entry as a region
+    fn next_leaf(&mut self) -> Option<MappedRegion> {
+        const IGNORED_FLAGS: u64 = EntryFlag::Accessed as u64 | EntryFlag::Dirty as u64;
+
+        while self.page < PAGE_COUNT {
+            match walk(self.page) {
+                Ok((entry, size)) => {
+                    let start = self.page & !(size.pages() - 1);
+                    self.page = start + size.pages();
+                    return Some(MappedRegion {
+                        virt_addr: canonical(start * PAGE_SIZE),
+                        phys_addr: entry.address()?.base_addr & !(size.bytes() - 1),
+                        size: size.bytes(),
+                        flags: entry.flags() & !IGNORED_FLAGS,
+                    });
+                }
+                Err(skipped) => self.page = (self.page & !(skipped - 1)) + skipped,
+            }
+        }
+        None
+    }
+}
+
+impl Iterator for MappedRegions {
+    type Item = MappedRegion;
+
+    fn next(&mut self) -> Option<MappedRegion> {
+        let mut region = self.pending.take().or_else(|| self.next_leaf())?;
+
+        while let Some(leaf) = self.next_leaf() {
+            if leaf.virt_addr == region.virt_addr + region.size
+                && leaf.phys_addr == region.phys_addr + region.size
+                && leaf.flags == region.flags
+            {
+                region.size += leaf.size;
+            } else {
+                self.pending = Some(leaf);
+                break;
+            }
+        }
+        Some(region)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory_manager::ALLOCATOR;
+    use core::ptr::read_volatile;
+
+    const TEST_ADDR: usize = 0x4000_0000;
+
+    #[test_case]
+    fn map_translate_unmap() {
+        let frame = ALLOCATOR.obtain().allocate_frame().unwrap();
+        let flags = EntryFlag::Writable as u64;
+
+        map_to(TEST_ADDR, frame.base_addr, flags, &mut ALLOCATOR.obtain()).unwrap();
+        assert_eq!(
+            translate_addr(TEST_ADDR + 0x123),
+            Some(frame.base_addr + 0x123)
+        );
+
+        assert_eq!(unmap(TEST_ADDR), Ok((frame, PageSize::Size4KiB)));
+        assert_eq!(translate_addr(TEST_ADDR), None);
+        assert_eq!(unmap(TEST_ADDR), Err(MapError::NotMapped(TEST_ADDR)));
+    }
+
+    #[test_case]
+    fn copy_on_write() {
+        let mut allocator = ALLOCATOR.obtain();
+        let shared = allocator.allocate_frame().unwrap();
+        let copy = allocator.allocate_frame().unwrap();
+
+        map_to(
+            TEST_ADDR,
+            shared.base_addr,
+            EntryFlag::Writable as u64,
+            &mut allocator,
+        )
+        .unwrap();
+        assert_eq!(copy_on_write_frame(TEST_ADDR), None);
+        walk(TEST_ADDR / PAGE_SIZE).unwrap().0.set_copy_on_write();
+        flush(TEST_ADDR);
+        assert_eq!(copy_on_write_frame(TEST_ADDR), Some(shared));
+
+        break_copy_on_write(TEST_ADDR, copy).unwrap();
+        assert_eq!(copy_on_write_frame(TEST_ADDR), None);
+        assert_eq!(translate_addr(TEST_ADDR), Some(copy.base_addr));
+        unmap(TEST_ADDR).unwrap();
+    }
+
+    #[test_case]
+    fn map_twice() {
+        let frame = ALLOCATOR.obtain().allocate_frame().unwrap();
+        let flags = EntryFlag::Writable as u64;
+
+        map_to(TEST_ADDR, frame.base_addr, flags, &mut ALLOCATOR.obtain()).unwrap();
+        assert_eq!(
+            map_to(TEST_ADDR, frame.base_addr, flags, &mut ALLOCATOR.obtain()),
+            Err(MapError::AlreadyMapped(TEST_ADDR))
         );
+        unmap(TEST_ADDR).unwrap();
+    }
+
+    #[test_case]
+    fn protect_page() {
+        let frame = ALLOCATOR.obtain().allocate_frame().unwrap();
+
+        map_to(TEST_ADDR, frame.base_addr, 0, &mut ALLOCATOR.obtain()).unwrap();
+        protect(TEST_ADDR, PAGE_SIZE, EntryFlag::Writable as u64).unwrap();
+
+        let (entry, _) = walk(TEST_ADDR / PAGE_SIZE).unwrap();
+        assert!(entry.contains(EntryFlag::Writable as u64));
+        assert_eq!(entry.address(), Some(frame));
+        unmap(TEST_ADDR).unwrap();
+    }
+
+    #[test_case]
+    fn protect_keeps_cache_type() {
+        let frame = ALLOCATOR.obtain().allocate_frame().unwrap();
+        let flags = pat::CacheType::WriteCombining.flags();
+
+        map_to(TEST_ADDR, frame.base_addr, flags, &mut ALLOCATOR.obtain()).unwrap();
+        protect(TEST_ADDR, PAGE_SIZE, EntryFlag::Writable as u64).unwrap();
+        let (entry, size) = walk(TEST_ADDR / PAGE_SIZE).unwrap();
+        assert_eq!(size, PageSize::Size4KiB);
+        assert_eq!(entry.flags() & pat::CACHE_FLAGS_4KIB, flags);
+        unmap(TEST_ADDR).unwrap();
+        ALLOCATOR.obtain().deallocate_frame(frame);
+
+        let size = PageSize::Size2MiB;
+        let virt_addr = TEST_ADDR + size.bytes();
+        map_range(virt_addr, 0, size.bytes(), size, 0, &mut ALLOCATOR.obtain()).unwrap();
+        protect(virt_addr, size.bytes(), 0).unwrap();
+        assert_eq!(walk(virt_addr / PAGE_SIZE).unwrap().1, size);
+        unmap(virt_addr).unwrap();
+    }
+
+    #[test_case]
+    fn protect_part_of_huge_page() {
+        let size = PageSize::Size2MiB;
+        let virt_addr = TEST_ADDR + size.bytes();
+        map_range(virt_addr, 0, size.bytes(), size, 0, &mut ALLOCATOR.obtain()).unwrap();
+        map_to(TEST_ADDR, 0, 0, &mut ALLOCATOR.obtain()).unwrap();
+
+        // Nothing changes, not even the pages before the huge one
+        let writable = EntryFlag::Writable as u64;
+        assert_eq!(
+            protect(TEST_ADDR, 2 * PAGE_SIZE, writable),
+            Err(MapError::NotMapped(TEST_ADDR + PAGE_SIZE))
+        );
+        assert_eq!(
+            protect(virt_addr, PAGE_SIZE, writable),
+            Err(MapError::HugePageConflict(virt_addr))
+        );
+        assert!(!walk(TEST_ADDR / PAGE_SIZE).unwrap().0.contains(writable));
+        assert!(!walk(virt_addr / PAGE_SIZE).unwrap().0.contains(writable));
+
+        unmap(virt_addr).unwrap();
+        unmap(TEST_ADDR).unwrap();
+    }
+
+    #[test_case]
+    fn huge_page() {
+        let size = PageSize::Size2MiB;
+        let virt_addr = TEST_ADDR + size.bytes();
+
+        map_range(virt_addr, 0, size.bytes(), size, 0, &mut ALLOCATOR.obtain()).unwrap();
+        assert_eq!(translate_addr(virt_addr + 0x7DFE), Some(0x7DFE));
+        // The bootloader's signature is still in memory
+        assert_eq!(
+            unsafe { read_volatile((virt_addr + 0x7DFE) as *const u16) },
+            0xAA55
+        );
+
+        assert!(mapped_regions().any(|region| region.virt_addr == virt_addr
+            && region.phys_addr == 0
+            && region.size == size.bytes()));
+        assert_eq!(
+            map_to(virt_addr, 0, 0, &mut ALLOCATOR.obtain()),
+            Err(MapError::HugePageConflict(virt_addr))
+        );
+        assert_eq!(unmap(virt_addr), Ok((Frame::from_address(0), size)));
+    }
+
+    #[test_case]
+    fn unaligned_range() {
+        let size = PageSize::Size2MiB;
+        assert_eq!(
+            map_range(
+                TEST_ADDR + PAGE_SIZE,
+                0,
+                size.bytes(),
+                size,
+                0,
+                &mut ALLOCATOR.obtain()
+            ),
+            Err(MapError::Unaligned(TEST_ADDR + PAGE_SIZE))
+        );
+    }
 }
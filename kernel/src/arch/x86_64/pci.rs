@@ -1,3 +1,4 @@
+use super::paging::pat::CacheType;
 use super::port;
 use crate::driver;
 use alloc::vec::Vec;
@@ -8,9 +9,17 @@ const ENABLE: u32 = 1 << 31;
 
 const VENDOR_ID: u8 = 0;
 pub const COMMAND: u8 = 4;
+const STATUS: u8 = 6;
 const CLASS: u8 = 10;
 const HEADER_TYPE: u8 = 14;
 const BAR: u8 = 16;
+const CAPABILITIES: u8 = 0x34;
+
+/// Bit of the status register set when the function has a capability list
+const HAS_CAPABILITIES: u16 = 1 << 4;
+
+/// Bit of the header type set when the device has functions other than Zero
+const MULTI_FUNCTION: u8 = 0x80;
 
 pub fn init() {
     let devices = discover_devices();
@@ -50,10 +59,38 @@ impl Device {
         DeviceClass::new(self.read_u16(Function::Zero, CLASS))
     }
 
+    /// Returns the first function of the device of class `class`
+    pub fn find_function(&self, class: DeviceClass) -> Option<Function> {
+        let functions = if self.read_u8(Function::Zero, HEADER_TYPE) & MULTI_FUNCTION != 0 {
+            &Function::ALL[..]
+        } else {
+            &Function::ALL[..1]
+        };
+        functions.iter().copied().find(|&func| {
+            self.exists(func) && DeviceClass::new(self.read_u16(func, CLASS)) == class
+        })
+    }
+
     pub fn id(&self) -> DeviceId {
         DeviceId::new(self.read_u32(Function::Zero, VENDOR_ID))
     }
 
+    /// Returns the ID and offset of every capability of `func`
+    pub fn capabilities(&self, func: Function) -> Vec<(u8, u8)> {
+        let mut capabilities = Vec::new();
+        if self.read_u16(func, STATUS) & HAS_CAPABILITIES == 0 {
+            return capabilities;
+        }
+
+        // The bottom two bits of the pointers are reserved
+        let mut offset = self.read_u8(func, CAPABILITIES) & !0b11;
+        while offset != 0 {
+            capabilities.push((self.read_u8(func, offset), offset));
+            offset = self.read_u8(func, offset + 1) & !0b11;
+        }
+        capabilities
+    }
+
     pub fn bar(&self, func: Function, register: u8) -> Option<Bar> {
         if register >= self.nb_bars(func) {
             return None;
@@ -145,7 +182,7 @@ impl Device {
         const PCI_TO_PCI_TYPE: u8 = 1;
         const PCI_TO_CARDBUS_TYPE: u8 = 2;
 
-        match self.read_u8(func, HEADER_TYPE) {
+        match self.read_u8(func, HEADER_TYPE) & !MULTI_FUNCTION {
             LEAF_DEVICE_TYPE => 6,
             PCI_TO_PCI_TYPE => 2,
             PCI_TO_CARDBUS_TYPE => 0,
@@ -162,7 +199,7 @@ fn config_address(bus: u8, slot: u8, func: Function, offset: u8) -> u32 {
         | ENABLE
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Bar {
     MMIO {
         base: usize,
@@ -174,8 +211,22 @@ pub enum Bar {
     },
 }
 
+impl Bar {
+    /// Returns how the BAR should be cached, prefetchable memory is write-combining
+    pub fn cache_type(&self) -> CacheType {
+        match self {
+            Bar::MMIO {
+                prefetchable: true, ..
+            } => CacheType::WriteCombining,
+            _ => CacheType::Uncached,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DeviceClass {
+    IdeController,
+    SataController,
     EthernetController,
     VgaCompatibleController,
     HostBridge,
@@ -186,6 +237,8 @@ pub enum DeviceClass {
 impl DeviceClass {
     pub fn new(class: u16) -> DeviceClass {
         match class {
+            0x01_01 => DeviceClass::IdeController,
+            0x01_06 => DeviceClass::SataController,
             0x02_00 => DeviceClass::EthernetController,
             0x03_00 => DeviceClass::VgaCompatibleController,
             0x06_00 => DeviceClass::HostBridge,
@@ -195,11 +248,10 @@ impl DeviceClass {
     }
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct DeviceId {
-    vendor_id: u16,
-    device_id: u16,
+    pub vendor_id: u16,
+    pub device_id: u16,
 }
 
 impl DeviceId {
@@ -223,3 +275,16 @@ pub enum Function {
     Six,
     Seven,
 }
+
+impl Function {
+    pub const ALL: [Function; 8] = [
+        Function::Zero,
+        Function::One,
+        Function::Two,
+        Function::Three,
+        Function::Four,
+        Function::Five,
+        Function::Six,
+        Function::Seven,
+    ];
+}
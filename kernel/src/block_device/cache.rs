@@ -0,0 +1,398 @@
+use super::{check_blocks, BlockDevice, BlockError};
+use crate::utils::mutex::{Mutex, MutexGuard};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+struct CachedBlock {
+    lba: usize,
+    data: Vec<u8>,
+    /// The block was written since it was last read from or written to the device
+    dirty: bool,
+    /// The block is being written back, it cannot be evicted until it is done
+    writing: bool,
+    /// Neighbours in the LRU list, towards the most and the least recently used block
+    newer: Option<usize>,
+    older: Option<usize>,
+}
+
+/// Cached blocks, indexed by LBA and linked from the most to the least recently used
+struct Blocks {
+    slots: Vec<CachedBlock>,
+    /// Slot of every cached block
+    index: BTreeMap<usize, usize>,
+    /// Slots of evicted blocks, reused by the next insertions
+    free: Vec<usize>,
+    newest: Option<usize>,
+    oldest: Option<usize>,
+    /// Incremented on every write, reads that raced with one do not fill the cache
+    writes: u64,
+}
+
+impl Blocks {
+    const fn new() -> Blocks {
+        Blocks {
+            slots: Vec::new(),
+            index: BTreeMap::new(),
+            free: Vec::new(),
+            newest: None,
+            oldest: None,
+            writes: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Returns the slot of block `lba` and makes it the most recently used
+    fn touch(&mut self, lba: usize) -> Option<usize> {
+        let slot = *self.index.get(&lba)?;
+        self.unlink(slot);
+        self.push_newest(slot);
+        Some(slot)
+    }
+
+    fn unlink(&mut self, slot: usize) {
+        let (newer, older) = (self.slots[slot].newer, self.slots[slot].older);
+        match newer {
+            Some(newer) => self.slots[newer].older = older,
+            None => self.newest = older,
+        }
+        match older {
+            Some(older) => self.slots[older].newer = newer,
+            None => self.oldest = newer,
+        }
+    }
+
+    fn push_newest(&mut self, slot: usize) {
+        self.slots[slot].newer = None;
+        self.slots[slot].older = self.newest;
+        match self.newest {
+            Some(newest) => self.slots[newest].newer = Some(slot),
+            None => self.oldest = Some(slot),
+        }
+        self.newest = Some(slot);
+    }
+
+    /// Adds block `lba` as the most recently used, it must not be cached
+    fn push(&mut self, lba: usize, data: Vec<u8>, dirty: bool) {
+        let block = CachedBlock {
+            lba,
+            data,
+            dirty,
+            writing: false,
+            newer: None,
+            older: None,
+        };
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.slots[slot] = block;
+                slot
+            }
+            None => {
+                self.slots.push(block);
+                self.slots.len() - 1
+            }
+        };
+        self.index.insert(lba, slot);
+        self.push_newest(slot);
+    }
+
+    /// Returns the least recently used block that is not being written back
+    fn victim(&self) -> Option<usize> {
+        let mut slot = self.oldest;
+        while let Some(index) = slot {
+            if !self.slots[index].writing {
+                return Some(index);
+            }
+            slot = self.slots[index].newer;
+        }
+        None
+    }
+
+    fn remove(&mut self, slot: usize) {
+        self.unlink(slot);
+        self.index.remove(&self.slots[slot].lba);
+        self.slots[slot].data = Vec::new();
+        self.free.push(slot);
+    }
+}
+
+/// Keeps the last blocks accessed on `device` in memory
+/// Writes stay in memory until their block is evicted or the cache is flushed
+/// The lock is released during device I/O, blocks being written back stay cached meanwhile
+pub struct BlockCache<D> {
+    device: D,
+    capacity: usize,
+    blocks: Mutex<Blocks>,
+}
+
+impl<D: BlockDevice> BlockCache<D> {
+    /// Creates a cache holding up to `capacity` blocks of `device`
+    pub const fn new(device: D, capacity: usize) -> BlockCache<D> {
+        BlockCache {
+            device,
+            capacity,
+            blocks: Mutex::new(Blocks::new()),
+        }
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Evicts the least recently used blocks until one more fits, writing them back if dirty
+    /// The lock is released while writing, so the guard is handed back
+    fn make_room<'a>(
+        &'a self,
+        mut blocks: MutexGuard<'a, Blocks>,
+    ) -> Result<MutexGuard<'a, Blocks>, BlockError> {
+        while blocks.len() >= self.capacity {
+            let slot = match blocks.victim() {
+                Some(slot) => slot,
+                // Every block is being written back, the cache grows for now
+                None => break,
+            };
+            if !blocks.slots[slot].dirty {
+                blocks.remove(slot);
+                continue;
+            }
+
+            let lba = blocks.slots[slot].lba;
+            blocks = self.write_back(blocks, slot)?;
+            // The block stays if it was used while being written
+            if let Some(&slot) = blocks.index.get(&lba) {
+                if Some(slot) == blocks.oldest && !blocks.slots[slot].dirty {
+                    blocks.remove(slot);
+                }
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// Writes the block at `slot` to the device with the lock released
+    fn write_back<'a>(
+        &'a self,
+        mut blocks: MutexGuard<'a, Blocks>,
+        slot: usize,
+    ) -> Result<MutexGuard<'a, Blocks>, BlockError> {
+        let block = &mut blocks.slots[slot];
+        let (lba, data) = (block.lba, block.data.clone());
+        block.dirty = false;
+        block.writing = true;
+        drop(blocks);
+
+        let res = self.device.write_blocks(lba, 1, &data);
+        let mut blocks = self.blocks.lock();
+        let block = &mut blocks.slots[slot];
+        block.writing = false;
+        if res.is_err() {
+            block.dirty = true;
+        }
+        res.map(|()| blocks)
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for BlockCache<D> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_blocks(&self, lba: usize, count: usize, dst: &mut [u8]) -> Result<(), BlockError> {
+        check_blocks(self, lba, count, dst.len())?;
+        let block_size = self.device.block_size();
+        let mut blocks = self.blocks.lock();
+        let mut index = 0;
+
+        while index < count {
+            let dst = &mut dst[index * block_size..];
+            if let Some(slot) = blocks.touch(lba + index) {
+                dst[..block_size].copy_from_slice(&blocks.slots[slot].data);
+                index += 1;
+                continue;
+            }
+
+            // Blocks missing from the cache are read with a single command
+            let missing = (index..count)
+                .take_while(|&other| !blocks.index.contains_key(&(lba + other)))
+                .count();
+            let writes = blocks.writes;
+            drop(blocks);
+            let res =
+                self.device
+                    .read_blocks(lba + index, missing, &mut dst[..missing * block_size]);
+            blocks = self.blocks.lock();
+            res?;
+
+            // A write during the read might have made the blocks read stale
+            if blocks.writes != writes {
+                continue;
+            }
+            for (offset, data) in dst.chunks_mut(block_size).take(missing).enumerate() {
+                if let Some(slot) = blocks.touch(lba + index + offset) {
+                    data.copy_from_slice(&blocks.slots[slot].data);
+                    continue;
+                }
+                blocks = self.make_room(blocks)?;
+                if !blocks.index.contains_key(&(lba + index + offset)) {
+                    blocks.push(lba + index + offset, data.to_vec(), false);
+                }
+            }
+            index += missing;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: usize, count: usize, src: &[u8]) -> Result<(), BlockError> {
+        check_blocks(self, lba, count, src.len())?;
+        let block_size = self.device.block_size();
+        let mut blocks = self.blocks.lock();
+        for (offset, data) in src.chunks(block_size).take(count).enumerate() {
+            let lba = lba + offset;
+            let len = data.len();
+            blocks.writes += 1;
+
+            let slot = match blocks.touch(lba) {
+                Some(slot) => slot,
+                None => {
+                    blocks = self.make_room(blocks)?;
+                    if blocks.touch(lba).is_none() {
+                        blocks.push(lba, alloc::vec![0; block_size], true);
+                    }
+                    blocks.index[&lba]
+                }
+            };
+            let block = &mut blocks.slots[slot];
+            block.data[..len].copy_from_slice(data);
+            block.data[len..].iter_mut().for_each(|byte| *byte = 0);
+            block.dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Writes every dirty block back, then flushes the device
+    fn flush(&self) -> Result<(), BlockError> {
+        let mut blocks = self.blocks.lock();
+        loop {
+            let dirty = blocks
+                .index
+                .values()
+                .copied()
+                .find(|&slot| blocks.slots[slot].dirty && !blocks.slots[slot].writing);
+            match dirty {
+                Some(slot) => blocks = self.write_back(blocks, slot)?,
+                None => break,
+            }
+        }
+        drop(blocks);
+        self.device.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::RamDisk;
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts the blocks read from and written to a RAM disk
+    struct CountingDisk {
+        disk: RamDisk,
+        reads: AtomicUsize,
+        writes: AtomicUsize,
+    }
+
+    impl CountingDisk {
+        fn new(block_count: usize) -> CountingDisk {
+            CountingDisk {
+                disk: RamDisk::new(block_count),
+                reads: AtomicUsize::new(0),
+                writes: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl BlockDevice for CountingDisk {
+        fn block_size(&self) -> usize {
+            self.disk.block_size()
+        }
+
+        fn block_count(&self) -> u64 {
+            self.disk.block_count()
+        }
+
+        fn read_blocks(&self, lba: usize, count: usize, dst: &mut [u8]) -> Result<(), BlockError> {
+            self.reads.fetch_add(count, Ordering::SeqCst);
+            self.disk.read_blocks(lba, count, dst)
+        }
+
+        fn write_blocks(&self, lba: usize, count: usize, src: &[u8]) -> Result<(), BlockError> {
+            self.writes.fetch_add(count, Ordering::SeqCst);
+            self.disk.write_blocks(lba, count, src)
+        }
+
+        fn flush(&self) -> Result<(), BlockError> {
+            self.disk.flush()
+        }
+    }
+
+    #[test_case]
+    fn cached_reads() {
+        let cache = BlockCache::new(CountingDisk::new(8), 4);
+        let mut buffer = [0; 3 * 512];
+        cache.read_blocks(0, 2, &mut buffer).unwrap();
+        cache.read_blocks(0, 3, &mut buffer).unwrap();
+        assert_eq!(cache.device().reads.load(Ordering::SeqCst), 3);
+    }
+
+    #[test_case]
+    fn write_back() {
+        let cache = BlockCache::new(CountingDisk::new(8), 2);
+        cache.write_blocks(0, 2, &[1; 2 * 512]).unwrap();
+        assert_eq!(cache.device().writes.load(Ordering::SeqCst), 0);
+
+        // Block 0 is the least recently used, it is written back when evicted
+        cache.write_blocks(2, 1, &[2; 512]).unwrap();
+        assert_eq!(cache.device().writes.load(Ordering::SeqCst), 1);
+        let mut buffer = [0; 512];
+        cache.device().disk.read_blocks(0, 1, &mut buffer).unwrap();
+        assert_eq!(buffer, [1; 512]);
+
+        cache.flush().unwrap();
+        assert_eq!(cache.device().writes.load(Ordering::SeqCst), 3);
+        cache.device().disk.read_blocks(2, 1, &mut buffer).unwrap();
+        assert_eq!(buffer, [2; 512]);
+    }
+
+    #[test_case]
+    fn least_recently_used() {
+        let cache = BlockCache::new(CountingDisk::new(8), 2);
+        let mut buffer = [0; 512];
+        cache.read_blocks(0, 1, &mut buffer).unwrap();
+        cache.read_blocks(1, 1, &mut buffer).unwrap();
+        cache.read_blocks(0, 1, &mut buffer).unwrap();
+        // Evicts block 1, block 0 was used since
+        cache.read_blocks(2, 1, &mut buffer).unwrap();
+        cache.read_blocks(0, 1, &mut buffer).unwrap();
+        assert_eq!(cache.device().reads.load(Ordering::SeqCst), 3);
+    }
+
+    #[test_case]
+    fn evicted_blocks_keep_data() {
+        let cache = BlockCache::new(CountingDisk::new(8), 3);
+        for lba in 0..8 {
+            cache.write_blocks(lba, 1, &[lba as u8; 512]).unwrap();
+        }
+        assert_eq!(cache.device().writes.load(Ordering::SeqCst), 5);
+
+        let mut buffer = [0; 8 * 512];
+        cache.read_blocks(0, 8, &mut buffer).unwrap();
+        for (lba, block) in buffer.chunks(512).enumerate() {
+            assert!(block.iter().all(|&byte| byte == lba as u8));
+        }
+    }
+}
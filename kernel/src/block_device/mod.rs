@@ -0,0 +1,181 @@
+//! Devices storing data in fixed size blocks, file systems are generic over them
+
+mod cache;
+pub mod partition;
+mod ram_disk;
+
+pub use cache::BlockCache;
+pub use partition::{partitions, Partition};
+pub use ram_disk::RamDisk;
+
+use crate::utils::mutex::Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Errors of block devices, drivers convert theirs to these
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The blocks are past the end of the device
+    OutOfRange,
+    /// The buffer is smaller than the blocks to transfer
+    BufferTooSmall,
+    /// The device cannot be written to
+    ReadOnly,
+    /// The partition table is corrupted
+    InvalidPartitionTable,
+    /// The device aborted the request, holds its error register
+    Device(u8),
+    /// The device did not complete the request in time
+    Timeout,
+    /// The device was disabled after a timeout and is not used anymore
+    Offline,
+    /// The device failed to complete the request
+    Io,
+}
+
+pub trait BlockDevice {
+    /// Returns the size of a block in bytes
+    fn block_size(&self) -> usize;
+
+    /// Returns the number of blocks of the device
+    fn block_count(&self) -> u64;
+
+    /// Reads `count` blocks starting at `lba` in `dst`
+    fn read_blocks(&self, lba: usize, count: usize, dst: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `count` blocks starting at `lba` from `src`
+    fn write_blocks(&self, lba: usize, count: usize, src: &[u8]) -> Result<(), BlockError>;
+
+    /// Makes the blocks written so far persistent
+    fn flush(&self) -> Result<(), BlockError>;
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for &D {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        (**self).block_count()
+    }
+
+    fn read_blocks(&self, lba: usize, count: usize, dst: &mut [u8]) -> Result<(), BlockError> {
+        (**self).read_blocks(lba, count, dst)
+    }
+
+    fn write_blocks(&self, lba: usize, count: usize, src: &[u8]) -> Result<(), BlockError> {
+        (**self).write_blocks(lba, count, src)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        (**self).flush()
+    }
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for Arc<D> {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        (**self).block_count()
+    }
+
+    fn read_blocks(&self, lba: usize, count: usize, dst: &mut [u8]) -> Result<(), BlockError> {
+        (**self).read_blocks(lba, count, dst)
+    }
+
+    fn write_blocks(&self, lba: usize, count: usize, src: &[u8]) -> Result<(), BlockError> {
+        (**self).write_blocks(lba, count, src)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        (**self).flush()
+    }
+}
+
+/// Checks the arguments of `read_blocks` and `write_blocks`: the `count` blocks starting at
+/// `lba` must be on `device` and the buffer of `len` bytes must hold them
+pub fn check_blocks<D: BlockDevice + ?Sized>(
+    device: &D,
+    lba: usize,
+    count: usize,
+    len: usize,
+) -> Result<(), BlockError> {
+    match lba.checked_add(count) {
+        Some(end) if end as u64 <= device.block_count() => {}
+        _ => return Err(BlockError::OutOfRange),
+    }
+    if len < count * device.block_size() {
+        return Err(BlockError::BufferTooSmall);
+    }
+    Ok(())
+}
+
+/// Block device of a disk, printed with its position on its controller
+pub trait DiskDevice: BlockDevice + fmt::Display + Send + Sync {}
+
+impl<D: BlockDevice + fmt::Display + Send + Sync> DiskDevice for D {}
+
+/// Disk found by one of the storage drivers
+#[derive(Clone)]
+pub struct Disk {
+    /// Name of the driver, printed before the disk
+    driver: &'static str,
+    device: Arc<dyn DiskDevice>,
+}
+
+/// Disks registered by the drivers, in the order they were found
+static DISKS: Mutex<Vec<Disk>> = Mutex::new(Vec::new());
+
+impl Disk {
+    pub fn new<D: DiskDevice + 'static>(driver: &'static str, device: D) -> Disk {
+        Disk {
+            driver,
+            device: Arc::new(device),
+        }
+    }
+
+    pub fn driver(&self) -> &'static str {
+        self.driver
+    }
+}
+
+/// Makes `disk` available to the file systems
+pub fn register(disk: Disk) {
+    DISKS.lock().push(disk);
+}
+
+/// Returns the disks found at boot, in the order the drivers found them
+pub fn disks() -> Vec<Disk> {
+    DISKS.lock().clone()
+}
+
+impl BlockDevice for Disk {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_blocks(&self, lba: usize, count: usize, dst: &mut [u8]) -> Result<(), BlockError> {
+        self.device.read_blocks(lba, count, dst)
+    }
+
+    fn write_blocks(&self, lba: usize, count: usize, src: &[u8]) -> Result<(), BlockError> {
+        self.device.write_blocks(lba, count, src)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.device.flush()
+    }
+}
+
+impl fmt::Display for Disk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.driver, self.device)
+    }
+}
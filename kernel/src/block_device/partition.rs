@@ -0,0 +1,421 @@
+//! MBR and GPT partition tables, every partition is a block device of its own
+
+use super::{check_blocks, BlockDevice, BlockError};
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+/// Offset of the 4 primary partition entries in the MBR
+const MBR_ENTRIES: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+/// Partition ID covering the whole disk when it uses a GPT
+const PROTECTIVE_MBR: u8 = 0xEE;
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+/// LBA of the GPT header
+const GPT_HEADER: usize = 1;
+/// Size of the fields of the GPT header, the rest of its block is reserved
+const GPT_HEADER_SIZE: usize = 92;
+/// Size of the fields of a GPT entry, larger entries are padded
+const GPT_ENTRY_SIZE: usize = 128;
+/// Largest entry array read, 128 times what the 128 entries of a usual GPT use
+const GPT_MAX_ENTRIES_SIZE: usize = 128 * 128 * GPT_ENTRY_SIZE;
+
+/// GUID stored in the mixed endian layout of GPT entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid {
+    data1: u32,
+    data2: u16,
+    data3: u16,
+    data4: [u8; 8],
+}
+
+impl Guid {
+    pub const fn new(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Guid {
+        Guid {
+            data1,
+            data2,
+            data3,
+            data4,
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Guid {
+        Guid {
+            data1: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            data2: u16::from_le_bytes(bytes[4..6].try_into().unwrap()),
+            data3: u16::from_le_bytes(bytes[6..8].try_into().unwrap()),
+            data4: bytes[8..16].try_into().unwrap(),
+        }
+    }
+
+    /// Returns the bytes of the GUID as stored on disk
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[0..4].copy_from_slice(&self.data1.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.data2.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.data3.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.data4);
+        bytes
+    }
+
+    fn is_nil(&self) -> bool {
+        *self == Guid::new(0, 0, 0, [0; 8])
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            self.data1, self.data2, self.data3, self.data4[0], self.data4[1]
+        )?;
+        for byte in &self.data4[2..] {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a partition holds, as told by the partition table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    /// Partition ID of an MBR entry
+    Mbr(u8),
+    /// Type GUID of a GPT entry
+    Gpt(Guid),
+}
+
+impl fmt::Display for PartitionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartitionType::Mbr(id) => write!(f, "MBR {:#04x}", id),
+            PartitionType::Gpt(guid) => write!(f, "GPT {}", guid),
+        }
+    }
+}
+
+/// Range of blocks of `device` described by its partition table
+#[derive(Clone)]
+pub struct Partition<D> {
+    device: D,
+    kind: PartitionType,
+    /// LBA of the first block of the partition on `device`
+    start: usize,
+    block_count: u64,
+}
+
+impl<D> Partition<D> {
+    pub fn kind(&self) -> PartitionType {
+        self.kind
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+}
+
+impl<D: BlockDevice> Partition<D> {
+    /// Returns the LBA on the device of `count` blocks starting at `lba` in the partition
+    fn device_lba(&self, lba: usize, count: usize, len: usize) -> Result<usize, BlockError> {
+        check_blocks(self, lba, count, len)?;
+        Ok(self.start + lba)
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Partition<D> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, lba: usize, count: usize, dst: &mut [u8]) -> Result<(), BlockError> {
+        let lba = self.device_lba(lba, count, dst.len())?;
+        self.device.read_blocks(lba, count, dst)
+    }
+
+    fn write_blocks(&self, lba: usize, count: usize, src: &[u8]) -> Result<(), BlockError> {
+        let lba = self.device_lba(lba, count, src.len())?;
+        self.device.write_blocks(lba, count, src)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.device.flush()
+    }
+}
+
+impl<D> fmt::Display for Partition<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}, {} blocks from LBA {}",
+            self.kind, self.block_count, self.start
+        )
+    }
+}
+
+/// Returns the partitions of `device`, none if it has no partition table
+/// Only the primary entries of an MBR are read, the backup GPT is not read
+pub fn partitions<D: BlockDevice + Clone>(device: &D) -> Result<Vec<Partition<D>>, BlockError> {
+    let mut mbr = alloc::vec![0; device.block_size()];
+    device.read_blocks(0, 1, &mut mbr)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+
+    let entries = mbr[MBR_ENTRIES..MBR_ENTRIES + 4 * MBR_ENTRY_SIZE].chunks(MBR_ENTRY_SIZE);
+    if entries.clone().any(|entry| entry[4] == PROTECTIVE_MBR) {
+        return gpt_partitions(device);
+    }
+
+    let partitions = entries
+        .map(|entry| Partition {
+            device: device.clone(),
+            kind: PartitionType::Mbr(entry[4]),
+            start: u32::from_le_bytes(entry[8..12].try_into().unwrap()) as usize,
+            block_count: u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64,
+        })
+        .filter(|partition| partition.kind != PartitionType::Mbr(0) && partition.block_count > 0)
+        .filter(fits)
+        .collect();
+    Ok(partitions)
+}
+
+fn gpt_partitions<D: BlockDevice + Clone>(device: &D) -> Result<Vec<Partition<D>>, BlockError> {
+    let block_size = device.block_size();
+    let mut header = alloc::vec![0; block_size];
+    device.read_blocks(GPT_HEADER, 1, &mut header)?;
+    if &header[0..8] != GPT_SIGNATURE {
+        return Ok(Vec::new());
+    }
+
+    let field = |range: core::ops::Range<usize>| {
+        header[range]
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| value << 8 | byte as usize)
+    };
+    let header_size = field(12..16);
+    let header_crc = field(16..20) as u32;
+    let entries_lba = field(72..80);
+    let entry_count = field(80..84);
+    let entry_size = field(84..88);
+    let entries_crc = field(88..92) as u32;
+
+    if header_size < GPT_HEADER_SIZE || header_size > block_size {
+        return Err(BlockError::InvalidPartitionTable);
+    }
+    header[16..20].iter_mut().for_each(|byte| *byte = 0);
+    if crc32(&header[..header_size]) != header_crc {
+        return Err(BlockError::InvalidPartitionTable);
+    }
+    if entry_size < GPT_ENTRY_SIZE || entry_size % 8 != 0 {
+        return Err(BlockError::InvalidPartitionTable);
+    }
+    let entries_size = match entry_count.checked_mul(entry_size) {
+        Some(size) if size <= GPT_MAX_ENTRIES_SIZE => size,
+        _ => return Err(BlockError::InvalidPartitionTable),
+    };
+
+    let blocks = (entries_size + block_size - 1) / block_size;
+    let mut entries = alloc::vec![0; blocks * block_size];
+    device.read_blocks(entries_lba, blocks, &mut entries)?;
+    if crc32(&entries[..entries_size]) != entries_crc {
+        return Err(BlockError::InvalidPartitionTable);
+    }
+
+    let partitions = entries[..entries_size]
+        .chunks(entry_size)
+        .map(|entry| {
+            let first = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+            let last = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+            Partition {
+                device: device.clone(),
+                kind: PartitionType::Gpt(Guid::from_bytes(&entry[0..16])),
+                start: first as usize,
+                block_count: (last + 1).saturating_sub(first),
+            }
+        })
+        .filter(|partition| !matches!(partition.kind, PartitionType::Gpt(guid) if guid.is_nil()))
+        .filter(fits)
+        .collect();
+    Ok(partitions)
+}
+
+/// Entries past the end of the disk are corrupted
+fn fits<D: BlockDevice>(partition: &Partition<D>) -> bool {
+    let end = (partition.start as u64).checked_add(partition.block_count);
+    matches!(end, Some(end) if end <= partition.device.block_count())
+}
+
+/// CRC-32 used by the GPT, the same as Ethernet's and zlib's
+fn crc32(data: &[u8]) -> u32 {
+    let crc = data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg())
+        })
+    });
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::RamDisk;
+    use super::*;
+
+    const GUID: Guid = Guid::new(
+        0x0FC6_3DAF,
+        0x8483,
+        0x4772,
+        [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4],
+    );
+
+    fn mbr_entry(block: &mut [u8], index: usize, kind: u8, start: u32, block_count: u32) {
+        let entry = &mut block[MBR_ENTRIES + index * MBR_ENTRY_SIZE..];
+        entry[4] = kind;
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&block_count.to_le_bytes());
+    }
+
+    fn write_mbr(disk: &RamDisk, entries: &[(u8, u32, u32)]) {
+        let mut block = [0; 512];
+        for (index, &(kind, start, block_count)) in entries.iter().enumerate() {
+            mbr_entry(&mut block, index, kind, start, block_count);
+        }
+        block[510..512].copy_from_slice(&MBR_SIGNATURE);
+        disk.write_blocks(0, 1, &block).unwrap();
+    }
+
+    #[test_case]
+    fn no_partition_table() {
+        let disk = RamDisk::new(8);
+        assert!(partitions(&&disk).unwrap().is_empty());
+    }
+
+    #[test_case]
+    fn mbr() {
+        let disk = RamDisk::new(16);
+        write_mbr(&disk, &[(0xDA, 1, 3), (0, 0, 0), (0x7F, 4, 12)]);
+
+        let partitions = partitions(&&disk).unwrap();
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(partitions[0].kind(), PartitionType::Mbr(0xDA));
+        assert_eq!(partitions[1].kind(), PartitionType::Mbr(0x7F));
+        assert_eq!(partitions[1].start(), 4);
+        assert_eq!(partitions[1].block_count(), 12);
+    }
+
+    #[test_case]
+    fn mbr_partition_past_end() {
+        let disk = RamDisk::new(16);
+        write_mbr(
+            &disk,
+            &[(0xDA, 1, 3), (0x7F, 4, 13), (0x7F, u32::MAX, u32::MAX)],
+        );
+        let partitions = partitions(&&disk).unwrap();
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].start(), 1);
+    }
+
+    #[test_case]
+    fn partition_offset() {
+        let disk = RamDisk::new(16);
+        write_mbr(&disk, &[(0x7F, 4, 2)]);
+        let partition = partitions(&&disk).unwrap().remove(0);
+
+        partition.write_blocks(1, 1, &[42; 512]).unwrap();
+        let mut buffer = [0; 512];
+        disk.read_blocks(5, 1, &mut buffer).unwrap();
+        assert_eq!(buffer, [42; 512]);
+        assert_eq!(
+            partition.read_blocks(1, 2, &mut [0; 1024]),
+            Err(BlockError::OutOfRange)
+        );
+    }
+
+    /// Writes a GPT with 4 entries of `entry_size` bytes, the `(first, last)` blocks of each
+    /// partition are given, the second entry is unused
+    fn write_gpt(disk: &RamDisk, entry_size: u32, partitions: &[(u64, u64)]) {
+        write_mbr(disk, &[(PROTECTIVE_MBR, 1, 15)]);
+
+        let mut entries = [0; 4 * 128];
+        for (index, &(first, last)) in partitions.iter().enumerate() {
+            let entry = &mut entries[index * 2 * 128..];
+            entry[0..16].copy_from_slice(&GUID.to_bytes());
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+        }
+        disk.write_blocks(2, 1, &entries).unwrap();
+        let entries_size = 4 * entry_size as usize;
+
+        let mut header = [0; 512];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&entry_size.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(&entries[..entries_size]).to_le_bytes());
+        let crc = crc32(&header[..GPT_HEADER_SIZE]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        disk.write_blocks(GPT_HEADER, 1, &header).unwrap();
+    }
+
+    #[test_case]
+    fn gpt() {
+        let disk = RamDisk::new(16);
+        write_gpt(&disk, 128, &[(3, 9), (10, 15)]);
+
+        let partitions = partitions(&&disk).unwrap();
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(partitions[0].kind(), PartitionType::Gpt(GUID));
+        assert_eq!(partitions[0].start(), 3);
+        assert_eq!(partitions[0].block_count(), 7);
+        assert_eq!(partitions[1].start(), 10);
+    }
+
+    #[test_case]
+    fn corrupted_gpt() {
+        let disk = RamDisk::new(16);
+        write_gpt(&disk, 64, &[(3, 9)]);
+        assert!(matches!(
+            partitions(&&disk),
+            Err(BlockError::InvalidPartitionTable)
+        ));
+
+        write_gpt(&disk, 128, &[(3, 9)]);
+        let mut header = [0; 512];
+        disk.read_blocks(GPT_HEADER, 1, &mut header).unwrap();
+        header[80] = 5;
+        disk.write_blocks(GPT_HEADER, 1, &header).unwrap();
+        assert!(matches!(
+            partitions(&&disk),
+            Err(BlockError::InvalidPartitionTable)
+        ));
+    }
+
+    #[test_case]
+    fn gpt_partition_past_end() {
+        let disk = RamDisk::new(16);
+        write_gpt(&disk, 128, &[(3, 9), (10, 16)]);
+        let partitions = partitions(&&disk).unwrap();
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].start(), 3);
+    }
+
+    #[test_case]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test_case]
+    fn guid_display() {
+        assert_eq!(
+            alloc::format!("{}", GUID),
+            "0FC63DAF-8483-4772-8E79-3D69D8477DE4"
+        );
+    }
+}
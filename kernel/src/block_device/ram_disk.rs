@@ -0,0 +1,107 @@
+use super::{check_blocks, BlockDevice, BlockError};
+use crate::utils::mutex::Mutex;
+use alloc::vec::Vec;
+
+const BLOCK_SIZE: usize = 512;
+
+/// Block device backed by memory, its content is lost when dropped
+pub struct RamDisk {
+    data: Mutex<Vec<u8>>,
+    read_only: bool,
+}
+
+impl RamDisk {
+    /// Creates a disk of `block_count` zeroed blocks
+    pub fn new(block_count: usize) -> RamDisk {
+        RamDisk {
+            data: Mutex::new(alloc::vec![0; block_count * BLOCK_SIZE]),
+            read_only: false,
+        }
+    }
+
+    /// Creates a disk holding `data` that cannot be written to
+    /// The last block is padded with zeros
+    pub fn read_only(mut data: Vec<u8>) -> RamDisk {
+        data.resize((data.len() + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE, 0);
+        RamDisk {
+            data: Mutex::new(data),
+            read_only: true,
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.lock().len() / BLOCK_SIZE) as u64
+    }
+
+    fn read_blocks(&self, lba: usize, count: usize, dst: &mut [u8]) -> Result<(), BlockError> {
+        check_blocks(self, lba, count, dst.len())?;
+        let (start, end) = (lba * BLOCK_SIZE, (lba + count) * BLOCK_SIZE);
+        dst[..end - start].copy_from_slice(&self.data.lock()[start..end]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: usize, count: usize, src: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        check_blocks(self, lba, count, src.len())?;
+        let (start, end) = (lba * BLOCK_SIZE, (lba + count) * BLOCK_SIZE);
+        self.data.lock()[start..end].copy_from_slice(&src[..end - start]);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn read_write() {
+        let disk = RamDisk::new(4);
+        disk.write_blocks(1, 2, &[7; 2 * BLOCK_SIZE]).unwrap();
+
+        let mut buffer = [0; 4 * BLOCK_SIZE];
+        disk.read_blocks(0, 4, &mut buffer).unwrap();
+        assert!(buffer[..BLOCK_SIZE].iter().all(|&byte| byte == 0));
+        assert!(buffer[BLOCK_SIZE..3 * BLOCK_SIZE]
+            .iter()
+            .all(|&byte| byte == 7));
+        assert_eq!(
+            disk.read_blocks(3, 2, &mut buffer),
+            Err(BlockError::OutOfRange)
+        );
+        assert_eq!(
+            disk.read_blocks(0, 2, &mut buffer[..BLOCK_SIZE]),
+            Err(BlockError::BufferTooSmall)
+        );
+        assert_eq!(
+            disk.write_blocks(usize::MAX, 2, &buffer),
+            Err(BlockError::OutOfRange)
+        );
+    }
+
+    #[test_case]
+    fn read_only() {
+        let disk = RamDisk::read_only(alloc::vec![3; BLOCK_SIZE + 1]);
+        assert_eq!(disk.block_count(), 2);
+        assert_eq!(
+            disk.write_blocks(0, 1, &[0; BLOCK_SIZE]),
+            Err(BlockError::ReadOnly)
+        );
+
+        let mut buffer = [0; 2 * BLOCK_SIZE];
+        disk.read_blocks(0, 2, &mut buffer).unwrap();
+        assert!(buffer[..=BLOCK_SIZE].iter().all(|&byte| byte == 3));
+        assert!(buffer[BLOCK_SIZE + 1..].iter().all(|&byte| byte == 0));
+    }
+}
@@ -99,13 +99,7 @@ readBlock:
     test byte ptr es:[di + 20], 1 # If so: is the "ignore this data" bit clear?
     je .skipent
 
-.notext:
-    mov ecx, es:[di + 16]         # Get type of region
-    cmp ecx, 2                    # Only keep usable and reclaimable memory
-    je .skipent                   # Eliminate type 2 region
-    cmp ecx, 4                    #
-    jge .skipent                  # Eliminate type 4 and 5 region
-
+.notext:                          # Every region type is kept, the kernel sorts them out
     mov ecx, es:[di + 8]          # Get lower uint32_t of memory region length
     or ecx, es:[di + 12]          # Or it with upper uint32_t to test for zero
     jz .skipent                   # If length uint64_t is 0, skip entry
@@ -183,6 +177,13 @@ gdt_descriptor :
    .long GDT               # 32-bit start address
 
 
+#=================#
+# Partition table
+#=================#
+
+    .org 446
+    .fill 64, 1, 0 # Written by the image builder
+
 #================#
 # Boot signature
 #================#
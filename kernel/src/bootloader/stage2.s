@@ -1,6 +1,7 @@
 .section .stage2, "awx"
 
-# Stage 2 sets up paging by identity mapping the first 2MB
+# Stage 2 sets up paging by mapping the first 2MiB both at 0 and at KERNEL_OFFSET
+# The kernel is loaded at 1MiB, so it must fit with its .bss in the MiB left, the linker checks it
 # Enters long mode and moves kernel to its target address
 # Calls _start in the kernel
 
@@ -12,6 +13,10 @@
 .equ pdpt,           pml4t + 0x1000
 .equ pdt,            pdpt + 0x1000
 .equ pt,             pdt + 0x1000
+.equ pdpt_high,      pt + 0x1000
+
+.equ KERNEL_OFFSET,  0xFFFFFFFF80000000
+.equ STACK_TOP,      KERNEL_OFFSET + 0x90000
 
 #=======================#
 # Set up Protected mode
@@ -29,26 +34,35 @@ stage2 :
 #====================#
 
     lea edi, [pml4t]                  # Set the destination index to pml4t.
-    mov ecx, 0x1000                   # Clear all entries
+    mov ecx, 0x1400                   # Clear all entries of the 5 tables
     xor eax, eax                      #
     rep stosd                         #
 
     lea edi, [pml4t]                  # Set the destination index to pml4t.
-    add edi, 511 * 8                  # Map last entry to the table itself
+    add edi, 510 * 8                  # Map entry 510 to the table itself
     mov dword ptr [edi], pml4t | 0x03 #
 
-    # Identity map the first 2MB for kernel and VGA
+    # Map the first 2MiB at KERNEL_OFFSET, using the same page directory
+    lea edi, [pml4t]                  # PML4T[511] = PDPT_HIGH
+    add edi, 511 * 8                  #
+    mov dword ptr [edi], pdpt_high | 0x03
+
+    lea edi, [pdpt_high]              # PDPT_HIGH[510] = PDT
+    add edi, 510 * 8                  #
+    mov dword ptr [edi], pdt | 0x03   #
+
+    # Identity map the first 2MiB, the bootloader runs there until it jumps to the kernel
     lea edi, [pml4t]                  # get address of PML4T
     mov cr3, edi                      # Set Paging entry point to pml4t's address
-    mov ebx, pdpt | 0x03              # PML4T[0] = PDPT[0] with read and write properties on
+    mov ebx, pdpt | 0x03              # PML4T[0] = PDPT with read and write properties on
     mov dword ptr [edi], ebx          #
 
     lea edi, [pdpt]                   # get address of PDPT
-    mov ebx, pdt | 0x03               # PML4T[0] = PDPT[0] with read and write properties on
+    mov ebx, pdt | 0x03               # PDPT[0] = PDT with read and write properties on
     mov dword ptr [edi], ebx          #
 
     lea edi, [pdt]                    # get address of PDT
-    mov ebx, pt | 0x03                # PML4T[0] = PDPT[0] with read and write properties on
+    mov ebx, pt | 0x03                # PDT[0] = PT with read and write properties on
     mov dword ptr [edi], ebx          #
 
     lea edi, [pt]                     # get address of PT
@@ -71,10 +85,12 @@ stage2 :
     mov ecx, 0xC0000080 # Set Long Mode enabled bit in EFER register
     rdmsr               #
     or eax, 1 << 8      #
+    or eax, 1 << 11     # Set No-Execute enabled bit, NX pages can be mapped
     wrmsr               #
 
     mov eax, cr0        # Enable paging by setting CR0.PG bit to 1
     or eax, 1 << 31     #
+    or eax, 1 << 16     # Set CR0.WP, read-only pages apply to the kernel too
     mov cr0, eax        #
 
     lgdt [gdt64_descriptor]
@@ -97,11 +113,11 @@ init_lm:
     mov gs, ax               #
     mov ss, ax               #
 
-    mov rbp, 0x90000         # Set up stack
+    mov rbp, STACK_TOP       # Set up stack in the higher half
     mov rsp, rbp             #
 
     lea rsi, [_stage2_end]   # Move loaded kernel
-    lea rdi, [_kernel_start] # To _kernel_start
+    lea rdi, [_kernel_start] # To _kernel_start, through the higher half mapping
     lea rcx, [_kernel_size]  # _kernel_size times
     rep movsb                #
 
@@ -0,0 +1,580 @@
+//! AHCI driver for SATA disks, based on the Serial ATA AHCI 1.3.1 specification
+//! Every command is issued from the first slot of its port and polled until it completes
+//! Controllers without 64bit addressing are not supported
+
+use crate::arch::ata::Identity;
+use crate::arch::interrupt::{self, TIMER_FREQUENCY};
+use crate::arch::pci::{self, Bar, Device, DeviceClass, Function};
+use crate::block_device::{self, check_blocks, partitions, BlockDevice, BlockError, Disk};
+use crate::memory_manager::{self, ALLOCATOR, PAGE_SIZE};
+use crate::serial_println;
+use crate::utils::mutex::Mutex;
+use alloc::vec::Vec;
+use core::fmt;
+
+pub const DEVICE_TYPE: DeviceClass = DeviceClass::SataController;
+
+/// BAR holding the HBA registers
+const ABAR: u8 = 5;
+
+/// Bits of the PCI command register enabling MMIO and bus mastering
+const PCI_MEMORY_SPACE: u16 = 1 << 1;
+const PCI_BUS_MASTER: u16 = 1 << 2;
+
+// HBA registers
+const CAP: usize = 0x00;
+const GHC: usize = 0x04;
+const PORTS_IMPLEMENTED: usize = 0x0C;
+
+// Port registers, as offsets from the registers of the port
+const PORT_REGISTERS: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const CLB: usize = 0x00;
+const CLBU: usize = 0x04;
+const FB: usize = 0x08;
+const FBU: usize = 0x0C;
+const IS: usize = 0x10;
+const CMD: usize = 0x18;
+const TFD: usize = 0x20;
+const SIG: usize = 0x24;
+const SSTS: usize = 0x28;
+const SERR: usize = 0x30;
+const CI: usize = 0x38;
+
+/// Bit of CAP set when the HBA can access memory above 4GiB
+const CAP_64BIT: u32 = 1 << 31;
+/// Bit of GHC switching the HBA to AHCI mode
+const GHC_AHCI_ENABLE: u32 = 1 << 31;
+
+const CMD_START: u32 = 1;
+const CMD_FIS_RECEIVE: u32 = 1 << 4;
+const CMD_FIS_RUNNING: u32 = 1 << 14;
+const CMD_LIST_RUNNING: u32 = 1 << 15;
+
+/// Bit of IS set when the device reported an error
+const IS_TASK_FILE_ERROR: u32 = 1 << 30;
+
+const STATUS_BUSY: u32 = 0x80;
+const STATUS_DEVICE_FAULT: u32 = 0x20;
+const STATUS_DATA_REQUEST: u32 = 0x08;
+const STATUS_ERROR: u32 = 0x01;
+
+/// SSTS value of a port with a device attached and communication established
+const DEVICE_PRESENT: u32 = 3;
+/// Signature of a SATA disk, ATAPI devices and port multipliers have other ones
+const SATA_SIGNATURE: u32 = 0x0000_0101;
+
+const READ_DMA: u8 = 0xC8;
+const READ_DMA_EXT: u8 = 0x25;
+const WRITE_DMA: u8 = 0xCA;
+const WRITE_DMA_EXT: u8 = 0x35;
+const CACHE_FLUSH: u8 = 0xE7;
+const CACHE_FLUSH_EXT: u8 = 0xEA;
+const IDENTIFY: u8 = 0xEC;
+
+/// Type of a register FIS sent from the host to the device
+const FIS_HOST_TO_DEVICE: u8 = 0x27;
+/// Bit of the FIS flags set when it holds a command
+const FIS_COMMAND: u8 = 0x80;
+/// Device register value selecting LBA addressing
+const DEVICE_LBA: u8 = 1 << 6;
+
+/// Length of a register FIS in dwords
+const FIS_LENGTH: u16 = 5;
+/// Bit of the command header flags set when the command writes to the device
+const HEADER_WRITE: u16 = 1 << 6;
+
+// Layout of the page each port gets, the command list and command table hold a single slot
+const COMMAND_LIST: usize = 0;
+const RECEIVED_FIS: usize = 1024;
+const COMMAND_TABLE: usize = 2048;
+const PRDT: usize = COMMAND_TABLE + 0x80;
+const PRDT_ENTRIES: usize = (PAGE_SIZE - PRDT) / 16;
+
+const SECTOR_SIZE: usize = 512;
+/// Sectors of a command, their PRDT entries always fit in the command table
+const COMMAND_SECTORS: usize = 512;
+/// Most sectors a command without 48bit LBA transfers
+const MAX_SECTORS: usize = 256;
+
+/// Ticks a device has to complete a command
+const TIMEOUT: u64 = 5 * TIMER_FREQUENCY;
+
+/// Every SATA disk found, indexed by `AhciDisk::index`
+static DISKS: Mutex<Vec<SataDisk>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AhciError {
+    /// The device aborted the command, holds its error register
+    Device(u8),
+    /// The device reported a fault
+    DeviceFault,
+    /// The port did not complete the command in time, it was stopped and is not used anymore
+    Timeout,
+    /// The port was stopped after a timeout
+    Stopped,
+}
+
+impl From<AhciError> for BlockError {
+    fn from(err: AhciError) -> BlockError {
+        match err {
+            AhciError::Device(error) => BlockError::Device(error),
+            AhciError::DeviceFault => BlockError::Io,
+            AhciError::Timeout => BlockError::Timeout,
+            AhciError::Stopped => BlockError::Offline,
+        }
+    }
+}
+
+/// Command header, the first entry of the command list
+#[repr(C)]
+struct CommandHeader {
+    /// FIS length and direction
+    flags: u16,
+    prdt_length: u16,
+    /// Bytes transferred, updated by the HBA
+    byte_count: u32,
+    /// Physical address of the command table
+    table: u64,
+    reserved: [u32; 4],
+}
+
+/// Register FIS sent from the host to the device
+#[repr(C)]
+struct CommandFis {
+    fis_type: u8,
+    flags: u8,
+    command: u8,
+    feature_low: u8,
+    lba_low: [u8; 3],
+    device: u8,
+    lba_high: [u8; 3],
+    feature_high: u8,
+    count: u16,
+    icc: u8,
+    control: u8,
+    reserved: u32,
+}
+
+/// Physical region descriptor, a memory range the HBA transfers to or from
+#[repr(C)]
+struct PrdtEntry {
+    addr: u64,
+    reserved: u32,
+    /// Number of bytes minus one
+    byte_count: u32,
+}
+
+/// Port a SATA disk is attached to
+struct Port {
+    /// Number of the port on its HBA
+    number: usize,
+    /// Virtual address of the registers of the port
+    registers: usize,
+    /// Physical address of the page holding the command list, received FIS and command table
+    memory: usize,
+    /// Set once the port was stopped after a timeout, the command it ran might not be done
+    stopped: bool,
+}
+
+struct SataDisk {
+    port: Port,
+    identity: Identity,
+}
+
+impl Port {
+    /// Read u32 from the port
+    /// # Safety
+    /// `reg` must be a port register
+    unsafe fn read(&self, reg: usize) -> u32 {
+        ((self.registers + reg) as *const u32).read_volatile()
+    }
+
+    /// Write u32 to the port
+    /// # Safety
+    /// `reg` must be a port register, may cause side effects
+    unsafe fn write(&self, reg: usize, value: u32) {
+        ((self.registers + reg) as *mut u32).write_volatile(value)
+    }
+
+    /// Stops the port, points it to its memory and starts it again
+    fn start(&self) -> Result<(), AhciError> {
+        // SAFETY: CMD, CLB, CLBU, FB, FBU, SERR and IS are port registers
+        // The port is stopped while its memory is changed
+        unsafe {
+            self.write(CMD, self.read(CMD) & !CMD_START);
+            wait_until(|| self.read(CMD) & CMD_LIST_RUNNING == 0)?;
+            self.write(CMD, self.read(CMD) & !CMD_FIS_RECEIVE);
+            wait_until(|| self.read(CMD) & CMD_FIS_RUNNING == 0)?;
+
+            let command_list = self.memory + COMMAND_LIST;
+            let received_fis = self.memory + RECEIVED_FIS;
+            self.write(CLB, command_list as u32);
+            self.write(CLBU, (command_list >> 32) as u32);
+            self.write(FB, received_fis as u32);
+            self.write(FBU, (received_fis >> 32) as u32);
+            self.write(SERR, !0);
+            self.write(IS, !0);
+
+            self.write(CMD, self.read(CMD) | CMD_FIS_RECEIVE);
+            self.write(CMD, self.read(CMD) | CMD_START);
+        }
+        Ok(())
+    }
+
+    /// Stops the port after a command timed out, once done the HBA does not access the memory
+    /// of the port nor the buffer of the command
+    fn stop(&mut self) {
+        self.stopped = true;
+        // SAFETY: CMD is a port register, clearing ST aborts the command
+        unsafe {
+            self.write(CMD, self.read(CMD) & !CMD_START);
+            // The buffers are not released before the HBA is done with them however long it takes
+            while self.read(CMD) & CMD_LIST_RUNNING != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Sends `command` for `sectors` sectors at `lba`, data goes to or from `buffer`
+    /// `buffer` must be word aligned and hold `sectors` sectors, or be empty if no data is transferred
+    fn issue(
+        &mut self,
+        command: u8,
+        lba: usize,
+        sectors: usize,
+        buffer: usize,
+        write: bool,
+    ) -> Result<(), AhciError> {
+        if self.stopped {
+            return Err(AhciError::Stopped);
+        }
+        // SAFETY: TFD is a port register
+        wait_until(|| unsafe { self.read(TFD) } & (STATUS_BUSY | STATUS_DATA_REQUEST) == 0)?;
+
+        let memory = memory_manager::phys_to_virt(self.memory);
+        let prdt_length = self.fill_prdt(memory, buffer, sectors * SECTOR_SIZE);
+        // Commands without 48bit LBA take bits 24 to 27 in the low nibble of the device register
+        let (device, lba_high) = match command {
+            READ_DMA | WRITE_DMA => (DEVICE_LBA | ((lba >> 24) as u8 & 0xF), [0; 3]),
+            _ => (
+                DEVICE_LBA,
+                [(lba >> 24) as u8, (lba >> 32) as u8, (lba >> 40) as u8],
+            ),
+        };
+        let fis = CommandFis {
+            fis_type: FIS_HOST_TO_DEVICE,
+            flags: FIS_COMMAND,
+            command,
+            feature_low: 0,
+            lba_low: [lba as u8, (lba >> 8) as u8, (lba >> 16) as u8],
+            device,
+            lba_high,
+            feature_high: 0,
+            // Commands without 48bit LBA only use the low byte, where 0 stands for 256
+            count: sectors as u16,
+            icc: 0,
+            control: 0,
+            reserved: 0,
+        };
+        let header = CommandHeader {
+            flags: FIS_LENGTH | if write { HEADER_WRITE } else { 0 },
+            prdt_length: prdt_length as u16,
+            byte_count: 0,
+            table: (self.memory + COMMAND_TABLE) as u64,
+            reserved: [0; 4],
+        };
+        // SAFETY: The page belongs to the port, the slot is idle
+        unsafe {
+            ((memory + COMMAND_TABLE) as *mut CommandFis).write_volatile(fis);
+            ((memory + COMMAND_LIST) as *mut CommandHeader).write_volatile(header);
+        }
+
+        // SAFETY: IS, CI and TFD are port registers, the command is ready
+        unsafe {
+            self.write(IS, !0);
+            self.write(CI, 1);
+            let done = || self.read(CI) & 1 == 0 || self.read(IS) & IS_TASK_FILE_ERROR != 0;
+            if let Err(err) = wait_until(done) {
+                self.stop();
+                return Err(err);
+            }
+
+            let task_file = self.read(TFD);
+            if task_file & STATUS_DEVICE_FAULT != 0 {
+                return Err(AhciError::DeviceFault);
+            }
+            if task_file & STATUS_ERROR != 0 || self.read(IS) & IS_TASK_FILE_ERROR != 0 {
+                let error = (task_file >> 8) as u8;
+                // A failed command stops the port, it must be restarted
+                self.start()?;
+                return Err(AhciError::Device(error));
+            }
+        }
+        Ok(())
+    }
+
+    /// Describes [buffer, buffer + size[ in the PRDT, one entry per page
+    /// Returns the number of entries
+    fn fill_prdt(&self, memory: usize, buffer: usize, size: usize) -> usize {
+        let prdt = (memory + PRDT) as *mut PrdtEntry;
+        let end = buffer + size;
+        let mut addr = buffer;
+        let mut index = 0;
+
+        while addr < end {
+            let len = (PAGE_SIZE - addr % PAGE_SIZE).min(end - addr);
+            let phys_addr = memory_manager::virt_to_phys(addr).expect("DMA buffer is not mapped");
+            assert!(index < PRDT_ENTRIES);
+            // SAFETY: The entry is in the command table of the port
+            unsafe {
+                prdt.add(index).write_volatile(PrdtEntry {
+                    addr: phys_addr as u64,
+                    reserved: 0,
+                    byte_count: len as u32 - 1,
+                });
+            }
+            addr += len;
+            index += 1;
+        }
+        index
+    }
+
+    fn identify(&mut self) -> Result<Identity, AhciError> {
+        let mut data = [0u16; 256];
+        self.issue(IDENTIFY, 0, 1, data.as_mut_ptr() as usize, false)?;
+        Ok(Identity::new(&data))
+    }
+}
+
+/// Polls `cond` until it is true
+fn wait_until(mut cond: impl FnMut() -> bool) -> Result<(), AhciError> {
+    let deadline = interrupt::ticks() + TIMEOUT;
+    while !cond() {
+        if interrupt::ticks() > deadline {
+            return Err(AhciError::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// Starts the ports of the AHCI controller `device` holding a SATA disk and registers the disks
+pub fn init(device: &Device, func: Function) {
+    let bar = match device.bar(func, ABAR) {
+        Some(bar) => bar,
+        None => return,
+    };
+    let abar = match bar {
+        Bar::MMIO { base, size, .. } => memory_manager::mmio_map(base, size, bar.cache_type())
+            .expect("Could not map AHCI registers"),
+        Bar::IO { .. } => return,
+    };
+    let command = device.read_u16(func, pci::COMMAND);
+    // SAFETY: COMMAND is the command register, enabling memory space and bus mastering
+    // has no other side effect
+    unsafe {
+        device.write_u16(
+            func,
+            pci::COMMAND,
+            command | PCI_MEMORY_SPACE | PCI_BUS_MASTER,
+        );
+    }
+
+    let hba = |reg: usize| (abar + reg) as *mut u32;
+    // SAFETY: CAP, GHC and PORTS_IMPLEMENTED are HBA registers
+    let (capabilities, implemented) = unsafe {
+        hba(GHC).write_volatile(hba(GHC).read_volatile() | GHC_AHCI_ENABLE);
+        (
+            hba(CAP).read_volatile(),
+            hba(PORTS_IMPLEMENTED).read_volatile(),
+        )
+    };
+    if capabilities & CAP_64BIT == 0 {
+        serial_println!("AHCI: controller without 64bit addressing is not supported");
+        return;
+    }
+
+    let first = DISKS.lock().len();
+    for number in (0..32).filter(|number| implemented & (1 << number) != 0) {
+        let registers = abar + PORT_REGISTERS + number * PORT_SIZE;
+        // SAFETY: SSTS and SIG are port registers of an implemented port
+        let (status, signature) = unsafe {
+            let port = |reg: usize| ((registers + reg) as *const u32).read_volatile();
+            (port(SSTS), port(SIG))
+        };
+        if status & 0xF != DEVICE_PRESENT || signature != SATA_SIGNATURE {
+            continue;
+        }
+
+        let frame = ALLOCATOR
+            .obtain()
+            .allocate_frame()
+            .expect("No frame left for AHCI port");
+        let memory = memory_manager::phys_to_virt(frame.base_addr) as *mut u8;
+        // SAFETY: The frame was just allocated
+        unsafe {
+            memory.write_bytes(0, PAGE_SIZE);
+        }
+
+        let mut port = Port {
+            number,
+            registers,
+            memory: frame.base_addr,
+            stopped: false,
+        };
+        match port.start().and_then(|_| port.identify()) {
+            Ok(identity) => DISKS.lock().push(SataDisk { port, identity }),
+            Err(err) => {
+                serial_println!("AHCI port {}: {:?}", number, err);
+                ALLOCATOR.obtain().deallocate_frame(frame);
+            }
+        }
+    }
+
+    // Disks of the controllers found before were already printed
+    for disk in disks().skip(first) {
+        block_device::register(Disk::new("AHCI", disk));
+        let identity = disk.identity();
+        serial_println!(
+            "AHCI {}: {} (serial {}), {} sectors",
+            disk,
+            identity.model,
+            identity.serial,
+            identity.sectors
+        );
+        for (index, partition) in partitions(&disk).unwrap_or_default().iter().enumerate() {
+            serial_println!("  Partition {}: {}", index + 1, partition);
+        }
+    }
+}
+
+/// Returns the SATA disks found at boot
+pub fn disks() -> impl Iterator<Item = AhciDisk> {
+    let disks: Vec<AhciDisk> = DISKS
+        .lock()
+        .iter()
+        .enumerate()
+        .map(|(index, disk)| AhciDisk {
+            index,
+            block_count: disk.identity.sectors,
+            lba48: disk.identity.lba48,
+        })
+        .collect();
+    disks.into_iter()
+}
+
+/// SATA disk attached to an AHCI port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AhciDisk {
+    index: usize,
+    /// Copied from the identity, they are needed by every command
+    block_count: u64,
+    lba48: bool,
+}
+
+impl AhciDisk {
+    /// Returns what the disk reported at boot
+    pub fn identity(&self) -> Identity {
+        DISKS.lock()[self.index].identity.clone()
+    }
+
+    /// Read `sectors` sectors starting at `lba` in `dst`
+    pub fn read_sectors(
+        &self,
+        lba: usize,
+        sectors: usize,
+        dst: &mut [u8],
+    ) -> Result<(), AhciError> {
+        let dst = &mut dst[..sectors * SECTOR_SIZE];
+        let (command, max_sectors) = self.command(READ_DMA_EXT, READ_DMA);
+        let mut disks = DISKS.lock();
+        let port = &mut disks[self.index].port;
+
+        for (index, chunk) in dst.chunks_mut(max_sectors * SECTOR_SIZE).enumerate() {
+            let lba = lba + index * max_sectors;
+            let count = chunk.len() / SECTOR_SIZE;
+            if chunk.as_ptr() as usize % 2 == 0 {
+                port.issue(command, lba, count, chunk.as_mut_ptr() as usize, false)?;
+            } else {
+                // The HBA needs word aligned buffers
+                let mut bounce = alloc::vec![0u16; chunk.len() / 2];
+                port.issue(command, lba, count, bounce.as_mut_ptr() as usize, false)?;
+                for (bytes, word) in chunk.chunks_mut(2).zip(&bounce) {
+                    bytes.copy_from_slice(&word.to_le_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `sectors` sectors starting at `lba` from `src`
+    pub fn write_sectors(&self, lba: usize, sectors: usize, src: &[u8]) -> Result<(), AhciError> {
+        let src = &src[..sectors * SECTOR_SIZE];
+        let (command, max_sectors) = self.command(WRITE_DMA_EXT, WRITE_DMA);
+        let mut disks = DISKS.lock();
+        let port = &mut disks[self.index].port;
+
+        for (index, chunk) in src.chunks(max_sectors * SECTOR_SIZE).enumerate() {
+            let lba = lba + index * max_sectors;
+            let count = chunk.len() / SECTOR_SIZE;
+            if chunk.as_ptr() as usize % 2 == 0 {
+                port.issue(command, lba, count, chunk.as_ptr() as usize, true)?;
+            } else {
+                // The HBA needs word aligned buffers
+                let bounce: Vec<u16> = chunk
+                    .chunks(2)
+                    .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+                    .collect();
+                port.issue(command, lba, count, bounce.as_ptr() as usize, true)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the sectors written so far persistent
+    pub fn flush(&self) -> Result<(), AhciError> {
+        let (command, _) = self.command(CACHE_FLUSH_EXT, CACHE_FLUSH);
+        DISKS.lock()[self.index].port.issue(command, 0, 0, 0, false)
+    }
+
+    /// Returns the 48bit LBA command if the disk supports it, the 28bit one otherwise
+    /// along with the most sectors a command transfers
+    fn command(&self, command_ext: u8, command: u8) -> (u8, usize) {
+        if self.lba48 {
+            (command_ext, COMMAND_SECTORS)
+        } else {
+            (command, MAX_SECTORS)
+        }
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, lba: usize, count: usize, dst: &mut [u8]) -> Result<(), BlockError> {
+        check_blocks(self, lba, count, dst.len())?;
+        Ok(self.read_sectors(lba, count, dst)?)
+    }
+
+    fn write_blocks(&self, lba: usize, count: usize, src: &[u8]) -> Result<(), BlockError> {
+        check_blocks(self, lba, count, src.len())?;
+        Ok(self.write_sectors(lba, count, src)?)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(AhciDisk::flush(self)?)
+    }
+}
+
+impl fmt::Display for AhciDisk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "port {}", DISKS.lock()[self.index].port.number)
+    }
+}
@@ -1,6 +1,8 @@
 /// e1000 driver based on
 /// https://www.intel.com/content/dam/www/public/us/en/documents/manuals/pcie-gbe-controllers-open-source-manual.pdf
 use crate::arch::pci::*;
+use crate::memory_manager::{self, MmioError};
+use crate::serial_println;
 
 pub const DEVICE_TYPE: DeviceClass = DeviceClass::EthernetController;
 
@@ -14,27 +16,45 @@ const RESET: u32 = 0x4000000;
 const EEPROM_PRESENT: u32 = 1 << 8;
 
 pub fn init(device: &Device) {
-    let e1000 = E1000::new(device);
+    let e1000 = match E1000::new(device) {
+        Ok(e1000) => e1000,
+        Err(err) => {
+            serial_println!("e1000: {:?}", err);
+            return;
+        }
+    };
     e1000.reset();
     e1000.init();
 }
 
+#[derive(Debug)]
+pub enum E1000Error {
+    /// BAR 0 is missing or not memory mapped
+    NoRegisters,
+    /// The registers could not be mapped
+    Mmio(MmioError),
+}
+
 struct E1000 {
     device: Device,
     pub mmio: usize,
 }
 
 impl E1000 {
-    pub fn new(device: &Device) -> E1000 {
-        if let Some(Bar::MMIO { base, size, .. }) = device.bar(Function::Zero, 0) {
-            crate::memory_manager::mmio_map(base, size);
-            E1000 {
-                device: *device,
-                mmio: base,
+    pub fn new(device: &Device) -> Result<E1000, E1000Error> {
+        let bar = device
+            .bar(Function::Zero, 0)
+            .ok_or(E1000Error::NoRegisters)?;
+        let mmio = match bar {
+            Bar::MMIO { base, size, .. } => {
+                memory_manager::mmio_map(base, size, bar.cache_type()).map_err(E1000Error::Mmio)?
             }
-        } else {
-            panic!("Unexpected BAR form");
-        }
+            Bar::IO { .. } => return Err(E1000Error::NoRegisters),
+        };
+        Ok(E1000 {
+            device: *device,
+            mmio,
+        })
     }
 
     pub fn reset(&self) {
@@ -48,7 +68,7 @@ impl E1000 {
     }
 
     pub fn init(&self) {
-        crate::serial_println!("EEPROM present: {}", self.is_eeprom_present());
+        serial_println!("EEPROM present: {}", self.is_eeprom_present());
     }
 
     /// Read u32 from device
@@ -1,9 +1,13 @@
 //! This module provides functions to interface with devices
 
+pub mod ahci;
 pub mod e1000;
 pub mod ps2_keyboard;
 pub mod vga_driver;
+pub mod virtio;
+pub mod virtio_blk;
 
+use crate::arch::ata;
 use crate::arch::pci::Device;
 
 pub fn init(devices: &[Device]) {
@@ -11,5 +15,14 @@ pub fn init(devices: &[Device]) {
         if device.class() == e1000::DEVICE_TYPE {
             e1000::init(device)
         }
+        if let Some(func) = device.find_function(ata::DEVICE_TYPE) {
+            ata::init_dma(device, func)
+        }
+        if let Some(func) = device.find_function(ahci::DEVICE_TYPE) {
+            ahci::init(device, func)
+        }
+        if virtio_blk::is_virtio_blk(device.id()) {
+            virtio_blk::init(device)
+        }
     }
 }
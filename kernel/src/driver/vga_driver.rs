@@ -1,3 +1,4 @@
+use crate::memory_manager::layout::low_memory;
 use crate::utils::lazy_static::LazyStatic;
 use core::fmt;
 use core::fmt::Write;
@@ -6,6 +7,7 @@ pub static WRITER: LazyStatic<Writer> = LazyStatic::new(Writer::new);
 
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
+const BUFFER_ADDR: usize = 0xb8000;
 
 #[allow(dead_code)]
 #[repr(u8)]
@@ -61,7 +63,7 @@ impl Writer {
         Writer {
             col_pos: 0,
             color_code: ColorCode::new(Color::White, Color::Black),
-            buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+            buffer: unsafe { &mut *(low_memory(BUFFER_ADDR) as *mut Buffer) },
         }
     }
 
@@ -0,0 +1,502 @@
+//! virtio-pci transport and split virtqueues, based on the Virtio 1.1 specification
+//! Devices are reached through the legacy I/O BAR or the modern capabilities pointing to MMIO
+//! Queues are polled by the drivers, devices are asked never to interrupt
+
+use crate::arch::paging::pat::CacheType;
+use crate::arch::pci::{self, Bar, Device, Function};
+use crate::arch::port;
+use crate::memory_manager::{self, ALLOCATOR, PAGE_SIZE};
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+/// Bits of the PCI command register enabling I/O space, MMIO and bus mastering,
+/// and disabling INTx interrupts
+const PCI_IO_SPACE: u16 = 1;
+const PCI_MEMORY_SPACE: u16 = 1 << 1;
+const PCI_BUS_MASTER: u16 = 1 << 2;
+const PCI_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+/// BAR holding the registers of the legacy interface
+const LEGACY_BAR: u8 = 0;
+
+// Legacy registers, as offsets from the I/O BAR
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_PFN: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+/// Device specific configuration, MSI-X is never enabled so it always starts here
+const LEGACY_CONFIG: u16 = 0x14;
+
+/// PCI capability ID of the virtio structures
+const VENDOR_CAPABILITY: u8 = 0x09;
+// Types of virtio structures
+const COMMON_CFG: u8 = 1;
+const NOTIFY_CFG: u8 = 2;
+const DEVICE_CFG: u8 = 4;
+
+// Common configuration registers of the modern interface
+const DEVICE_FEATURE_SELECT: usize = 0x00;
+const DEVICE_FEATURE: usize = 0x04;
+const DRIVER_FEATURE_SELECT: usize = 0x08;
+const DRIVER_FEATURE: usize = 0x0C;
+const DEVICE_STATUS: usize = 0x14;
+const QUEUE_SELECT: usize = 0x16;
+const QUEUE_SIZE: usize = 0x18;
+const QUEUE_ENABLE: usize = 0x1C;
+const QUEUE_NOTIFY_OFF: usize = 0x1E;
+const QUEUE_DESC: usize = 0x20;
+const QUEUE_DRIVER: usize = 0x28;
+const QUEUE_DEVICE: usize = 0x30;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 0x80;
+
+/// Feature bit set by devices implementing the modern interface
+const FEATURE_VERSION_1: u64 = 1 << 32;
+
+/// Largest queue the driver sets up, legacy devices impose their own size
+const MAX_QUEUE_SIZE: u16 = 128;
+
+// Descriptor flags
+const DESC_NEXT: u16 = 1;
+const DESC_WRITE: u16 = 2;
+/// Flag of the available ring asking the device not to interrupt
+const AVAIL_NO_INTERRUPT: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// The device does not support the features the driver needs
+    FeaturesRejected,
+    /// The device does not have the queue
+    QueueUnavailable,
+    /// The device did not complete the request in time, it was reset and is not used anymore
+    Timeout,
+    /// The device was reset after a timeout
+    Reset,
+    /// The queue does not have enough free descriptors for the request
+    QueueFull,
+    /// The device failed to complete the request
+    Io,
+    /// The device does not support the request
+    Unsupported,
+}
+
+/// How the registers of a device are reached
+pub enum Transport {
+    Legacy {
+        /// First port of the I/O BAR
+        port: u16,
+    },
+    Modern {
+        /// Virtual addresses of the common and device specific configurations
+        common: usize,
+        device: usize,
+        /// Virtual address of the notification area
+        notify: usize,
+        /// Bytes between the notification addresses of two consecutive queues
+        notify_multiplier: u32,
+    },
+}
+
+impl Transport {
+    /// Enables `func` of `device` and finds its registers,
+    /// the modern interface is preferred when the device offers both
+    pub fn new(device: &Device, func: Function) -> Option<Transport> {
+        let command = device.read_u16(func, pci::COMMAND);
+        // SAFETY: COMMAND is the command register, the device is not set up yet
+        // so enabling it has no other side effect
+        unsafe {
+            device.write_u16(
+                func,
+                pci::COMMAND,
+                command | PCI_IO_SPACE | PCI_MEMORY_SPACE | PCI_BUS_MASTER | PCI_INTERRUPT_DISABLE,
+            );
+        }
+
+        Transport::modern(device, func).or_else(|| match device.bar(func, LEGACY_BAR)? {
+            Bar::IO { port } => Some(Transport::Legacy { port: port as u16 }),
+            Bar::MMIO { .. } => None,
+        })
+    }
+
+    fn modern(device: &Device, func: Function) -> Option<Transport> {
+        // Every BAR is mapped once, the structures often share one
+        let mut mapped: Vec<(u8, usize)> = Vec::new();
+        let mut map = |bar_index: u8, offset: u32| -> Option<usize> {
+            let base = match mapped.iter().find(|(index, _)| *index == bar_index) {
+                Some(&(_, base)) => base,
+                None => {
+                    let bar = device.bar(func, bar_index)?;
+                    // Registers have side effects, they must not be cached
+                    let base = match bar {
+                        Bar::MMIO { base, size, .. } => {
+                            memory_manager::mmio_map(base, size, CacheType::Uncached).ok()?
+                        }
+                        Bar::IO { .. } => return None,
+                    };
+                    mapped.push((bar_index, base));
+                    base
+                }
+            };
+            Some(base + offset as usize)
+        };
+
+        let (mut common, mut notify, mut device_cfg) = (None, None, None);
+        let mut notify_multiplier = 0;
+        for (_, cap) in device
+            .capabilities(func)
+            .into_iter()
+            .filter(|&(id, _)| id == VENDOR_CAPABILITY)
+        {
+            let bar_index = device.read_u8(func, cap + 4);
+            let offset = device.read_u32(func, cap + 8);
+            // Only the first structure of each type is used
+            match device.read_u8(func, cap + 3) {
+                COMMON_CFG if common.is_none() => common = map(bar_index, offset),
+                NOTIFY_CFG if notify.is_none() => {
+                    notify = map(bar_index, offset);
+                    notify_multiplier = device.read_u32(func, cap + 16);
+                }
+                DEVICE_CFG if device_cfg.is_none() => device_cfg = map(bar_index, offset),
+                _ => {}
+            }
+        }
+
+        Some(Transport::Modern {
+            common: common?,
+            device: device_cfg?,
+            notify: notify?,
+            notify_multiplier,
+        })
+    }
+
+    /// Resets the device and negotiates the features of `wanted` it offers
+    /// Returns the accepted features
+    pub fn init(&self, wanted: u64) -> Result<u64, VirtioError> {
+        self.reset();
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        // SAFETY: The registers belong to the device, the driver is negotiating its features
+        let features = unsafe {
+            match *self {
+                Transport::Legacy { port } => {
+                    let features = port::ind(port + LEGACY_DEVICE_FEATURES) as u64 & wanted;
+                    port::outd(port + LEGACY_DRIVER_FEATURES, features as u32);
+                    features
+                }
+                Transport::Modern { common, .. } => {
+                    let reg = |offset: usize| (common + offset) as *mut u32;
+                    let mut offered = 0;
+                    for select in 0..2 {
+                        reg(DEVICE_FEATURE_SELECT).write_volatile(select);
+                        offered |= (reg(DEVICE_FEATURE).read_volatile() as u64) << (32 * select);
+                    }
+                    let features = offered & (wanted | FEATURE_VERSION_1);
+                    for select in 0..2 {
+                        reg(DRIVER_FEATURE_SELECT).write_volatile(select);
+                        reg(DRIVER_FEATURE).write_volatile((features >> (32 * select)) as u32);
+                    }
+
+                    self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+                    if features & FEATURE_VERSION_1 == 0 || self.status() & STATUS_FEATURES_OK == 0
+                    {
+                        self.set_status(STATUS_FAILED);
+                        return Err(VirtioError::FeaturesRejected);
+                    }
+                    features
+                }
+            }
+        };
+        Ok(features)
+    }
+
+    /// Resets the device, once done it does not access its queues nor the buffers they hold
+    pub fn reset(&self) {
+        self.set_status(0);
+        // Modern devices read back 0 once the reset is complete, the buffers are not
+        // released before that however long it takes
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Tells the device the driver is ready, once its queues are set up
+    pub fn start(&self) {
+        let status = match self {
+            Transport::Legacy { .. } => STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK,
+            Transport::Modern { .. } => {
+                STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK
+            }
+        };
+        self.set_status(status);
+    }
+
+    fn status(&self) -> u8 {
+        // SAFETY: The status register has no side effect when read
+        unsafe {
+            match *self {
+                Transport::Legacy { port } => port::inb(port + LEGACY_STATUS),
+                Transport::Modern { common, .. } => {
+                    ((common + DEVICE_STATUS) as *const u8).read_volatile()
+                }
+            }
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        // SAFETY: The status register belongs to the device, writing 0 resets it
+        unsafe {
+            match *self {
+                Transport::Legacy { port } => port::outb(port + LEGACY_STATUS, status),
+                Transport::Modern { common, .. } => {
+                    ((common + DEVICE_STATUS) as *mut u8).write_volatile(status)
+                }
+            }
+        }
+    }
+
+    /// Sets up the queue `index` of the device
+    pub fn setup_queue(&self, index: u16) -> Result<Virtqueue, VirtioError> {
+        // SAFETY: The queue registers belong to the device, the queue is not in use yet
+        unsafe {
+            match *self {
+                Transport::Legacy { port } => {
+                    port::outw(port + LEGACY_QUEUE_SELECT, index);
+                    let size = port::inw(port + LEGACY_QUEUE_SIZE);
+                    let queue = Virtqueue::new(index, size, 0)?;
+                    port::outd(port + LEGACY_QUEUE_PFN, (queue.memory / PAGE_SIZE) as u32);
+                    Ok(queue)
+                }
+                Transport::Modern { common, .. } => {
+                    let reg = |offset: usize| (common + offset) as *mut u16;
+                    let addr = |offset: usize, value: usize| {
+                        let reg = (common + offset) as *mut u32;
+                        reg.write_volatile(value as u32);
+                        reg.add(1).write_volatile((value >> 32) as u32);
+                    };
+
+                    reg(QUEUE_SELECT).write_volatile(index);
+                    let size = reg(QUEUE_SIZE).read_volatile().min(MAX_QUEUE_SIZE);
+                    let notify_offset = reg(QUEUE_NOTIFY_OFF).read_volatile();
+                    let queue = Virtqueue::new(index, size, notify_offset)?;
+                    reg(QUEUE_SIZE).write_volatile(size);
+                    addr(QUEUE_DESC, queue.memory);
+                    addr(QUEUE_DRIVER, queue.memory + queue.avail_offset());
+                    addr(QUEUE_DEVICE, queue.memory + queue.used_offset);
+                    reg(QUEUE_ENABLE).write_volatile(1);
+                    Ok(queue)
+                }
+            }
+        }
+    }
+
+    /// Tells the device new buffers are available in `queue`
+    pub fn notify(&self, queue: &Virtqueue) {
+        // The available ring must be visible before the device looks at it
+        fence(Ordering::SeqCst);
+        // SAFETY: The notification register belongs to the device
+        unsafe {
+            match *self {
+                Transport::Legacy { port } => port::outw(port + LEGACY_QUEUE_NOTIFY, queue.index),
+                Transport::Modern {
+                    notify,
+                    notify_multiplier,
+                    ..
+                } => {
+                    let offset = queue.notify_offset as usize * notify_multiplier as usize;
+                    ((notify + offset) as *mut u16).write_volatile(queue.index)
+                }
+            }
+        }
+    }
+
+    /// Reads the u32 at `offset` in the device specific configuration
+    pub fn read_config_u32(&self, offset: usize) -> u32 {
+        // SAFETY: The device specific configuration has no side effect when read
+        unsafe {
+            match *self {
+                Transport::Legacy { port } => port::ind(port + LEGACY_CONFIG + offset as u16),
+                Transport::Modern { device, .. } => {
+                    ((device + offset) as *const u32).read_volatile()
+                }
+            }
+        }
+    }
+
+    /// Reads the u64 at `offset` in the device specific configuration
+    pub fn read_config_u64(&self, offset: usize) -> u64 {
+        self.read_config_u32(offset) as u64 | (self.read_config_u32(offset + 4) as u64) << 32
+    }
+}
+
+/// Memory range the device reads from or writes to
+pub struct Buffer {
+    /// Physical address of the range
+    pub addr: usize,
+    pub len: u32,
+    /// Whether the device writes to the range
+    pub writable: bool,
+}
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// Split virtqueue, laid out as legacy devices expect it:
+/// the descriptor table, the available ring, then the used ring on the next page
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    notify_offset: u16,
+    /// Physical address of the descriptor table
+    memory: usize,
+    used_offset: usize,
+    /// First descriptor of the free list, chained through their `next` field
+    free: u16,
+    free_count: u16,
+    /// Index of the next entry of the available ring
+    avail_index: u16,
+    /// Index of the next entry of the used ring to process
+    used_index: u16,
+}
+
+impl Virtqueue {
+    fn new(index: u16, size: u16, notify_offset: u16) -> Result<Virtqueue, VirtioError> {
+        if size == 0 {
+            return Err(VirtioError::QueueUnavailable);
+        }
+        let avail_end = 16 * size as usize + 6 + 2 * size as usize;
+        let used_offset = (avail_end + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        let pages = (used_offset + 6 + 8 * size as usize + PAGE_SIZE - 1) / PAGE_SIZE;
+        let frame = ALLOCATOR
+            .obtain()
+            .allocate_contiguous(pages)
+            .expect("No frame left for virtqueue");
+
+        let queue = Virtqueue {
+            index,
+            size,
+            notify_offset,
+            memory: frame.base_addr,
+            used_offset,
+            free: 0,
+            free_count: size,
+            avail_index: 0,
+            used_index: 0,
+        };
+        let virt_addr = memory_manager::phys_to_virt(queue.memory) as *mut u8;
+        // SAFETY: The frames were just allocated
+        unsafe {
+            virt_addr.write_bytes(0, pages * PAGE_SIZE);
+            for desc in 0..size {
+                (*queue.descriptor(desc)).next = desc.wrapping_add(1);
+            }
+            queue.avail(0).write_volatile(AVAIL_NO_INTERRUPT);
+        }
+        Ok(queue)
+    }
+
+    fn avail_offset(&self) -> usize {
+        16 * self.size as usize
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        (memory_manager::phys_to_virt(self.memory) + 16 * index as usize) as *mut Descriptor
+    }
+
+    /// Returns the u16 at `offset` in the available ring
+    fn avail(&self, offset: usize) -> *mut u16 {
+        (memory_manager::phys_to_virt(self.memory) + self.avail_offset() + offset) as *mut u16
+    }
+
+    /// Returns the u32 at `offset` in the used ring
+    fn used(&self, offset: usize) -> *const u32 {
+        (memory_manager::phys_to_virt(self.memory) + self.used_offset + offset) as *const u32
+    }
+
+    /// Makes `buffers` available to the device as one chain
+    /// Returns the ID of the chain, None if there are not enough free descriptors
+    pub fn submit(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return None;
+        }
+
+        let head = self.free;
+        for (index, buffer) in buffers.iter().enumerate() {
+            let desc = self.descriptor(self.free);
+            let has_next = index + 1 < buffers.len();
+            let flags =
+                if buffer.writable { DESC_WRITE } else { 0 } | if has_next { DESC_NEXT } else { 0 };
+            // SAFETY: The descriptor is free, its `next` field links the free list
+            unsafe {
+                let next = (*desc).next;
+                desc.write_volatile(Descriptor {
+                    addr: buffer.addr as u64,
+                    len: buffer.len,
+                    flags,
+                    next,
+                });
+                self.free = next;
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+
+        let slot = 4 + 2 * (self.avail_index % self.size) as usize;
+        self.avail_index = self.avail_index.wrapping_add(1);
+        // SAFETY: The entry and the index are in the available ring,
+        // the entry is visible before the index is
+        unsafe {
+            self.avail(slot).write_volatile(head);
+            fence(Ordering::SeqCst);
+            self.avail(2).write_volatile(self.avail_index);
+        }
+        Some(head)
+    }
+
+    /// Returns the ID of a chain the device is done with and the bytes it wrote,
+    /// its descriptors are freed
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        fence(Ordering::SeqCst);
+        // SAFETY: The index and the entries are in the used ring
+        let (id, len) = unsafe {
+            let used_index = (self.used(0) as *const u16).add(1).read_volatile();
+            if used_index == self.used_index {
+                return None;
+            }
+            let elem = 4 + 8 * (self.used_index % self.size) as usize;
+            (
+                self.used(elem).read_volatile() as u16,
+                self.used(elem + 4).read_volatile(),
+            )
+        };
+        self.used_index = self.used_index.wrapping_add(1);
+
+        // The chain goes back to the front of the free list
+        let mut tail = id;
+        let mut count = 1;
+        // SAFETY: The device gave the chain back, its descriptors are not used anymore
+        unsafe {
+            while (*self.descriptor(tail)).flags & DESC_NEXT != 0 {
+                tail = (*self.descriptor(tail)).next;
+                count += 1;
+            }
+            (*self.descriptor(tail)).next = self.free;
+        }
+        self.free = id;
+        self.free_count += count;
+        Some((id, len))
+    }
+}
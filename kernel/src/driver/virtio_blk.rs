@@ -0,0 +1,311 @@
+//! virtio-blk driver, one request at a time is submitted and polled until it completes
+
+use super::virtio::{Buffer, Transport, VirtioError, Virtqueue};
+use crate::arch::interrupt::{self, TIMER_FREQUENCY};
+use crate::arch::pci::{Device, DeviceId, Function};
+use crate::block_device::{self, check_blocks, partitions, BlockDevice, BlockError, Disk};
+use crate::memory_manager::{self, ALLOCATOR, PAGE_SIZE};
+use crate::serial_println;
+use crate::utils::mutex::Mutex;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Device IDs of the transitional and modern block devices
+const DEVICE_IDS: [u16; 2] = [0x1001, 0x1042];
+
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+const FEATURE_FLUSH: u64 = 1 << 9;
+
+/// Offset of the capacity in sectors in the device specific configuration
+const CAPACITY: usize = 0;
+
+const REQUEST_QUEUE: u16 = 0;
+
+// Request types
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+// Request status
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 2;
+
+/// Size of the sectors the requests address, whatever the block size of the disk
+const SECTOR_SIZE: usize = 512;
+/// Sectors of a request, their pages always fit in the queue along with the header and status
+const REQUEST_SECTORS: usize = 128;
+/// Most descriptors of a request, the data might not be page aligned
+const REQUEST_DESCRIPTORS: usize = REQUEST_SECTORS * SECTOR_SIZE / PAGE_SIZE + 3;
+
+// Layout of the page each disk gets for its request header and status
+const HEADER: usize = 0;
+const HEADER_SIZE: u32 = 16;
+const STATUS: usize = 16;
+
+/// Ticks a device has to complete a request
+const TIMEOUT: u64 = 5 * TIMER_FREQUENCY;
+
+/// Every virtio-blk disk found, indexed by `VirtioDisk::index`
+static DISKS: Mutex<Vec<BlkDevice>> = Mutex::new(Vec::new());
+
+struct BlkDevice {
+    pci: Device,
+    transport: Transport,
+    queue: Virtqueue,
+    /// Physical address of the page holding the request header and status
+    memory: usize,
+    features: u64,
+    sectors: u64,
+    /// Set once the device was reset after a timeout, its queue is not usable anymore
+    reset: bool,
+}
+
+#[repr(C)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+impl BlkDevice {
+    /// Sends a request of type `kind` at `sector`, with `len` bytes of data at `buffer`
+    fn request(
+        &mut self,
+        kind: u32,
+        sector: u64,
+        buffer: usize,
+        len: usize,
+    ) -> Result<(), VirtioError> {
+        if self.reset {
+            return Err(VirtioError::Reset);
+        }
+        let memory = memory_manager::phys_to_virt(self.memory);
+        // SAFETY: The page belongs to the disk, no request is in flight
+        unsafe {
+            ((memory + HEADER) as *mut RequestHeader).write_volatile(RequestHeader {
+                kind,
+                reserved: 0,
+                sector,
+            });
+            ((memory + STATUS) as *mut u8).write_volatile(!0);
+        }
+
+        let mut buffers = Vec::with_capacity(REQUEST_DESCRIPTORS);
+        buffers.push(Buffer {
+            addr: self.memory + HEADER,
+            len: HEADER_SIZE,
+            writable: false,
+        });
+        // One descriptor per page, they are not physically contiguous
+        let end = buffer + len;
+        let mut addr = buffer;
+        while addr < end {
+            let len = (PAGE_SIZE - addr % PAGE_SIZE).min(end - addr);
+            buffers.push(Buffer {
+                addr: memory_manager::virt_to_phys(addr).expect("DMA buffer is not mapped"),
+                len: len as u32,
+                writable: kind == REQUEST_IN,
+            });
+            addr += len;
+        }
+        buffers.push(Buffer {
+            addr: self.memory + STATUS,
+            len: 1,
+            writable: true,
+        });
+
+        let head = self.queue.submit(&buffers).ok_or(VirtioError::QueueFull)?;
+        self.transport.notify(&self.queue);
+
+        let deadline = interrupt::ticks() + TIMEOUT;
+        loop {
+            if let Some((id, _)) = self.queue.pop_used() {
+                if id == head {
+                    break;
+                }
+            } else if interrupt::ticks() > deadline {
+                // The device may still access the buffers, which the caller frees once this
+                // returns, the reset makes sure it does not
+                self.transport.reset();
+                self.reset = true;
+                return Err(VirtioError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+
+        // SAFETY: The device wrote the status of the request
+        match unsafe { ((memory + STATUS) as *const u8).read_volatile() } {
+            STATUS_OK => Ok(()),
+            STATUS_UNSUPPORTED => Err(VirtioError::Unsupported),
+            _ => Err(VirtioError::Io),
+        }
+    }
+}
+
+/// Returns whether `id` is a virtio-blk device
+pub fn is_virtio_blk(id: DeviceId) -> bool {
+    id.vendor_id == super::virtio::VENDOR_ID && DEVICE_IDS.contains(&id.device_id)
+}
+
+/// Sets up the virtio-blk device `device` and registers its disk
+pub fn init(device: &Device) {
+    let transport = match Transport::new(device, Function::Zero) {
+        Some(transport) => transport,
+        None => return,
+    };
+    let setup = transport
+        .init(FEATURE_READ_ONLY | FEATURE_FLUSH)
+        .and_then(|features| Ok((features, transport.setup_queue(REQUEST_QUEUE)?)));
+    let (features, queue) = match setup {
+        Ok(setup) => setup,
+        Err(err) => {
+            serial_println!("virtio-blk {}:{}: {:?}", device.bus, device.slot, err);
+            return;
+        }
+    };
+    transport.start();
+
+    let frame = ALLOCATOR
+        .obtain()
+        .allocate_frame()
+        .expect("No frame left for virtio-blk requests");
+    let sectors = transport.read_config_u64(CAPACITY);
+    let index = {
+        let mut disks = DISKS.lock();
+        disks.push(BlkDevice {
+            pci: *device,
+            transport,
+            queue,
+            memory: frame.base_addr,
+            features,
+            sectors,
+            reset: false,
+        });
+        disks.len() - 1
+    };
+
+    let disk = VirtioDisk { index };
+    block_device::register(Disk::new("virtio", disk));
+    serial_println!("virtio-blk {}: {} sectors", disk, sectors);
+    for (index, partition) in partitions(&disk).unwrap_or_default().iter().enumerate() {
+        serial_println!("  Partition {}: {}", index + 1, partition);
+    }
+}
+
+/// Returns the virtio-blk disks found at boot
+pub fn disks() -> impl Iterator<Item = VirtioDisk> {
+    (0..DISKS.lock().len()).map(|index| VirtioDisk { index })
+}
+
+/// Disk behind a virtio-blk device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtioDisk {
+    index: usize,
+}
+
+impl VirtioDisk {
+    /// Returns the capacity of the disk in sectors
+    pub fn sectors(&self) -> u64 {
+        DISKS.lock()[self.index].sectors
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        DISKS.lock()[self.index].features & FEATURE_READ_ONLY != 0
+    }
+
+    /// Read `sectors` sectors starting at `lba` in `dst`
+    pub fn read_sectors(
+        &self,
+        lba: usize,
+        sectors: usize,
+        dst: &mut [u8],
+    ) -> Result<(), VirtioError> {
+        let dst = &mut dst[..sectors * SECTOR_SIZE];
+        let mut disks = DISKS.lock();
+        let disk = &mut disks[self.index];
+
+        for (index, chunk) in dst.chunks_mut(REQUEST_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = lba + index * REQUEST_SECTORS;
+            disk.request(
+                REQUEST_IN,
+                lba as u64,
+                chunk.as_mut_ptr() as usize,
+                chunk.len(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Write `sectors` sectors starting at `lba` from `src`
+    pub fn write_sectors(&self, lba: usize, sectors: usize, src: &[u8]) -> Result<(), VirtioError> {
+        let src = &src[..sectors * SECTOR_SIZE];
+        let mut disks = DISKS.lock();
+        let disk = &mut disks[self.index];
+
+        for (index, chunk) in src.chunks(REQUEST_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = lba + index * REQUEST_SECTORS;
+            disk.request(
+                REQUEST_OUT,
+                lba as u64,
+                chunk.as_ptr() as usize,
+                chunk.len(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Makes the sectors written so far persistent
+    /// Devices without the flush feature have no write cache, there is nothing to do
+    pub fn flush(&self) -> Result<(), VirtioError> {
+        let mut disks = DISKS.lock();
+        let disk = &mut disks[self.index];
+        if disk.features & FEATURE_FLUSH == 0 {
+            return Ok(());
+        }
+        disk.request(REQUEST_FLUSH, 0, 0, 0)
+    }
+}
+
+impl From<VirtioError> for BlockError {
+    fn from(err: VirtioError) -> BlockError {
+        match err {
+            VirtioError::Timeout => BlockError::Timeout,
+            VirtioError::Reset => BlockError::Offline,
+            _ => BlockError::Io,
+        }
+    }
+}
+
+impl BlockDevice for VirtioDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors()
+    }
+
+    fn read_blocks(&self, lba: usize, count: usize, dst: &mut [u8]) -> Result<(), BlockError> {
+        check_blocks(self, lba, count, dst.len())?;
+        Ok(self.read_sectors(lba, count, dst)?)
+    }
+
+    fn write_blocks(&self, lba: usize, count: usize, src: &[u8]) -> Result<(), BlockError> {
+        check_blocks(self, lba, count, src.len())?;
+        if self.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        Ok(self.write_sectors(lba, count, src)?)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(VirtioDisk::flush(self)?)
+    }
+}
+
+impl fmt::Display for VirtioDisk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pci = DISKS.lock()[self.index].pci;
+        write!(f, "PCI {}:{}", pci.bus, pci.slot)
+    }
+}
@@ -0,0 +1,508 @@
+//! Read-only ISO 9660 file system, as found on CD-ROMs
+//! Names come from Rock Ridge NM entries when the volume has them, otherwise the ISO 9660
+//! names are shown lowercase and without their version, as Linux does
+
+use crate::block_device::BlockDevice;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+/// Size of the sectors volume descriptors are addressed in
+const SECTOR_SIZE: usize = 2048;
+/// Sector of the first volume descriptor
+const FIRST_DESCRIPTOR: usize = 16;
+const PRIMARY_DESCRIPTOR: u8 = 1;
+const TERMINATOR: u8 = 255;
+const IDENTIFIER: &[u8] = b"CD001";
+
+// Offsets in the primary volume descriptor
+const LOGICAL_BLOCK_SIZE: usize = 128;
+const ROOT_RECORD: usize = 156;
+
+// Offsets in a directory record
+const EXTENT: usize = 2;
+const DATA_LENGTH: usize = 10;
+const FLAGS: usize = 25;
+const NAME_LENGTH: usize = 32;
+const NAME: usize = 33;
+/// Length of a record with a single byte name, the shortest valid one
+const MIN_RECORD_LENGTH: usize = NAME + 1;
+
+/// Bit of the record flags set for directories
+const FLAG_DIRECTORY: u8 = 1 << 1;
+
+/// Bytes of the SP entry starting the system use area of the root, when SUSP is used
+const SP_CHECK: [u8; 2] = [0xBE, 0xEF];
+/// Bits of the NM flags set for the current and parent directories
+const NM_CURRENT: u8 = 1 << 1;
+const NM_PARENT: u8 = 1 << 2;
+/// Continuation areas followed for a single record, a loop on a corrupted volume stops there
+const MAX_CONTINUATIONS: usize = 16;
+/// Largest continuation area read, SUSP keeps each of them in a single logical block
+const MAX_CONTINUATION_SIZE: usize = SECTOR_SIZE;
+/// Largest directory read, the size of a corrupted record does not allocate gigabytes
+const MAX_DIRECTORY_SIZE: usize = 1 << 20;
+
+/// Entry of a directory, either a file or a directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    name: String,
+    /// Logical block of the data
+    extent: usize,
+    size: usize,
+    is_directory: bool,
+    /// Permissions from the Rock Ridge PX entry, if any
+    mode: Option<u32>,
+}
+
+impl Record {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.is_directory
+    }
+
+    /// Returns the POSIX permissions of the record, directories and files without
+    /// Rock Ridge permissions can be read by everyone, directories traversed too
+    pub fn mode(&self) -> u32 {
+        match self.mode {
+            Some(mode) => mode & 0o7777,
+            None if self.is_directory => 0o555,
+            None => 0o444,
+        }
+    }
+}
+
+/// ISO 9660 volume stored on `device`
+#[derive(Clone)]
+pub struct Iso9660<D> {
+    device: D,
+    logical_block_size: usize,
+    root: Record,
+    /// Bytes skipped at the start of every system use area, None without SUSP
+    susp_skip: Option<usize>,
+}
+
+impl<D: BlockDevice> Iso9660<D> {
+    /// Reads the primary volume descriptor of `device`, None if it does not hold an ISO 9660 volume
+    pub fn new(device: D) -> Option<Iso9660<D>> {
+        let mut fs = Iso9660 {
+            device,
+            logical_block_size: SECTOR_SIZE,
+            root: Record {
+                name: String::new(),
+                extent: 0,
+                size: 0,
+                is_directory: true,
+                mode: None,
+            },
+            susp_skip: None,
+        };
+
+        let mut sector = FIRST_DESCRIPTOR;
+        let descriptor = loop {
+            let descriptor = fs.read((sector * SECTOR_SIZE) as u64, SECTOR_SIZE)?;
+            if &descriptor[1..6] != IDENTIFIER || descriptor[0] == TERMINATOR {
+                return None;
+            }
+            if descriptor[0] == PRIMARY_DESCRIPTOR {
+                break descriptor;
+            }
+            sector += 1;
+        };
+
+        // Logical blocks are a power of two between 512 bytes and a sector
+        fs.logical_block_size = u16_le(&descriptor[LOGICAL_BLOCK_SIZE..]) as usize;
+        if !fs.logical_block_size.is_power_of_two()
+            || !(512..=SECTOR_SIZE).contains(&fs.logical_block_size)
+        {
+            return None;
+        }
+        let root = &descriptor[ROOT_RECORD..ROOT_RECORD + descriptor[ROOT_RECORD] as usize];
+        fs.root = fs.parse_record(root)?;
+        fs.root.name = String::new();
+
+        // The SP entry starts the system use area of the first record of the root
+        let dot = fs.read(fs.block_offset(fs.root.extent), fs.root.size.min(255))?;
+        let system_use = system_use(dot.get(..*dot.first()? as usize)?);
+        if system_use.len() >= 7 && &system_use[0..2] == b"SP" && system_use[4..6] == SP_CHECK {
+            fs.susp_skip = Some(system_use[6] as usize);
+        }
+        Some(fs)
+    }
+
+    pub fn root(&self) -> Record {
+        self.root.clone()
+    }
+
+    /// Returns the record at `path`, relative to the root of the volume
+    pub fn open(&self, path: &str) -> Option<Record> {
+        let mut record = self.root();
+        for name in path
+            .split('/')
+            .filter(|name| !name.is_empty() && *name != ".")
+        {
+            record = self
+                .read_dir(&record)?
+                .into_iter()
+                .find(|entry| entry.name == name)?;
+        }
+        Some(record)
+    }
+
+    /// Returns the entries of the directory `dir`, without the current and parent directories
+    pub fn read_dir(&self, dir: &Record) -> Option<Vec<Record>> {
+        if !dir.is_directory || dir.size > MAX_DIRECTORY_SIZE {
+            return None;
+        }
+        let data = self.read(self.block_offset(dir.extent), dir.size)?;
+
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let len = data[offset] as usize;
+            // Records do not cross logical blocks, the rest of the block is padding
+            if len == 0 {
+                offset = (offset / self.logical_block_size + 1) * self.logical_block_size;
+                continue;
+            }
+            if len < MIN_RECORD_LENGTH {
+                return None;
+            }
+            let raw = data.get(offset..offset + len)?;
+            offset += len;
+
+            // The current and parent directories are named with a single 0 or 1 byte
+            if raw[NAME_LENGTH] == 1 && raw[NAME] <= 1 {
+                continue;
+            }
+            if let Some(record) = self.parse_record(raw) {
+                records.push(record);
+            }
+        }
+        Some(records)
+    }
+
+    /// Reads up to `buf.len()` bytes of `record` starting at `offset`, returns the number read
+    pub fn read_file(&self, record: &Record, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        let len = buf.len().min(record.size.saturating_sub(offset));
+        if len == 0 {
+            return Some(0);
+        }
+        let data = self.read(self.block_offset(record.extent) + offset as u64, len)?;
+        buf[..len].copy_from_slice(&data);
+        Some(len)
+    }
+
+    fn parse_record(&self, raw: &[u8]) -> Option<Record> {
+        if raw.len() < MIN_RECORD_LENGTH {
+            return None;
+        }
+        let name_len = raw[NAME_LENGTH] as usize;
+        let mut record = Record {
+            name: iso_name(raw.get(NAME..NAME + name_len)?),
+            extent: u32_le(&raw[EXTENT..]) as usize,
+            size: u32_le(&raw[DATA_LENGTH..]) as usize,
+            is_directory: raw[FLAGS] & FLAG_DIRECTORY != 0,
+            mode: None,
+        };
+
+        if let Some(skip) = self.susp_skip {
+            let area = system_use(raw).get(skip..).unwrap_or(&[]);
+            self.parse_rock_ridge(area, &mut record);
+        }
+        Some(record)
+    }
+
+    /// Applies the Rock Ridge entries of the system use area `area` to `record`
+    fn parse_rock_ridge(&self, area: &[u8], record: &mut Record) {
+        let mut name: Option<Vec<u8>> = None;
+        let mut areas = alloc::vec![area.to_vec()];
+        let mut continuations = 0;
+
+        while let Some(area) = areas.pop() {
+            let mut offset = 0;
+            while offset + 4 <= area.len() {
+                let len = area[offset + 2] as usize;
+                let entry = match area.get(offset..offset + len) {
+                    Some(entry) if len >= 4 => entry,
+                    _ => break,
+                };
+                offset += len;
+
+                match &entry[0..2] {
+                    // Names too long for an entry are split over several ones
+                    b"NM" if len >= 5 && entry[4] & (NM_CURRENT | NM_PARENT) == 0 => {
+                        name.get_or_insert_with(Vec::new)
+                            .extend_from_slice(&entry[5..]);
+                    }
+                    b"PX" if len >= 12 => record.mode = Some(u32_le(&entry[4..])),
+                    // Continuation area, the entries go on in another block
+                    b"CE" if len >= 28 && continuations < MAX_CONTINUATIONS => {
+                        continuations += 1;
+                        let block = u32_le(&entry[4..]) as usize;
+                        let start = u32_le(&entry[12..]) as u64;
+                        let size = u32_le(&entry[20..]) as usize;
+                        if size > MAX_CONTINUATION_SIZE {
+                            continue;
+                        }
+                        if let Some(data) = self.read(self.block_offset(block) + start, size) {
+                            areas.push(data);
+                        }
+                    }
+                    b"ST" => break,
+                    _ => {}
+                }
+            }
+        }
+
+        if let Some(name) = name {
+            record.name = String::from_utf8_lossy(&name).into_owned();
+        }
+    }
+
+    /// Returns the byte offset of the logical block `block`
+    fn block_offset(&self, block: usize) -> u64 {
+        (block * self.logical_block_size) as u64
+    }
+
+    /// Reads `len` bytes starting at byte `offset` of the device, None if they are past its end
+    fn read(&self, offset: u64, len: usize) -> Option<Vec<u8>> {
+        let block_size = self.device.block_size() as u64;
+        let first = offset / block_size;
+        let last = (offset + len as u64 + block_size - 1) / block_size;
+        if last > self.device.block_count() {
+            return None;
+        }
+        let mut blocks = alloc::vec![0; ((last - first) * block_size) as usize];
+        if !blocks.is_empty() {
+            self.device
+                .read_blocks(first as usize, (last - first) as usize, &mut blocks)
+                .ok()?;
+        }
+
+        let start = (offset - first * block_size) as usize;
+        Some(blocks[start..start + len].to_vec())
+    }
+}
+
+/// Returns the system use area of the directory record `raw`, after its name and padding
+fn system_use(raw: &[u8]) -> &[u8] {
+    let name_len = raw.get(NAME_LENGTH).copied().unwrap_or(0) as usize;
+    // A padding byte keeps the system use area at an even offset
+    let start = NAME + name_len + (1 - name_len % 2);
+    raw.get(start..).unwrap_or(&[])
+}
+
+/// Decodes an ISO 9660 name, without its version and the dot of names without extension
+fn iso_name(raw: &[u8]) -> String {
+    let name = String::from_utf8_lossy(raw).to_lowercase();
+    let name = name.split(';').next().unwrap_or("");
+    name.trim_end_matches('.').into()
+}
+
+fn u16_le(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes(bytes[0..2].try_into().unwrap())
+}
+
+/// Reads the little endian half of a both-endian field
+fn u32_le(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[0..4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block_device::RamDisk;
+
+    /// Blocks of the RAM disks holding the test volumes, 21 sectors
+    const DISK_BLOCKS: usize = 21 * SECTOR_SIZE / 512;
+    // Sectors of the test volumes
+    const ROOT: u32 = 18;
+    const DIR: u32 = 19;
+    const DATA: u32 = 20;
+
+    fn record(extent: u32, size: u32, flags: u8, name: &[u8], system_use: &[u8]) -> Vec<u8> {
+        let mut record = alloc::vec![0; NAME + name.len() + (1 - name.len() % 2)];
+        record[EXTENT..EXTENT + 4].copy_from_slice(&extent.to_le_bytes());
+        record[DATA_LENGTH..DATA_LENGTH + 4].copy_from_slice(&size.to_le_bytes());
+        record[FLAGS] = flags;
+        record[NAME_LENGTH] = name.len() as u8;
+        record[NAME..NAME + name.len()].copy_from_slice(name);
+        record.extend_from_slice(system_use);
+        if record.len() % 2 != 0 {
+            record.push(0);
+        }
+        record[0] = record.len() as u8;
+        record
+    }
+
+    /// Writes the sector `sector` of `disk`, starting with `records`
+    fn write_sector(disk: &RamDisk, sector: u32, records: &[Vec<u8>]) {
+        let mut data = alloc::vec![0; SECTOR_SIZE];
+        let mut offset = 0;
+        for record in records {
+            data[offset..offset + record.len()].copy_from_slice(record);
+            offset += record.len();
+        }
+        let blocks = SECTOR_SIZE / 512;
+        disk.write_blocks(sector as usize * blocks, blocks, &data)
+            .unwrap();
+    }
+
+    /// Returns a volume whose root holds `file` and `dir`, `dir` holding `inner`
+    /// `dot` is the system use area of the first record of the root
+    fn volume(dot: &[u8], file: Vec<u8>, dir: Vec<u8>, inner: Vec<u8>) -> RamDisk {
+        let disk = RamDisk::new(DISK_BLOCKS);
+        let mut descriptor = alloc::vec![0; SECTOR_SIZE];
+        descriptor[0] = PRIMARY_DESCRIPTOR;
+        descriptor[1..6].copy_from_slice(IDENTIFIER);
+        descriptor[LOGICAL_BLOCK_SIZE..LOGICAL_BLOCK_SIZE + 2]
+            .copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+        let root = record(ROOT, SECTOR_SIZE as u32, FLAG_DIRECTORY, &[0], &[]);
+        descriptor[ROOT_RECORD..ROOT_RECORD + root.len()].copy_from_slice(&root);
+        write_sector(&disk, FIRST_DESCRIPTOR as u32, &[descriptor]);
+
+        let mut terminator = alloc::vec![TERMINATOR];
+        terminator.extend_from_slice(IDENTIFIER);
+        write_sector(&disk, FIRST_DESCRIPTOR as u32 + 1, &[terminator]);
+
+        let size = SECTOR_SIZE as u32;
+        let dot = record(ROOT, size, FLAG_DIRECTORY, &[0], dot);
+        let dotdot = record(ROOT, size, FLAG_DIRECTORY, &[1], &[]);
+        write_sector(&disk, ROOT, &[dot, dotdot.clone(), file, dir]);
+        let dot = record(DIR, size, FLAG_DIRECTORY, &[0], &[]);
+        write_sector(&disk, DIR, &[dot, dotdot, inner]);
+
+        let content: Vec<u8> = (0..100).collect();
+        write_sector(&disk, DATA, &[content]);
+        disk
+    }
+
+    #[test_case]
+    fn not_a_volume() {
+        let disk = RamDisk::new(DISK_BLOCKS);
+        assert!(Iso9660::new(&disk).is_none());
+    }
+
+    #[test_case]
+    fn iso_names() {
+        let disk = volume(
+            &[],
+            record(DATA, 100, 0, b"README.TXT;1", &[]),
+            record(DIR, SECTOR_SIZE as u32, FLAG_DIRECTORY, b"DIR", &[]),
+            record(DATA, 10, 0, b"INNER.;1", &[]),
+        );
+        let fs = Iso9660::new(&disk).unwrap();
+
+        let names: Vec<String> = fs
+            .read_dir(&fs.root())
+            .unwrap()
+            .into_iter()
+            .map(|record| record.name)
+            .collect();
+        assert_eq!(names, ["readme.txt", "dir"]);
+        let inner = fs.open("/dir/./inner").unwrap();
+        assert_eq!(inner.size(), 10);
+        assert!(!inner.is_directory());
+        assert!(fs.open("dir").unwrap().is_directory());
+        assert!(fs.open("missing").is_none());
+        assert!(fs.open("readme.txt/inner").is_none());
+    }
+
+    #[test_case]
+    fn read_file() {
+        let disk = volume(
+            &[],
+            record(DATA, 100, 0, b"README.TXT;1", &[]),
+            record(DIR, SECTOR_SIZE as u32, FLAG_DIRECTORY, b"DIR", &[]),
+            record(DATA, 10, 0, b"INNER.;1", &[]),
+        );
+        let fs = Iso9660::new(&disk).unwrap();
+        let file = fs.open("readme.txt").unwrap();
+
+        let mut buf = [0; 64];
+        assert_eq!(fs.read_file(&file, 90, &mut buf), Some(10));
+        assert_eq!(buf[..10], [90, 91, 92, 93, 94, 95, 96, 97, 98, 99]);
+        assert_eq!(fs.read_file(&file, 100, &mut buf), Some(0));
+    }
+
+    #[test_case]
+    fn rock_ridge() {
+        let sp = [b'S', b'P', 7, 1, 0xBE, 0xEF, 0];
+        let mut px = alloc::vec![b'P', b'X', 36, 1];
+        px.extend_from_slice(&0o100_750u32.to_le_bytes());
+        px.resize(36, 0);
+        let mut system_use = alloc::vec![b'N', b'M', 5 + 11, 1, 0];
+        system_use.extend_from_slice(b"A long name");
+        system_use.extend_from_slice(&[b'N', b'M', 5 + 6, 1, 0]);
+        system_use.extend_from_slice(b".lisp!");
+        system_use.extend_from_slice(&px);
+
+        let disk = volume(
+            &sp,
+            record(DATA, 100, 0, b"ALONGNAM.LIS;1", &system_use),
+            record(DIR, SECTOR_SIZE as u32, FLAG_DIRECTORY, b"DIR", &[]),
+            record(DATA, 10, 0, b"INNER.;1", &[]),
+        );
+        let fs = Iso9660::new(&disk).unwrap();
+
+        let file = fs.open("A long name.lisp!").unwrap();
+        assert_eq!(file.size(), 100);
+        assert_eq!(file.mode(), 0o750);
+        // Records without Rock Ridge entries keep their ISO 9660 name
+        assert_eq!(fs.open("dir").unwrap().mode(), 0o555);
+        assert!(fs.open("alongnam.lis").is_none());
+    }
+
+    #[test_case]
+    fn corrupted_volume() {
+        let volume = || {
+            volume(
+                &[],
+                record(DATA, 100, 0, b"README.TXT;1", &[]),
+                record(DIR, SECTOR_SIZE as u32, FLAG_DIRECTORY, b"DIR", &[]),
+                record(DATA, 10, 0, b"INNER.;1", &[]),
+            )
+        };
+        let mut sector = alloc::vec![0; SECTOR_SIZE];
+
+        // A logical block size of 0 would divide by 0 on padding
+        let disk = volume();
+        let blocks = SECTOR_SIZE / 512;
+        let descriptor = FIRST_DESCRIPTOR * blocks;
+        disk.read_blocks(descriptor, blocks, &mut sector).unwrap();
+        sector[LOGICAL_BLOCK_SIZE..LOGICAL_BLOCK_SIZE + 2].copy_from_slice(&[0, 0]);
+        disk.write_blocks(descriptor, blocks, &sector).unwrap();
+        assert!(Iso9660::new(&disk).is_none());
+
+        // A record too short to hold a name makes the directory unreadable
+        let disk = volume();
+        let root = ROOT as usize * blocks;
+        disk.read_blocks(root, blocks, &mut sector).unwrap();
+        let file = sector[0] as usize + sector[sector[0] as usize] as usize;
+        sector[file] = NAME_LENGTH as u8;
+        disk.write_blocks(root, blocks, &sector).unwrap();
+        let fs = Iso9660::new(&disk).unwrap();
+        assert!(fs.read_dir(&fs.root()).is_none());
+
+        // Directories past the end of the disk or larger than any sane one are not read
+        for &(extent, size) in &[(DISK_BLOCKS as u32, SECTOR_SIZE as u32), (DIR, u32::MAX)] {
+            let disk = self::volume(
+                &[],
+                record(DATA, 100, 0, b"README.TXT;1", &[]),
+                record(extent, size, FLAG_DIRECTORY, b"DIR", &[]),
+                record(DATA, 10, 0, b"INNER.;1", &[]),
+            );
+            let fs = Iso9660::new(&disk).unwrap();
+            let dir = fs.open("dir").unwrap();
+            assert!(fs.read_dir(&dir).is_none());
+        }
+    }
+}
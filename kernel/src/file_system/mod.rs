@@ -1,53 +1,164 @@
 //! Implementation of the file system syscalls
+//! The root file system is a USTAR archive, the CD-ROM is mounted on `CDROM_MOUNT_POINT`
 
+mod iso9660;
+mod root;
 mod ustar;
-use crate::arch::ata;
-pub use ustar::ls;
-use ustar::{Entry, ReadDir, BLOCK_SIZE};
+/// The kernel reads headers through `ustar::Header`, only its tests check the offsets
+#[allow(dead_code)]
+mod ustar_layout;
+use crate::arch::ata::{self, AtapiDrive};
+use crate::block_device::BlockDevice;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+pub use iso9660::{Iso9660, Record};
+use root::root_fs;
+pub use root::{sync, RootDevice, DATA_PARTITION_GUID, DATA_PARTITION_ID, INITRD_PARTITION_ID};
+pub use ustar::Ustar;
+use ustar::{normalize, Entry, BLOCK_SIZE};
 
 const READ_PERM: u64 = 0b100;
 const WRITE_PERM: u64 = 0b010;
 const EXEC_PERM: u64 = 0b001;
+/// Shift of the permissions of the owner in a POSIX mode, the only ones the kernel checks
+const OWNER_SHIFT: u64 = 6;
+
+/// Directory the file system of the CD-ROM is mounted on
+pub const CDROM_MOUNT_POINT: &str = "/cdrom";
+
+/// Returns the files of the directory `dir_name`
+pub fn read_dir(dir_name: &str) -> Option<Vec<File>> {
+    if let Some(path) = cdrom_path(dir_name) {
+        let fs = cdrom()?;
+        let records = fs.read_dir(&fs.open(&path)?)?;
+        let files = records
+            .into_iter()
+            .map(|record| {
+                let path = alloc::format!("{}{}/{}", CDROM_MOUNT_POINT, path, record.name());
+                File::on_cdrom(fs.clone(), record, path)
+            })
+            .collect();
+        return Some(files);
+    }
+
+    let entries = root_fs().read_dir(dir_name)?;
+    Some(entries.into_iter().map(File::new).collect())
+}
+
+/// Creates the directory `path`, None if its parent does not exist or it already does
+pub fn mkdir(path: &str) -> Option<File> {
+    if cdrom_path(path).is_some() {
+        return None;
+    }
+    root_fs().create_dir(path)
+}
 
-pub fn read_dir(dir_name: &str) -> Option<ReadDir> {
-    if dir_name == "/" {
-        Some(ReadDir::root())
+/// Removes the directory `path`, None if it is not an empty directory
+pub fn rmdir(path: &str) -> Option<()> {
+    if cdrom_path(path).is_some() {
+        return None;
+    }
+    root_fs().remove_dir(path)
+}
+
+/// Returns the file system of the first CD-ROM holding an ISO 9660 volume
+/// The volume is read again every time, the medium might have changed
+pub fn cdrom() -> Option<Iso9660<AtapiDrive>> {
+    ata::atapi_drives().find_map(|(drive, _)| Iso9660::new(drive))
+}
+
+/// Returns the normalized `path` relative to the CD-ROM, None if it is not below its mount point
+fn cdrom_path(path: &str) -> Option<String> {
+    let path = alloc::format!("/{}", normalize(path));
+    let path = path.strip_prefix(CDROM_MOUNT_POINT)?;
+    if path.is_empty() || path.starts_with('/') {
+        Some(path.into())
     } else {
         None
     }
 }
 
-pub struct File {
+pub struct File<D: BlockDevice = RootDevice> {
+    node: Node<D>,
     index: usize,
-    entry: Entry,
+}
+
+/// File system a file is stored on
+enum Node<D> {
+    Ustar {
+        fs: Ustar<D>,
+        /// Boxed as it takes a whole block
+        entry: Box<Entry>,
+    },
+    /// Read-only file of the CD-ROM, along with its absolute path
+    Cdrom {
+        fs: Iso9660<AtapiDrive>,
+        record: Record,
+        path: String,
+    },
 }
 
 impl File {
     pub fn new(entry: Entry) -> File {
-        File { index: 0, entry }
+        File::with_fs(root_fs(), entry)
     }
+
+    /// Creates a file, the CD-ROM is read-only so files cannot be created there
     pub fn create(filename: &str) -> Option<File> {
-        Some(File::new(ustar::create_file(filename)))
+        if cdrom_path(filename).is_some() {
+            return None;
+        }
+        root_fs().create_file(filename)
     }
 
     pub fn open(filename: &str) -> Option<File> {
-        ustar::open(filename).map(File::new)
+        if let Some(path) = cdrom_path(filename) {
+            let fs = cdrom()?;
+            let record = fs.open(&path)?;
+            let path = alloc::format!("{}{}", CDROM_MOUNT_POINT, path);
+            return Some(File::on_cdrom(fs, record, path));
+        }
+        root_fs().open(filename)
     }
 
-    pub fn read(&mut self, buf: &mut [u8]) -> Option<usize> {
-        self.read_generic(buf, ata::read_sectors)
+    fn on_cdrom(fs: Iso9660<AtapiDrive>, record: Record, path: String) -> File {
+        File {
+            node: Node::Cdrom { fs, record, path },
+            index: 0,
+        }
     }
+}
 
-    fn read_generic<T>(&mut self, buf: &mut [u8], sector_reader: T) -> Option<usize>
-    where
-        T: Fn(usize, u8, &mut [u8]),
-    {
-        let len = buf.len().min(self.entry.size - self.index);
-        let lba = self.entry.get_sector() + 1 + get_sector(self.index);
+impl<D: BlockDevice + Clone> File<D> {
+    pub fn with_fs(fs: Ustar<D>, entry: Entry) -> File<D> {
+        File {
+            node: Node::Ustar {
+                fs,
+                entry: Box::new(entry),
+            },
+            index: 0,
+        }
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let (fs, entry) = match &mut self.node {
+            Node::Ustar { fs, entry } => (fs, entry),
+            Node::Cdrom { fs, record, .. } => {
+                let len = fs.read_file(record, self.index, buf)?;
+                self.index += len;
+                return Some(len);
+            }
+        };
+        fs.refresh(entry)?;
+        let len = buf.len().min(entry.get_size() - self.index);
+        let lba = entry.get_sector() + 1 + get_sector(self.index);
         let sectors = get_sector(self.index + len.saturating_sub(1)) - get_sector(self.index) + 1;
 
         let mut sector_buf = alloc::vec![0; BLOCK_SIZE * sectors];
-        sector_reader(lba, sectors as u8, &mut sector_buf);
+        fs.device()
+            .read_blocks(lba, sectors, &mut sector_buf)
+            .ok()?;
         let block_offset = self.index % BLOCK_SIZE;
         buf[..len].copy_from_slice(&sector_buf[block_offset..(len + block_offset)]);
 
@@ -55,51 +166,72 @@ impl File {
         Some(len)
     }
 
+    /// Writes `buf` at the current position, None if the file is read-only
+    /// A file growing past its last block is moved after the last entry if others follow it
     pub fn write(&mut self, buf: &[u8]) -> Option<usize> {
+        let (fs, entry) = match &mut self.node {
+            Node::Ustar { fs, entry } => (fs, entry),
+            Node::Cdrom { .. } => return None,
+        };
+        fs.refresh(entry)?;
+        let size = entry.get_size().max(self.index + buf.len());
+        if entry.get_sector() + 1 + (size + BLOCK_SIZE - 1) / BLOCK_SIZE > entry.end_sector() {
+            fs.grow(entry, size).ok()?;
+        }
         let end = get_sector(self.index + buf.len().saturating_sub(1));
         let start = get_sector(self.index);
-        let sectors = start - end + 1;
-        let lba = self.entry.get_sector() + 1 + get_sector(self.index);
+        let sectors = end - start + 1;
+        let lba = entry.get_sector() + 1 + get_sector(self.index);
         let block_offset = self.index % BLOCK_SIZE;
+        let device = fs.device();
 
         let mut sector_buf = alloc::vec![0; sectors * BLOCK_SIZE ];
-        ata::read_sectors(lba, sectors as u8, &mut sector_buf);
+        device.read_blocks(lba, sectors, &mut sector_buf).ok()?;
 
         sector_buf[block_offset..block_offset + buf.len()].copy_from_slice(buf);
-        ata::write_sectors(lba, sectors as u8, &sector_buf);
+        device.write_blocks(lba, sectors, &sector_buf).ok()?;
 
         self.index += buf.len();
-        self.entry.size += buf.len();
-        ata::write_sectors(
-            self.entry.get_sector(),
-            1,
-            ustar::any_as_u8_slice(&self.entry),
-        );
+        let end_sector = entry.end_sector();
+        entry.set_size(entry.get_size().max(self.index));
+        fs.save(entry).ok()?;
+        if entry.end_sector() > end_sector {
+            fs.end_archive(entry).ok()?;
+        }
         Some(buf.len())
     }
 
     pub fn get_size(&self) -> usize {
-        self.entry.size
+        match &self.node {
+            Node::Ustar { entry, .. } => entry.get_size(),
+            Node::Cdrom { record, .. } => record.size(),
+        }
     }
 
     pub fn get_path(&self) -> &str {
-        self.entry.get_name()
+        match &self.node {
+            Node::Ustar { entry, .. } => entry.get_name(),
+            Node::Cdrom { path, .. } => path,
+        }
     }
 
     pub fn is_directory(&self) -> bool {
-        self.entry.is_directory()
+        match &self.node {
+            Node::Ustar { entry, .. } => entry.is_directory(),
+            Node::Cdrom { record, .. } => record.is_directory(),
+        }
     }
 
     pub fn can_read(&self) -> bool {
-        self.entry.get_permissions() & READ_PERM != 0
+        self.get_permissions() & READ_PERM != 0
     }
 
     pub fn can_write(&self) -> bool {
-        self.entry.get_permissions() & WRITE_PERM != 0
+        self.get_permissions() & WRITE_PERM != 0
     }
 
     pub fn can_execute(&self) -> bool {
-        self.entry.get_permissions() & EXEC_PERM != 0
+        self.get_permissions() & EXEC_PERM != 0
     }
 
     pub fn set_readable(&mut self, is_readable: bool) {
@@ -114,17 +246,34 @@ impl File {
         self.set_permission(is_executable, EXEC_PERM);
     }
 
+    /// Returns the permissions of the owner, files of the CD-ROM are never writeable
+    fn get_permissions(&self) -> u64 {
+        match &self.node {
+            Node::Ustar { entry, .. } => (entry.get_permissions() >> OWNER_SHIFT) & 0o7,
+            Node::Cdrom { record, .. } => (record.mode() as u64 >> OWNER_SHIFT) & !WRITE_PERM & 0o7,
+        }
+    }
+
+    /// Sets or clears `flag`, the permissions of read-only files do not change
     fn set_permission(&mut self, cond: bool, flag: u64) {
-        let perms = self.entry.get_permissions();
+        let (fs, entry) = match &mut self.node {
+            Node::Ustar { fs, entry } => (fs, entry),
+            Node::Cdrom { .. } => return,
+        };
+        if fs.refresh(entry).is_none() {
+            return;
+        }
+        let perms = entry.get_permissions();
         if cond {
-            self.entry.set_permissions(perms | (flag));
+            entry.set_permissions(perms | flag << OWNER_SHIFT);
         } else {
-            self.entry.set_permissions(perms & !(flag));
+            entry.set_permissions(perms & !(flag << OWNER_SHIFT));
         }
+        fs.save(entry).expect("Could not save USTAR entry");
     }
 }
 
-impl Drop for File {
+impl<D: BlockDevice> Drop for File<D> {
     fn drop(&mut self) {
         // Nothing needed for now
     }
@@ -137,146 +286,378 @@ fn get_sector(addr: usize) -> usize {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::block_device::RamDisk;
+    use alloc::string::String;
     use alloc::vec::Vec;
-    fn create_file(size: usize) -> File {
-        let mut entry = Entry::default();
-        entry.size = size;
-        File::new(entry)
-    }
-
-    fn sector_reader(sectors: &[u8], mut lba: usize, nb_sectors: u8, buf: &mut [u8]) {
-        //The first sector is normally reserved for the metadata, which we ignore here
-        lba -= 1;
-        let start_addr = lba * BLOCK_SIZE;
 
-        for i in 0..(nb_sectors as usize * BLOCK_SIZE) {
-            buf[i] = sectors[start_addr + i];
-        }
+    /// Stores `content` in a file at the start of `disk`
+    fn create_file<'a>(disk: &'a RamDisk, content: &[u8]) -> File<&'a RamDisk> {
+        let fs = Ustar::new(disk, 0);
+        let mut entry = Entry::new("file", 0).unwrap();
+        entry.set_size(content.len());
+        fs.save(&entry).unwrap();
+
+        let blocks = (content.len() + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        let mut data = alloc::vec![0; blocks * BLOCK_SIZE];
+        data[..content.len()].copy_from_slice(content);
+        disk.write_blocks(1, blocks, &data).unwrap();
+        File::with_fs(fs, entry)
     }
 
     #[test_case]
     fn read_one_block() {
-        let mut file = create_file(512);
+        let disk = RamDisk::new(8);
         let sectors: Vec<u8> = (0..512).map(|val| val as u8).collect();
-        let sector_reader =
-            |lba, nb_sectors, buf: &mut [u8]| sector_reader(&sectors, lba, nb_sectors, buf);
+        let mut file = create_file(&disk, &sectors);
 
         let mut buf = [0; 512];
-        assert_eq!(file.read_generic(&mut buf, sector_reader), Some(512));
+        assert_eq!(file.read(&mut buf), Some(512));
         assert_eq!(buf, sectors.as_slice());
     }
 
     #[test_case]
     fn read_two_blocks() {
-        let mut file = create_file(1024);
+        let disk = RamDisk::new(8);
         let sectors: Vec<u8> = (0..1024).map(|val| val as u8).collect();
-        let sector_reader =
-            |lba, nb_sectors, buf: &mut [u8]| sector_reader(&sectors, lba, nb_sectors, buf);
+        let mut file = create_file(&disk, &sectors);
 
         let mut buf = [0; 1024];
-        assert_eq!(file.read_generic(&mut buf, sector_reader), Some(1024));
+        assert_eq!(file.read(&mut buf), Some(1024));
         assert_eq!(buf, sectors.as_slice());
     }
 
     #[test_case]
     fn two_reads() {
-        let mut file = create_file(512);
+        let disk = RamDisk::new(8);
         let sectors: Vec<u8> = (0..512).map(|val| val as u8).collect();
-        let sector_reader =
-            |lba, nb_sectors, buf: &mut [u8]| sector_reader(&sectors, lba, nb_sectors, buf);
+        let mut file = create_file(&disk, &sectors);
 
         let mut buf = [0; 512];
-        assert_eq!(file.read_generic(&mut buf[..100], sector_reader), Some(100));
-        assert_eq!(
-            file.read_generic(&mut buf[100..], sector_reader),
-            Some(512 - 100)
-        );
+        assert_eq!(file.read(&mut buf[..100]), Some(100));
+        assert_eq!(file.read(&mut buf[100..]), Some(512 - 100));
         assert_eq!(buf, sectors.as_slice());
     }
 
     #[test_case]
     fn two_reads_two_blocks() {
-        let mut file = create_file(1024);
+        let disk = RamDisk::new(8);
         let sectors: Vec<u8> = (0..1024).map(|val| val as u8).collect();
-        let sector_reader =
-            |lba, nb_sectors, buf: &mut [u8]| sector_reader(&sectors, lba, nb_sectors, buf);
+        let mut file = create_file(&disk, &sectors);
 
         let mut buf = [0; 1024];
-        assert_eq!(file.read_generic(&mut buf[..345], sector_reader), Some(345));
-        assert_eq!(
-            file.read_generic(&mut buf[345..], sector_reader),
-            Some(1024 - 345)
-        );
+        assert_eq!(file.read(&mut buf[..345]), Some(345));
+        assert_eq!(file.read(&mut buf[345..]), Some(1024 - 345));
         assert_eq!(buf, sectors.as_slice());
     }
 
     #[test_case]
     fn short_read_across_section() {
-        let mut file = create_file(1024);
+        let disk = RamDisk::new(8);
         let sectors: Vec<u8> = (0..1024).map(|val| val as u8).collect();
-        let sector_reader =
-            |lba, nb_sectors, buf: &mut [u8]| sector_reader(&sectors, lba, nb_sectors, buf);
+        let mut file = create_file(&disk, &sectors);
 
         let mut buf = [0; 1024];
-        assert_eq!(file.read_generic(&mut buf[..500], sector_reader), Some(500));
-        assert_eq!(
-            file.read_generic(&mut buf[500..600], sector_reader),
-            Some(600 - 500)
-        );
-        assert_eq!(
-            file.read_generic(&mut buf[600..], sector_reader),
-            Some(1024 - 600)
-        );
+        assert_eq!(file.read(&mut buf[..500]), Some(500));
+        assert_eq!(file.read(&mut buf[500..600]), Some(600 - 500));
+        assert_eq!(file.read(&mut buf[600..]), Some(1024 - 600));
         assert_eq!(buf, sectors.as_slice());
     }
 
     #[test_case]
     fn read_too_much() {
-        let mut file = create_file(512);
+        let disk = RamDisk::new(8);
         let sectors: Vec<u8> = (0..512).map(|val| val as u8).collect();
-        let sector_reader =
-            |lba, nb_sectors, buf: &mut [u8]| sector_reader(&sectors, lba, nb_sectors, buf);
+        let mut file = create_file(&disk, &sectors);
 
         let mut buf = [0; 600];
-        assert_eq!(file.read_generic(&mut buf, sector_reader), Some(512));
+        assert_eq!(file.read(&mut buf), Some(512));
         assert_eq!(buf[..512], sectors);
     }
 
     #[test_case]
     fn read_zero() {
-        let mut file = create_file(512);
+        let disk = RamDisk::new(8);
         let sectors: Vec<u8> = (0..512).map(|val| val as u8).collect();
-        let sector_reader =
-            |lba, nb_sectors, buf: &mut [u8]| sector_reader(&sectors, lba, nb_sectors, buf);
+        let mut file = create_file(&disk, &sectors);
 
         let mut buf = [0; 0];
-        assert_eq!(file.read_generic(&mut buf, sector_reader), Some(0));
+        assert_eq!(file.read(&mut buf), Some(0));
     }
 
     #[test_case]
     fn read_tricky() {
-        let mut file = create_file(2000);
-        let sectors: Vec<u8> = (0..2048).map(|val| val as u8).collect();
-        let sector_reader =
-            |lba, nb_sectors, buf: &mut [u8]| sector_reader(&sectors, lba, nb_sectors, buf);
+        let disk = RamDisk::new(8);
+        let sectors: Vec<u8> = (0..2000).map(|val| val as u8).collect();
+        let mut file = create_file(&disk, &sectors);
 
         let mut buf = [0; 2000];
+        assert_eq!(file.read(&mut buf[0..300]), Some(300 - 0));
+        assert_eq!(file.read(&mut buf[300..613]), Some(613 - 300));
+        assert_eq!(file.read(&mut buf[613..1700]), Some(1700 - 613));
+        assert_eq!(file.read(&mut buf[1700..2000]), Some(2000 - 1700));
+        assert_eq!(buf, sectors[..2000]);
+    }
+
+    #[test_case]
+    fn write_across_blocks() {
+        let disk = RamDisk::new(8);
+        let fs = Ustar::new(&disk, 0);
+        let content: Vec<u8> = (0..700).map(|val| val as u8).collect();
+
+        let mut file = fs.create_file("file").unwrap();
+        assert_eq!(file.write(&content[..300]), Some(300));
+        assert_eq!(file.write(&content[300..]), Some(400));
+
+        let mut file = fs.open("file").unwrap();
+        assert_eq!(file.get_size(), 700);
+        let mut buf = [0; 700];
+        assert_eq!(file.read(&mut buf), Some(700));
+        assert_eq!(buf, content.as_slice());
+    }
+
+    #[test_case]
+    fn files_follow_each_other() {
+        let disk = RamDisk::new(8);
+        let fs = Ustar::new(&disk, 0);
+
+        let mut first = fs.create_file("first").unwrap();
+        assert_eq!(first.write(&[1; 600]), Some(600));
+        let mut second = fs.create_file("second").unwrap();
+        assert_eq!(second.write(&[2; 10]), Some(10));
+
+        let names: Vec<String> = fs.entries().map(|entry| entry.get_name().into()).collect();
+        assert_eq!(names, ["first", "second"]);
+        let mut buf = [0; 600];
+        let mut first = fs.open("first").unwrap();
+        assert_eq!(first.read(&mut buf), Some(600));
+        assert!(buf.iter().all(|&byte| byte == 1));
+    }
+
+    #[test_case]
+    fn normalize_paths() {
+        assert_eq!(normalize("/dir/./sub/../file/"), "dir/file");
+        assert_eq!(normalize("//dir//file"), "dir/file");
+        assert_eq!(normalize("/.."), "");
+    }
+
+    #[test_case]
+    fn nested_directories() {
+        let disk = RamDisk::new(8);
+        let fs = Ustar::new(&disk, 0);
+        assert!(fs.create_dir("dir").is_some());
+        assert!(fs.create_dir("/dir/sub/").is_some());
+        assert!(fs.create_dir("dir").is_none());
+        assert!(fs.create_file("missing/file").is_none());
+        let mut file = fs.create_file("dir/sub/file").unwrap();
+        assert_eq!(file.write(b"nested"), Some(6));
+
+        let mut file = fs.open("/dir/./sub/../sub/file").unwrap();
+        let mut buf = [0; 6];
+        assert_eq!(file.read(&mut buf), Some(6));
+        assert_eq!(&buf, b"nested");
+        assert!(fs.open("dir/sub/..").unwrap().is_directory());
+
+        let names = |dir| -> Option<Vec<String>> {
+            let entries = fs.read_dir(dir)?;
+            Some(
+                entries
+                    .iter()
+                    .map(|entry| entry.get_name().into())
+                    .collect(),
+            )
+        };
+        assert_eq!(names("/").unwrap(), ["dir"]);
+        assert_eq!(names("dir").unwrap(), ["dir/sub"]);
+        assert_eq!(names("dir/sub").unwrap(), ["dir/sub/file"]);
+        assert!(names("dir/sub/file").is_none());
+    }
+
+    #[test_case]
+    fn implicit_directories() {
+        let disk = RamDisk::new(8);
+        let fs = Ustar::new(&disk, 0);
+        // Archives made by tar may have no entry for directories holding files
+        let mut entry = Entry::new("dir/sub/file", 0).unwrap();
+        entry.set_size(3);
+        fs.save(&entry).unwrap();
+        fs.end_archive(&entry).unwrap();
+
+        assert!(fs.open("dir").unwrap().is_directory());
+        assert!(fs.open("dir/sub").unwrap().is_directory());
+        assert!(fs.create_file("dir/sub").is_none());
+        assert!(fs.create_dir("dir").is_none());
+        assert!(fs.create_file("dir/other").is_some());
+        assert!(fs.create_file("dir/sub/file").is_none());
+        assert!(fs.remove_dir("dir/sub").is_none());
+
+        let names = |dir| -> Vec<String> {
+            let entries = fs.read_dir(dir).unwrap();
+            entries
+                .iter()
+                .map(|entry| entry.get_name().into())
+                .collect()
+        };
+        assert_eq!(names("/"), ["dir"]);
+        assert_eq!(names("dir"), ["dir/sub", "dir/other"]);
+        assert_eq!(names("dir/sub"), ["dir/sub/file"]);
+        assert!(fs.read_dir("dir/sub/file").is_none());
+        assert!(fs.read_dir("di").is_none());
+    }
+
+    #[test_case]
+    fn files_stay_open_when_entries_move() {
+        let disk = RamDisk::new(16);
+        let fs = Ustar::new(&disk, 0);
+        fs.create_dir("dir").unwrap();
+        let mut file = fs.create_file("file").unwrap();
+        assert_eq!(file.write(&[4; 10]), Some(10));
+        let mut reader = fs.open("file").unwrap();
+
+        // The file moves back over the removed directory
+        assert!(fs.remove_dir("dir").is_some());
+        let mut buf = [0; 10];
+        assert_eq!(reader.read(&mut buf), Some(10));
+        assert_eq!(buf, [4; 10]);
+        assert_eq!(file.write(&[5; 10]), Some(10));
+        let mut buf = [0; 20];
+        assert_eq!(fs.open("file").unwrap().read(&mut buf), Some(20));
+        assert_eq!(buf[10..], [5; 10]);
+
+        // It moves back again when the file before it grows and moves after it
+        let mut other = fs.create_file("other").unwrap();
+        assert_eq!(other.write(&[6; 10]), Some(10));
+        let mut reader = fs.open("other").unwrap();
+        assert_eq!(file.write(&[5; 600]), Some(600));
+        let mut buf = [0; 10];
+        assert_eq!(reader.read(&mut buf), Some(10));
+        assert_eq!(buf, [6; 10]);
+        let names: Vec<String> = fs.entries().map(|entry| entry.get_name().into()).collect();
+        assert_eq!(names, ["other", "file"]);
+    }
+
+    #[test_case]
+    fn remove_directories() {
+        let disk = RamDisk::new(8);
+        let fs = Ustar::new(&disk, 0);
+        fs.create_dir("empty").unwrap();
+        fs.create_dir("full").unwrap();
+        fs.create_file("full/file").unwrap();
+        let mut file = fs.create_file("file").unwrap();
+        assert_eq!(file.write(&[3; 10]), Some(10));
+
+        assert!(fs.remove_dir("full").is_none());
+        assert!(fs.remove_dir("file").is_none());
+        assert!(fs.remove_dir("/").is_none());
+        assert!(fs.remove_dir("empty").is_some());
+
+        // The following entries move back
+        let names: Vec<String> = fs.entries().map(|entry| entry.get_name().into()).collect();
+        assert_eq!(names, ["full", "full/file", "file"]);
+        let mut buf = [0; 10];
+        assert_eq!(fs.open("file").unwrap().read(&mut buf), Some(10));
+        assert_eq!(buf, [3; 10]);
+    }
+
+    #[test_case]
+    fn grow_file_before_others() {
+        let disk = RamDisk::new(16);
+        let fs = Ustar::new(&disk, 0);
+        let mut first = fs.create_file("first").unwrap();
+        assert_eq!(first.write(&[1; 10]), Some(10));
+        let mut second = fs.create_file("second").unwrap();
+        assert_eq!(second.write(&[2; 10]), Some(10));
+
+        // Growing within its last block leaves the file in place
+        assert_eq!(first.write(&[1; 502]), Some(502));
+        // Past it, the file moves after the second one instead of overwriting it
+        assert_eq!(first.write(&[1; 600]), Some(600));
+        let names: Vec<String> = fs.entries().map(|entry| entry.get_name().into()).collect();
+        assert_eq!(names, ["second", "first"]);
+
+        let mut buf = [0; 1112];
+        assert_eq!(fs.open("first").unwrap().read(&mut buf), Some(1112));
+        assert!(buf.iter().all(|&byte| byte == 1));
+        assert_eq!(fs.open("second").unwrap().read(&mut buf), Some(10));
+        assert_eq!(buf[..10], [2; 10]);
+
+        // Files that do not fit anymore are left as they are
+        let mut second = fs.open("second").unwrap();
+        assert_eq!(second.write(&[2; 5000]), None);
+        assert_eq!(fs.open("second").unwrap().get_size(), 10);
+    }
+
+    #[test_case]
+    fn headers_are_posix() {
+        let disk = RamDisk::new(8);
+        disk.write_blocks(0, 8, &[0xFF; 8 * BLOCK_SIZE]).unwrap();
+        let fs = Ustar::new(&disk, 0);
+        let mut file = fs.create_file("file").unwrap();
+        assert_eq!(file.write(&[1; 700]), Some(700));
+
+        let mut header = [0; BLOCK_SIZE];
+        disk.read_blocks(0, 1, &mut header).unwrap();
+        assert_eq!(header[100..108], *b"0000644\0");
+        assert_eq!(header[124..136], *b"00000001274\0");
+        assert_eq!(header[156], b'0');
+        assert_eq!(header[257..263], *b"ustar\0");
+        assert_eq!(header[263..265], *b"00");
+        let checksum = header[..148].iter().chain(&header[156..]);
+        let checksum = checksum.map(|&byte| byte as u32).sum::<u32>() + 8 * b' ' as u32;
         assert_eq!(
-            file.read_generic(&mut buf[0..300], sector_reader),
-            Some(300 - 0)
-        );
-        assert_eq!(
-            file.read_generic(&mut buf[300..613], sector_reader),
-            Some(613 - 300)
-        );
-        assert_eq!(
-            file.read_generic(&mut buf[613..1700], sector_reader),
-            Some(1700 - 613)
-        );
-        assert_eq!(
-            file.read_generic(&mut buf[1700..2000], sector_reader),
-            Some(2000 - 1700)
+            header[148..156],
+            *alloc::format!("{:06o}\0 ", checksum).as_bytes()
         );
-        assert_eq!(buf, sectors[..2000]);
+
+        // The archive ends with two empty blocks after the data
+        let mut end = [0xFF; 2 * BLOCK_SIZE];
+        disk.read_blocks(3, 2, &mut end).unwrap();
+        assert!(end.iter().all(|&byte| byte == 0));
+    }
+
+    #[test_case]
+    fn corrupted_header_is_ignored() {
+        let disk = RamDisk::new(8);
+        let fs = Ustar::new(&disk, 0);
+        fs.create_file("file").unwrap();
+
+        let mut header = [0; BLOCK_SIZE];
+        disk.read_blocks(0, 1, &mut header).unwrap();
+        header[0] = b'F';
+        disk.write_blocks(0, 1, &header).unwrap();
+        assert!(fs.open("File").is_none());
+    }
+
+    #[test_case]
+    fn long_paths_use_prefix() {
+        let disk = RamDisk::new(8);
+        let fs = Ustar::new(&disk, 0);
+        let dir = "d".repeat(120);
+        let path = alloc::format!("{}/file", dir);
+        fs.create_file(&path).unwrap();
+        assert!(fs.create_file(&"f".repeat(101)).is_none());
+
+        let mut header = [0; BLOCK_SIZE];
+        disk.read_blocks(0, 1, &mut header).unwrap();
+        assert_eq!(header[..5], *b"file\0");
+        assert_eq!(header[345..465], *dir.as_bytes());
+        assert_eq!(fs.open(&path).unwrap().get_path(), path);
+    }
+
+    #[test_case]
+    fn owner_permissions() {
+        let disk = RamDisk::new(8);
+        let mut file = create_file(&disk, b"data");
+        assert!(file.can_read());
+        assert!(file.can_write());
+        assert!(!file.can_execute());
+
+        // The flags are the permissions of the owner in the POSIX mode
+        file.set_executable(true);
+        let mut header = [0; BLOCK_SIZE];
+        disk.read_blocks(0, 1, &mut header).unwrap();
+        assert_eq!(header[100..108], *b"0000744\0");
+        file.set_readable(false);
+        assert!(!file.can_read());
+        assert!(file.can_write());
     }
 }
@@ -0,0 +1,128 @@
+//! Device holding the root file system, an initrd if the boot disk has one, else its data partition
+//! The boot disk is the first disk with one of those partitions
+
+use super::ustar::{Ustar, BLOCK_SIZE};
+use crate::block_device::partition::{Guid, PartitionType};
+use crate::block_device::{
+    disks, partitions, BlockCache, BlockDevice, BlockError, Disk, Partition, RamDisk,
+};
+use crate::utils::lazy_static::LazyStatic;
+use alloc::boxed::Box;
+
+/// Partition ID of the file system in an MBR, reserved for local use
+pub const DATA_PARTITION_ID: u8 = 0x7F;
+/// Type GUID of the file system in a GPT
+pub const DATA_PARTITION_GUID: Guid = Guid::new(
+    0x5553_5441,
+    0x5246,
+    0x4B53,
+    [0x9B, 0x2E, 0x4C, 0x0A, 0x1F, 0x6D, 0x30, 0x7E],
+);
+/// Partition ID of the initrd in an MBR, reserved for local use
+pub const INITRD_PARTITION_ID: u8 = 0x7E;
+
+/// Number of blocks of the boot disk kept in memory
+const CACHE_BLOCKS: usize = 256;
+
+static ROOT_DEVICE: LazyStatic<RootDevice> = LazyStatic::new(find_root_device);
+
+type DiskPartition = Partition<&'static BlockCache<Disk>>;
+
+#[derive(Clone)]
+pub enum RootDevice {
+    /// Data partition of the boot disk
+    Disk(DiskPartition),
+    /// Read-only copy of the initrd partition of the boot disk
+    Initrd(&'static RamDisk),
+}
+
+impl BlockDevice for RootDevice {
+    fn block_size(&self) -> usize {
+        match self {
+            RootDevice::Disk(partition) => partition.block_size(),
+            RootDevice::Initrd(disk) => disk.block_size(),
+        }
+    }
+
+    fn block_count(&self) -> u64 {
+        match self {
+            RootDevice::Disk(partition) => partition.block_count(),
+            RootDevice::Initrd(disk) => disk.block_count(),
+        }
+    }
+
+    fn read_blocks(&self, lba: usize, count: usize, dst: &mut [u8]) -> Result<(), BlockError> {
+        match self {
+            RootDevice::Disk(partition) => partition.read_blocks(lba, count, dst),
+            RootDevice::Initrd(disk) => disk.read_blocks(lba, count, dst),
+        }
+    }
+
+    fn write_blocks(&self, lba: usize, count: usize, src: &[u8]) -> Result<(), BlockError> {
+        match self {
+            RootDevice::Disk(partition) => partition.write_blocks(lba, count, src),
+            RootDevice::Initrd(disk) => disk.write_blocks(lba, count, src),
+        }
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        match self {
+            RootDevice::Disk(partition) => partition.flush(),
+            RootDevice::Initrd(disk) => disk.flush(),
+        }
+    }
+}
+
+fn is_initrd(kind: PartitionType) -> bool {
+    kind == PartitionType::Mbr(INITRD_PARTITION_ID)
+}
+
+fn is_data(kind: PartitionType) -> bool {
+    matches!(
+        kind,
+        PartitionType::Mbr(DATA_PARTITION_ID) | PartitionType::Gpt(DATA_PARTITION_GUID)
+    )
+}
+
+/// Returns the initrd of the boot disk if it has one, else its data partition
+fn find_root_device() -> RootDevice {
+    let boot_disk = disks().into_iter().find(|disk| {
+        partitions(disk)
+            .unwrap_or_default()
+            .iter()
+            .any(|partition| is_initrd(partition.kind()) || is_data(partition.kind()))
+    });
+    let disk = boot_disk.expect("No disk holds a data partition");
+    let table = partitions(&disk).expect("Could not read the partition table of the boot disk");
+
+    // The initrd is read once, going through the cache would only evict blocks of the data partition
+    if let Some(initrd) = table.iter().find(|partition| is_initrd(partition.kind())) {
+        let disk = load_initrd(initrd).expect("Could not load the initrd");
+        return RootDevice::Initrd(Box::leak(Box::new(disk)));
+    }
+    let cache: &'static BlockCache<Disk> = Box::leak(Box::new(BlockCache::new(disk, CACHE_BLOCKS)));
+    partitions(&cache)
+        .expect("Could not read the partition table of the boot disk")
+        .into_iter()
+        .find(|partition| is_data(partition.kind()))
+        .map(RootDevice::Disk)
+        .expect("No data partition on the boot disk")
+}
+
+/// Copies `partition` to memory
+fn load_initrd(partition: &Partition<Disk>) -> Result<RamDisk, BlockError> {
+    let blocks = partition.block_count() as usize;
+    let mut data = alloc::vec![0; blocks * BLOCK_SIZE];
+    partition.read_blocks(0, blocks, &mut data)?;
+    Ok(RamDisk::read_only(data))
+}
+
+/// Returns the file system stored on the root device
+pub fn root_fs() -> Ustar<RootDevice> {
+    Ustar::new(ROOT_DEVICE.obtain().clone(), 0)
+}
+
+/// Writes the blocks modified since the last sync to the boot disk
+pub fn sync() -> Result<(), BlockError> {
+    ROOT_DEVICE.obtain().flush()
+}
@@ -1,180 +1,574 @@
 //! Implementation of a USTAR file system
+//! The archive is flat as in tar, a file belongs to the directory its path starts with
+//! Directories without an entry are implied by the paths of the entries below them
 
-use crate::arch::ata;
-use core::{mem, slice, str};
+use super::ustar_layout::{self as layout, NAME, PREFIX};
+use super::File;
+use crate::block_device::{BlockDevice, BlockError};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{mem, slice};
 
-pub const BLOCK_SIZE: usize = 512;
+pub use super::ustar_layout::BLOCK_SIZE;
 
-extern "C" {
-    static _kernel_size: usize;
-}
+/// Modes of the files and directories the kernel creates, as umask 022 gives them
+const FILE_MODE: u64 = 0o644;
+const DIRECTORY_MODE: u64 = 0o755;
 
-fn fs_start_lba() -> usize {
-    unsafe {
-        // Symbol is initialized by the linker
-        (&_kernel_size as *const _ as usize / BLOCK_SIZE) + 2
-    }
-}
+/// Blocks moved at once when entries move back over a removed one
+const MOVE_BLOCKS: usize = 16;
 
+/// Number of times entries moved in an archive, open files look theirs up again when it changes
+static MOVES: AtomicUsize = AtomicUsize::new(0);
+
+/// Type of an entry, stored as an ASCII digit
 #[derive(PartialEq, Eq)]
 #[repr(u8)]
 pub enum TypeFlag {
-    File = 0,
-    HardLink = 1,
-    SymbolicLink = 2,
-    CharacterDevice = 3,
-    BlockDevice = 4,
-    Directory = 5,
-    Pipe = 6,
+    File = layout::FILE,
+    HardLink = b'1',
+    SymbolicLink = b'2',
+    CharacterDevice = b'3',
+    BlockDevice = b'4',
+    Directory = layout::DIRECTORY,
+    Pipe = b'6',
+    Contiguous = b'7',
 }
 
+/// POSIX header of an entry, laid out as described in `ustar_layout`
 #[repr(C)]
 #[repr(align(512))]
-pub struct Entry {
-    name: [u8; 100],
-    permissions: u64,
-    owner_id: u64,
-    group_id: u64,
-    pub size: usize,
+struct Header {
+    name: [u8; NAME.len],
+    mode: [u8; 8],
+    owner_id: [u8; 8],
+    group_id: [u8; 8],
+    size: [u8; 12],
     last_modified: [u8; 12],
-    checksum: u64,
-    type_flag: TypeFlag,
+    checksum: [u8; 8],
+    type_flag: u8,
     linked_file: [u8; 100],
-    ustar_indicator: [u8; 6],
-    ustar_version: [u8; 2],
+    magic: [u8; 6],
+    version: [u8; 2],
     owner: [u8; 32],
     group: [u8; 32],
-    device_major_number: u64,
-    device_minor_number: u64,
-    ///Variation, the filename_prefix is slightly reduced
-    filename_prefix: [u8; 147],
-    ///To store entry's LBA
+    device_major_number: [u8; 8],
+    device_minor_number: [u8; 8],
+    /// Directories of the path when it does not fit in `name`
+    filename_prefix: [u8; PREFIX.len],
+    padding: [u8; 12],
+}
+
+/// Entry of the archive, along with the LBA of its header
+pub struct Entry {
+    header: Header,
+    /// Normalized path, the prefix followed by the name
+    path: String,
     sector: usize,
+    /// Value of `MOVES` when `sector` was known to be right
+    moves: usize,
+    /// Set for directories implied by the paths below them, they have no header
+    implicit: bool,
 }
 
-pub struct ReadDir {
-    lba: usize,
+/// What a single pass over the archive found about a path
+struct Lookup {
+    /// Entry stored with the path
+    entry: Option<Entry>,
+    /// Whether entries are stored below the path, which makes it a directory
+    has_children: bool,
+    /// Whether the parent of the path is a directory
+    parent_exists: bool,
+    /// Block following the last entry
+    end: usize,
 }
 
-impl ReadDir {
-    pub fn new(lba: usize) -> ReadDir {
-        ReadDir { lba }
+impl Lookup {
+    fn exists(&self) -> bool {
+        self.entry.is_some() || self.has_children
     }
-    pub fn root() -> ReadDir {
-        ReadDir::new(fs_start_lba())
+}
+
+/// USTAR archive stored on `device`, its first entry is at block `start`
+#[derive(Clone)]
+pub struct Ustar<D> {
+    device: D,
+    start: usize,
+}
+
+impl<D: BlockDevice + Clone> Ustar<D> {
+    pub fn new(device: D, start: usize) -> Ustar<D> {
+        assert_eq!(device.block_size(), BLOCK_SIZE);
+        Ustar { device, start }
     }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Returns every entry of the archive, in the order they are stored
+    pub fn entries(&self) -> Entries<D> {
+        Entries {
+            fs: self.clone(),
+            lba: self.start,
+        }
+    }
+
+    /// Returns the entries of the directory `path`, None if it is not a directory
+    /// Directories only implied by the entries below them are listed too
+    pub fn read_dir(&self, path: &str) -> Option<Vec<Entry>> {
+        let path = normalize(path);
+        let mut is_directory = path.is_empty();
+        let mut entries: Vec<Entry> = Vec::new();
+        for entry in self.entries() {
+            if entry.get_name() == path {
+                is_directory |= entry.is_directory();
+                continue;
+            }
+            let child = match child_of(&path, entry.get_name()) {
+                Some(child) => child,
+                None => continue,
+            };
+            is_directory = true;
+            let index = entries.iter().position(|known| known.get_name() == child);
+            if child.len() == entry.get_name().len() {
+                match index {
+                    // The entry of a directory stored after the entries below it
+                    Some(index) => entries[index] = entry,
+                    None => entries.push(entry),
+                }
+            } else if index.is_none() {
+                entries.push(Entry::implicit_directory(child));
+            }
+        }
+        if is_directory {
+            Some(entries)
+        } else {
+            None
+        }
+    }
+
+    /// Creates an empty file after the last entry
+    /// None if its directory does not exist, it already exists or its path does not fit in a header
+    pub fn create_file(&self, name: &str) -> Option<File<D>> {
+        let path = normalize(name);
+        let lookup = self.lookup(&path);
+        if path.is_empty() || !lookup.parent_exists || lookup.exists() {
+            return None;
+        }
+        let entry = Entry::new(&path, lookup.end)?;
+        self.append(entry)
+    }
+
+    /// Creates the directory `name`, None if its parent does not exist or `name` already does
+    pub fn create_dir(&self, name: &str) -> Option<File<D>> {
+        let path = normalize(name);
+        let lookup = self.lookup(&path);
+        if path.is_empty() || !lookup.parent_exists || lookup.exists() {
+            return None;
+        }
+        let entry = Entry::new_directory(&path, lookup.end)?;
+        self.append(entry)
+    }
+
+    /// Removes the empty directory `name`
+    pub fn remove_dir(&self, name: &str) -> Option<()> {
+        let path = normalize(name);
+        let lookup = self.lookup(&path);
+        match lookup.entry {
+            Some(entry) if !path.is_empty() && entry.is_directory() && !lookup.has_children => {
+                self.remove(&entry, lookup.end).ok()
+            }
+            _ => None,
+        }
+    }
+
+    pub fn open(&self, filename: &str) -> Option<File<D>> {
+        let path = normalize(filename);
+        let entry = if path.is_empty() {
+            Entry::implicit_directory("/")
+        } else {
+            self.find(&path)?
+        };
+        Some(File::with_fs(self.clone(), entry))
+    }
+
+    /// Looks `entry` up again if entries moved since its block was read, None if it was removed
+    pub fn refresh(&self, entry: &mut Entry) -> Option<()> {
+        if !entry.implicit && entry.moves != MOVES.load(Ordering::Relaxed) {
+            *entry = self.find(&entry.path)?;
+        }
+        Some(())
+    }
+
+    /// Returns the entry of the normalized `path`, implicit directories included
+    fn find(&self, path: &str) -> Option<Entry> {
+        let lookup = self.lookup(path);
+        match lookup.entry {
+            Some(entry) => Some(entry),
+            None if lookup.has_children => Some(Entry::implicit_directory(path)),
+            None => None,
+        }
+    }
+
+    /// Looks the normalized `path` up in a single pass over the archive
+    fn lookup(&self, path: &str) -> Lookup {
+        let parent = parent(path);
+        let mut lookup = Lookup {
+            entry: None,
+            has_children: false,
+            parent_exists: parent.is_empty(),
+            end: self.start,
+        };
+        for entry in self.entries() {
+            lookup.end = entry.end_sector();
+            let name = entry.get_name();
+            if !lookup.parent_exists {
+                lookup.parent_exists =
+                    (name == parent && entry.is_directory()) || is_below(name, parent);
+            }
+            lookup.has_children |= !path.is_empty() && is_below(name, path);
+            if name == path && lookup.entry.is_none() {
+                lookup.entry = Some(entry);
+            }
+        }
+        lookup
+    }
+
+    /// Stores `entry` at the end of the archive
+    fn append(&self, entry: Entry) -> Option<File<D>> {
+        self.save(&entry).ok()?;
+        self.end_archive(&entry).ok()?;
+        Some(File::with_fs(self.clone(), entry))
+    }
+
+    /// Removes `entry`, the entries after it move back over it along with the end of the archive
+    /// `end` is the block following the last entry
+    fn remove(&self, entry: &Entry, end: usize) -> Result<(), BlockError> {
+        let removed = entry.end_sector() - entry.sector;
+        if entry.end_sector() < end {
+            MOVES.fetch_add(1, Ordering::Relaxed);
+            let mut blocks = alloc::vec![0; MOVE_BLOCKS * BLOCK_SIZE];
+            let mut lba = entry.end_sector();
+            while lba < end {
+                let count = MOVE_BLOCKS.min(end - lba);
+                let blocks = &mut blocks[..count * BLOCK_SIZE];
+                self.device.read_blocks(lba, count, blocks)?;
+                self.device.write_blocks(lba - removed, count, blocks)?;
+                lba += count;
+            }
+        }
+        // An archive filling the device might have no room for its end
+        let end = end - removed;
+        let count = (self.device.block_count() as usize - end).min(2);
+        self.device
+            .write_blocks(end, count, &[0; 2 * BLOCK_SIZE][..count * BLOCK_SIZE])
+    }
+
+    /// Makes room for `size` bytes of data in `entry`, followed by the end of the archive
+    /// If other entries follow it, `entry` is moved after the last one and they move back
+    pub fn grow(&self, entry: &mut Entry, size: usize) -> Result<(), BlockError> {
+        let end = self
+            .entries()
+            .last()
+            .map_or(self.start, |entry| entry.end_sector());
+        let blocks = entry.end_sector() - entry.sector;
+        let is_last = entry.end_sector() == end;
+        let sector = if is_last { entry.sector } else { end };
+        if (sector + 1 + (size + BLOCK_SIZE - 1) / BLOCK_SIZE + 2) as u64
+            > self.device.block_count()
+        {
+            return Err(BlockError::OutOfRange);
+        }
+        if is_last {
+            return Ok(());
+        }
+
+        let mut block = [0; BLOCK_SIZE];
+        for offset in 0..blocks {
+            self.device
+                .read_blocks(entry.sector + offset, 1, &mut block)?;
+            self.device.write_blocks(end + offset, 1, &block)?;
+        }
+        self.remove(entry, end + blocks)?;
+        entry.sector = end - blocks;
+        entry.moves = MOVES.load(Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Reads the entry stored at `lba`, None if there is none or its checksum is wrong
+    pub fn read_entry(&self, lba: usize) -> Option<Entry> {
+        let mut header = Header::default();
+        self.device
+            .read_blocks(lba, 1, any_as_u8_slice_mut(&mut header))
+            .ok()?;
+        Entry::from_header(header, lba)
+    }
+
+    /// Writes the header of `entry`, implicit directories have none
+    pub fn save(&self, entry: &Entry) -> Result<(), BlockError> {
+        if entry.implicit {
+            return Ok(());
+        }
+        self.device
+            .write_blocks(entry.sector, 1, any_as_u8_slice(&entry.header))
+    }
+
+    /// Writes the two empty blocks ending the archive after the data of `entry`
+    pub fn end_archive(&self, entry: &Entry) -> Result<(), BlockError> {
+        self.device
+            .write_blocks(entry.end_sector(), 2, &[0; 2 * BLOCK_SIZE])
+    }
+}
+
+pub struct Entries<D> {
+    fs: Ustar<D>,
+    lba: usize,
 }
 
-impl Iterator for ReadDir {
+impl<D: BlockDevice + Clone> Iterator for Entries<D> {
     type Item = Entry;
     fn next(&mut self) -> Option<Self::Item> {
-        Entry::from_sector(self.lba).map(|entry| {
-            self.lba += ((entry.size + BLOCK_SIZE - 1) / BLOCK_SIZE) + 1;
-            entry
-        })
+        let entry = self.fs.read_entry(self.lba)?;
+        self.lba = entry.end_sector();
+        Some(entry)
     }
 }
 
 impl Entry {
-    pub fn new(name: &str, sector: usize) -> Entry {
-        let mut entry = Entry::default();
-        entry.name[..name.len()].copy_from_slice(name.as_bytes());
-        entry.sector = sector;
-        entry
+    /// Creates a regular file, None if `path` does not fit in the name and prefix fields
+    pub fn new(path: &str, sector: usize) -> Option<Entry> {
+        let (prefix, name) = split_path(path)?;
+        let mut header = Header::default();
+        header.name[..name.len()].copy_from_slice(name.as_bytes());
+        header.filename_prefix[..prefix.len()].copy_from_slice(prefix.as_bytes());
+        write_octal(&mut header.mode, FILE_MODE);
+        write_octal(&mut header.owner_id, 0);
+        write_octal(&mut header.group_id, 0);
+        write_octal(&mut header.size, 0);
+        write_octal(&mut header.last_modified, 0);
+
+        let mut entry = Entry {
+            header,
+            path: path.into(),
+            sector,
+            moves: MOVES.load(Ordering::Relaxed),
+            implicit: false,
+        };
+        entry.update_checksum();
+        Some(entry)
     }
 
-    pub fn from_sector(lba: usize) -> Option<Entry> {
-        let mut entry = Entry::default();
-        ata::read_sectors(lba, 1, any_as_u8_slice_mut(&mut entry));
-        match entry.is_file() {
-            true => Some(entry),
-            false => None,
+    /// Returns a directory that has no header, only implied by the paths of the entries below it
+    fn implicit_directory(path: &str) -> Entry {
+        let mut header = Header {
+            type_flag: TypeFlag::Directory as u8,
+            ..Header::default()
+        };
+        write_octal(&mut header.mode, DIRECTORY_MODE);
+        Entry {
+            header,
+            path: path.into(),
+            sector: 0,
+            moves: MOVES.load(Ordering::Relaxed),
+            implicit: true,
         }
     }
 
-    pub fn save(&self) {
-        ata::write_sectors(self.sector, 1, any_as_u8_slice(self));
+    /// Creates a directory, its name ends with a '/' as tar expects
+    pub fn new_directory(path: &str, sector: usize) -> Option<Entry> {
+        let mut entry = Entry::new(&alloc::format!("{}/", path), sector)?;
+        entry.header.type_flag = TypeFlag::Directory as u8;
+        write_octal(&mut entry.header.mode, DIRECTORY_MODE);
+        entry.path = path.into();
+        entry.update_checksum();
+        Some(entry)
     }
 
-    pub fn is_file(&self) -> bool {
-        let res = str::from_utf8(&self.ustar_indicator);
-        res.is_ok() && res.unwrap() == "ustar\0"
+    /// Returns the entry of `header`, None if it is not a valid USTAR header
+    /// GNU headers are accepted, they only differ by their magic
+    fn from_header(header: Header, sector: usize) -> Option<Entry> {
+        let checksum = parse_octal(&header.checksum)?;
+        if !header.magic.starts_with(b"ustar") || checksum != header.checksum() {
+            return None;
+        }
+
+        let name = c_str(&header.name);
+        let prefix = c_str(&header.filename_prefix);
+        let path = if prefix.is_empty() {
+            normalize(&String::from_utf8_lossy(name))
+        } else {
+            normalize(&alloc::format!(
+                "{}/{}",
+                String::from_utf8_lossy(prefix),
+                String::from_utf8_lossy(name)
+            ))
+        };
+        Some(Entry {
+            header,
+            path,
+            sector,
+            moves: MOVES.load(Ordering::Relaxed),
+            implicit: false,
+        })
     }
 
     pub fn get_sector(&self) -> usize {
         self.sector
     }
 
+    /// Returns the block following the data of the entry
+    pub fn end_sector(&self) -> usize {
+        self.sector + 1 + (self.get_size() + BLOCK_SIZE - 1) / BLOCK_SIZE
+    }
+
     pub fn get_name(&self) -> &str {
-        let cstr = &self.name;
-        let len = cstr.iter().position(|c| c == &b'\0').unwrap_or(cstr.len());
-        str::from_utf8(&cstr[..len]).expect("Could not parse Cstring")
+        &self.path
     }
 
     pub fn is_directory(&self) -> bool {
-        self.type_flag == TypeFlag::Directory
+        self.header.type_flag == TypeFlag::Directory as u8
+    }
+
+    pub fn get_size(&self) -> usize {
+        parse_octal(&self.header.size).unwrap_or(0) as usize
+    }
+
+    pub fn set_size(&mut self, size: usize) {
+        write_octal(&mut self.header.size, size as u64);
+        self.update_checksum();
     }
 
     pub fn get_permissions(&self) -> u64 {
-        self.permissions
+        parse_octal(&self.header.mode).unwrap_or(0)
     }
 
     pub fn set_permissions(&mut self, permissions: u64) {
-        self.permissions = permissions;
-        self.save();
+        write_octal(&mut self.header.mode, permissions);
+        self.update_checksum();
+    }
+
+    /// Stores the checksum as 6 digits followed by a NUL and a space, as tar does
+    fn update_checksum(&mut self) {
+        let checksum = self.header.checksum();
+        write_octal(&mut self.header.checksum[..7], checksum);
+        self.header.checksum[7] = b' ';
     }
 }
 
-impl Default for Entry {
-    fn default() -> Entry {
-        let ustar_indicator = [b'u', b's', b't', b'a', b'r', 0];
-        Entry {
-            name: [b'\0'; 100],
-            permissions: 0,
-            owner_id: 0,
-            group_id: 0,
-            size: 0,
+impl Header {
+    /// Returns the sum of the bytes of the header, the checksum field counting as spaces
+    fn checksum(&self) -> u64 {
+        let sum = |bytes: &[u8]| bytes.iter().map(|&byte| byte as u64).sum::<u64>();
+        sum(any_as_u8_slice(self)) - sum(&self.checksum) + sum(&[b' '; 8])
+    }
+}
+
+impl Default for Header {
+    fn default() -> Header {
+        Header {
+            name: [0; NAME.len],
+            mode: [0; 8],
+            owner_id: [0; 8],
+            group_id: [0; 8],
+            size: [0; 12],
             last_modified: [0; 12],
-            checksum: 0,
-            type_flag: TypeFlag::File,
+            checksum: [0; 8],
+            type_flag: TypeFlag::File as u8,
             linked_file: [0; 100],
-            ustar_indicator,
-            ustar_version: [0; 2],
+            magic: *layout::POSIX_MAGIC,
+            version: *layout::POSIX_VERSION,
             owner: [0; 32],
             group: [0; 32],
-            device_major_number: 0,
-            device_minor_number: 0,
-            filename_prefix: [0; 147],
-            sector: 0,
+            device_major_number: [0; 8],
+            device_minor_number: [0; 8],
+            filename_prefix: [0; PREFIX.len],
+            padding: [0; 12],
         }
     }
 }
 
-pub fn ls() {
-    let dir = ReadDir::root();
-    for entry in dir {
-        crate::println!("{}", entry.get_name());
+/// Returns `path` relative to the root, without trailing '/' nor `.` and `..` components
+/// The parent of the root is the root
+pub fn normalize(path: &str) -> String {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            _ => components.push(component),
+        }
     }
+    components.join("/")
 }
 
-pub fn create_file(name: &str) -> Entry {
-    let lba = ReadDir::root()
-        .last()
-        .map(|entry| entry.sector + 1 + (entry.size + BLOCK_SIZE - 1) / BLOCK_SIZE)
-        .unwrap_or_else(fs_start_lba);
+/// Returns the directory of the normalized `path`
+fn parent(path: &str) -> &str {
+    path.rfind('/').map_or("", |index| &path[..index])
+}
 
-    let entry = Entry::new(name, lba);
-    entry.save();
-    entry
+/// Returns whether the normalized `path` is below the directory `dir`
+fn is_below(path: &str, dir: &str) -> bool {
+    !dir.is_empty()
+        && path.len() > dir.len() + 1
+        && path.starts_with(dir)
+        && path.as_bytes()[dir.len()] == b'/'
 }
 
-pub fn open(filename: &str) -> Option<Entry> {
-    if filename == "/" {
-        let mut entry = Entry::new("/", fs_start_lba());
-        entry.type_flag = TypeFlag::Directory;
-        Some(entry)
+/// Returns the path of the entry of the directory `dir` that `path` is or is below
+fn child_of<'a>(dir: &str, path: &'a str) -> Option<&'a str> {
+    let start = if dir.is_empty() {
+        0
+    } else if is_below(path, dir) {
+        dir.len() + 1
     } else {
-        ReadDir::root().find(|entry| entry.get_name() == filename)
+        return None;
+    };
+    let len = path[start..]
+        .find('/')
+        .map_or(path.len(), |len| start + len);
+    Some(&path[..len]).filter(|child| !child.is_empty())
+}
+
+/// Splits `path` in a prefix and a name that fit in their fields, the prefix is empty if possible
+fn split_path(path: &str) -> Option<(&str, &str)> {
+    if path.len() <= NAME.len {
+        return Some(("", path));
+    }
+    let (index, _) = path.match_indices('/').find(|&(index, _)| {
+        index <= PREFIX.len && path.len() - index - 1 <= NAME.len && index + 1 < path.len()
+    })?;
+    Some((&path[..index], &path[index + 1..]))
+}
+
+/// Returns the bytes of `field` before the first NUL
+fn c_str(field: &[u8]) -> &[u8] {
+    let len = field.iter().position(|&c| c == 0).unwrap_or(field.len());
+    &field[..len]
+}
+
+/// Parses the octal number of `field`, padded with leading spaces and ended by a NUL or a space
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    field
+        .iter()
+        .skip_while(|&&c| c == b' ')
+        .take_while(|&&c| c != 0 && c != b' ')
+        .try_fold(0, |value: u64, &c| match c {
+            b'0'..=b'7' => Some(value * 8 + (c - b'0') as u64),
+            _ => None,
+        })
+}
+
+/// Writes `value` in octal in `field`, zero padded and ended by a NUL
+fn write_octal(field: &mut [u8], mut value: u64) {
+    let (last, digits) = field.split_last_mut().expect("Empty numeric field");
+    *last = 0;
+    for digit in digits.iter_mut().rev() {
+        *digit = b'0' + (value % 8) as u8;
+        value /= 8;
     }
 }
 
@@ -195,3 +589,58 @@ fn any_as_u8_slice_mut<T: Sized>(p: &mut T) -> &mut [u8] {
         slice::from_raw_parts_mut((p as *mut T) as *mut u8, mem::size_of::<T>())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::super::ustar_layout::*;
+    use super::Header;
+
+    #[test_case]
+    fn header_layout() {
+        let header = Header::default();
+        let base = &header as *const Header as usize;
+        let field = |start: *const u8, len: usize| Field {
+            offset: start as usize - base,
+            len,
+        };
+        let fields = [
+            (NAME, field(header.name.as_ptr(), header.name.len())),
+            (MODE, field(header.mode.as_ptr(), header.mode.len())),
+            (
+                OWNER_ID,
+                field(header.owner_id.as_ptr(), header.owner_id.len()),
+            ),
+            (
+                GROUP_ID,
+                field(header.group_id.as_ptr(), header.group_id.len()),
+            ),
+            (SIZE, field(header.size.as_ptr(), header.size.len())),
+            (
+                LAST_MODIFIED,
+                field(header.last_modified.as_ptr(), header.last_modified.len()),
+            ),
+            (
+                CHECKSUM,
+                field(header.checksum.as_ptr(), header.checksum.len()),
+            ),
+            (TYPE_FLAG, field(&header.type_flag, 1)),
+            (MAGIC, field(header.magic.as_ptr(), header.magic.len())),
+            (
+                VERSION,
+                field(header.version.as_ptr(), header.version.len()),
+            ),
+            (
+                PREFIX,
+                field(
+                    header.filename_prefix.as_ptr(),
+                    header.filename_prefix.len(),
+                ),
+            ),
+        ];
+        for (expected, actual) in fields.iter() {
+            assert_eq!(expected.offset, actual.offset);
+            assert_eq!(expected.len, actual.len);
+        }
+        assert_eq!(core::mem::size_of::<Header>(), BLOCK_SIZE);
+    }
+}
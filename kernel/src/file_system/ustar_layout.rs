@@ -0,0 +1,38 @@
+//! Layout of POSIX USTAR headers
+//! Shared with the kernel runner, which packs the initrd and file system the kernel reads
+
+pub const BLOCK_SIZE: usize = 512;
+
+/// Bytes of a header holding one of its fields
+pub struct Field {
+    pub offset: usize,
+    pub len: usize,
+}
+
+impl Field {
+    const fn new(offset: usize, len: usize) -> Field {
+        Field { offset, len }
+    }
+}
+
+/// Numbers are NUL terminated octal ASCII, paths longer than `NAME` are split on a '/'
+/// with the directories in `PREFIX`
+pub const NAME: Field = Field::new(0, 100);
+pub const MODE: Field = Field::new(100, 8);
+pub const OWNER_ID: Field = Field::new(108, 8);
+pub const GROUP_ID: Field = Field::new(116, 8);
+pub const SIZE: Field = Field::new(124, 12);
+pub const LAST_MODIFIED: Field = Field::new(136, 12);
+pub const CHECKSUM: Field = Field::new(148, 8);
+pub const TYPE_FLAG: Field = Field::new(156, 1);
+pub const MAGIC: Field = Field::new(257, 6);
+pub const VERSION: Field = Field::new(263, 2);
+pub const PREFIX: Field = Field::new(345, 155);
+
+/// Magic of POSIX archives, GNU archives follow "ustar" with spaces instead
+pub const POSIX_MAGIC: &[u8; 6] = b"ustar\0";
+pub const POSIX_VERSION: &[u8; 2] = b"00";
+
+/// Type flags of regular files and directories
+pub const FILE: u8 = b'0';
+pub const DIRECTORY: u8 = b'5';
@@ -12,6 +12,7 @@
 extern crate alloc;
 
 pub mod arch;
+pub mod block_device;
 pub mod driver;
 #[allow(dead_code)]
 pub mod file_system;
@@ -35,6 +36,10 @@ mod test {
 
     #[no_mangle]
     pub extern "C" fn _start() -> ! {
+        arch::switch_stack(memory_manager::stack::KernelStack::new(), run_tests)
+    }
+
+    fn run_tests() -> ! {
         arch::init();
         test_main();
         exit_qemu(QemuExitCode::Success)
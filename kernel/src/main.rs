@@ -3,10 +3,16 @@
 
 extern crate kernel;
 
+use kernel::memory_manager::stack::KernelStack;
 use kernel::*;
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
+    // Leave the bootloader's stack, which has no guard page, before anything runs on it
+    arch::switch_stack(KernelStack::new(), main)
+}
+
+fn main() -> ! {
     arch::init();
     run_tty();
     arch::halt()
@@ -0,0 +1,586 @@
+//! Address spaces own the lower half of the virtual memory and share the kernel half
+
+use super::vma::{Areas, Vma};
+use super::{frame::Frame, phys_to_virt, ALLOCATOR, PAGE_SIZE};
+use crate::arch::interrupt;
+use crate::arch::paging::tables::{
+    self, Entry, EntryFlag, Level1, Level2, Level3, Level4, MapError, PageSize, Table, TableLevel,
+    ENTRY_COUNT, RECURSIVE_INDEX,
+};
+use crate::utils::lazy_static::LazyStatic;
+use alloc::collections::BTreeMap;
+use core::ptr;
+
+/// Level 4 entries below this index map user space
+const KERNEL_HALF_INDEX: usize = ENTRY_COUNT / 2;
+/// First address of the kernel half
+pub const USER_SPACE_END: usize = 0x0000_8000_0000_0000;
+
+const TABLE_FLAGS: u64 = EntryFlag::Present as u64 | EntryFlag::Writable as u64;
+
+/// Virtual memory areas of every address space, indexed by their level 4 table
+static AREAS: LazyStatic<BTreeMap<Frame, Areas>> = LazyStatic::new(BTreeMap::new);
+
+/// Creates every level 3 table of the kernel half
+/// Address spaces copy the level 4 entries of the kernel half, so they can never change
+pub fn init() {
+    let level4 = tables::get_level4();
+    let mut allocator = ALLOCATOR.obtain();
+
+    for index in KERNEL_HALF_INDEX..ENTRY_COUNT {
+        if index != RECURSIVE_INDEX && level4[index].is_unused() {
+            let frame = allocator.allocate_frame().expect("Out of memory");
+            table::<Level3>(frame).zero();
+            level4[index].set(frame, TABLE_FLAGS);
+        }
+    }
+}
+
+pub struct AddressSpace {
+    level4: Frame,
+}
+
+impl AddressSpace {
+    /// Creates an empty address space sharing the kernel half of the active one
+    pub fn new() -> AddressSpace {
+        let frame = ALLOCATOR.obtain().allocate_frame().expect("Out of memory");
+        let level4 = table::<Level4>(frame);
+        let active = tables::get_level4();
+
+        level4.zero();
+        for index in KERNEL_HALF_INDEX..ENTRY_COUNT {
+            if let Some(table) = active[index].address() {
+                level4[index].set(table, active[index].flags());
+            }
+        }
+        level4[RECURSIVE_INDEX].set(frame, TABLE_FLAGS);
+        AREAS.obtain().insert(frame, Areas::default());
+
+        AddressSpace { level4: frame }
+    }
+
+    /// Makes this address space the active one
+    pub fn activate(&self) {
+        // SAFETY: The level 4 table shares the kernel half of every address space
+        unsafe {
+            tables::set_active_level4(self.level4);
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        tables::active_level4() == self.level4
+    }
+
+    /// Maps the user page at `virt_addr` to `frame`
+    /// The address space takes ownership of the frame
+    pub fn map(&mut self, virt_addr: usize, frame: Frame, flags: u64) -> Result<(), MapError> {
+        assert!(
+            virt_addr < USER_SPACE_END,
+            "{:#x} is not a user address",
+            virt_addr
+        );
+        let flags = flags | EntryFlag::UserAccessible as u64;
+
+        self.with_tables(|| {
+            tables::map_to(virt_addr, frame.base_addr, flags, &mut ALLOCATOR.obtain())
+        })
+    }
+
+    /// Maps a zeroed frame at the user page `virt_addr`
+    pub fn allocate(&mut self, virt_addr: usize, flags: u64) -> Result<Frame, MapError> {
+        let frame = ALLOCATOR
+            .obtain()
+            .allocate_frame()
+            .ok_or(MapError::OutOfFrames)?;
+        zero_frame(frame);
+
+        match self.map(virt_addr, frame, flags) {
+            Ok(()) => Ok(frame),
+            Err(err) => {
+                ALLOCATOR.obtain().deallocate_frame(frame);
+                Err(err)
+            }
+        }
+    }
+
+    /// Unmaps the user page at `virt_addr` and frees its frame
+    pub fn unmap(&mut self, virt_addr: usize) -> Result<(), MapError> {
+        assert!(
+            virt_addr < USER_SPACE_END,
+            "{:#x} is not a user address",
+            virt_addr
+        );
+
+        let (frame, size) = self.with_tables(|| tables::unmap(virt_addr))?;
+        release_frames(frame, size);
+        Ok(())
+    }
+
+    /// Reserves `size` bytes of user memory at `start`, backed by zeroed frames once accessed
+    pub fn reserve(&mut self, start: usize, size: usize, flags: u64) -> Result<(), MapError> {
+        let flags = flags | EntryFlag::UserAccessible as u64;
+        reserve(self.level4, Vma::new(start, size, flags))
+    }
+
+    /// Reserves an empty heap at `start`, grown with `brk`
+    pub fn reserve_heap(&mut self, start: usize, flags: u64) -> Result<(), MapError> {
+        if start % PAGE_SIZE != 0 {
+            return Err(MapError::Unaligned(start));
+        }
+        let flags = flags | EntryFlag::UserAccessible as u64;
+        AREAS
+            .obtain()
+            .entry(self.level4)
+            .or_default()
+            .insert_heap(start, flags)
+    }
+
+    /// Moves the end of the heap to `end`, pages are backed once accessed
+    /// Frees the pages the heap no longer covers and returns the previous end
+    pub fn brk(&mut self, end: usize) -> Result<usize, MapError> {
+        if end % PAGE_SIZE != 0 {
+            return Err(MapError::Unaligned(end));
+        }
+        assert!(end <= USER_SPACE_END, "{:#x} is not a user address", end);
+        let previous = AREAS
+            .obtain()
+            .get_mut(&self.level4)
+            .ok_or(MapError::NotMapped(end))?
+            .resize_heap(end)?;
+
+        for page in (end..previous).step_by(PAGE_SIZE) {
+            match self.with_tables(|| tables::unmap(page)) {
+                Ok((frame, size)) => release_frames(frame, size),
+                Err(MapError::NotMapped(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(previous)
+    }
+
+    /// Reserves a stack of `size` bytes below `top`, backed once accessed
+    /// Accesses right below the stack grow it down to at most `max_size` bytes
+    pub fn reserve_stack(
+        &mut self,
+        top: usize,
+        size: usize,
+        max_size: usize,
+        flags: u64,
+    ) -> Result<(), MapError> {
+        let flags = flags | EntryFlag::UserAccessible as u64;
+        reserve(self.level4, Vma::stack(top, size, max_size, flags))
+    }
+
+    /// Duplicates the address space, both share their frames until one of them writes to it
+    /// Fails on huge pages, which cannot be copied one frame at a time
+    pub fn clone_cow(&mut self) -> Result<AddressSpace, MapError> {
+        const IGNORED_FLAGS: u64 = EntryFlag::Accessed as u64 | EntryFlag::Dirty as u64;
+
+        let child = AddressSpace::new();
+        let areas = AREAS.obtain().get(&self.level4).cloned();
+        if let Some(areas) = areas {
+            AREAS.obtain().insert(child.level4, areas);
+        }
+
+        let res = child.with_tables(|| {
+            walk_user(
+                self.level4,
+                &mut |addr, entry, size| {
+                    if size != PageSize::Size4KiB {
+                        return Err(MapError::HugePageConflict(addr));
+                    }
+                    let frame = match entry.address() {
+                        Some(frame) => frame,
+                        None => return Ok(()),
+                    };
+
+                    entry.set_copy_on_write();
+                    let flags = entry.flags() & !IGNORED_FLAGS;
+                    let mut allocator = ALLOCATOR.obtain();
+                    tables::map_to(addr, frame.base_addr, flags, &mut allocator)?;
+                    allocator.share(frame);
+                    Ok(())
+                },
+                &mut |_| {},
+            )
+        });
+
+        // Pages of this address space became read-only
+        if self.is_active() {
+            tables::flush_all();
+        }
+        res.map(|()| child)
+    }
+
+    /// Translates `virt_addr` using this address space's tables
+    pub fn translate(&self, virt_addr: usize) -> Option<usize> {
+        self.with_tables(|| tables::translate_addr(virt_addr))
+    }
+
+    /// Runs `f` with the recursive mapping pointing to this address space's tables
+    /// Every function of `tables` then applies to this address space
+    fn with_tables<T>(&self, f: impl FnOnce() -> T) -> T {
+        if self.is_active() {
+            return f();
+        }
+
+        interrupt::without_interrupts(|| {
+            // While swapped, the active level 4 table is only reachable
+            // through the physical memory map
+            let active_frame = tables::active_level4();
+            let active = table::<Level4>(active_frame);
+
+            active[RECURSIVE_INDEX].set(self.level4, TABLE_FLAGS);
+            tables::flush_all();
+            let res = f();
+            active[RECURSIVE_INDEX].set(active_frame, TABLE_FLAGS);
+            tables::flush_all();
+            res
+        })
+    }
+}
+
+impl Default for AddressSpace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees every user frame, the tables mapping them and the level 4 table
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "Cannot drop the active address space");
+        walk_user(
+            self.level4,
+            &mut |_, entry, size| {
+                if let Some(frame) = entry.address() {
+                    release_frames(frame, size);
+                }
+                Ok(())
+            },
+            &mut |frame| ALLOCATOR.obtain().deallocate_frame(frame),
+        )
+        .expect("Could not free address space");
+        ALLOCATOR.obtain().deallocate_frame(self.level4);
+        AREAS.obtain().remove(&self.level4);
+    }
+}
+
+/// Reserves `size` bytes at `start` in the active address space
+pub fn reserve_active(start: usize, size: usize, flags: u64) -> Result<(), MapError> {
+    reserve(tables::active_level4(), Vma::new(start, size, flags))
+}
+
+/// Releases the areas of the active address space located between `start` and `end`
+/// Returns true if any area was released
+pub fn release_active(start: usize, end: usize) -> bool {
+    match AREAS.obtain().get_mut(&tables::active_level4()) {
+        Some(areas) => areas.remove(start, end),
+        None => false,
+    }
+}
+
+fn reserve(level4: Frame, vma: Vma) -> Result<(), MapError> {
+    if vma.start % PAGE_SIZE != 0 || vma.end % PAGE_SIZE != 0 {
+        return Err(MapError::Unaligned(vma.start));
+    }
+    assert!(
+        vma.end <= USER_SPACE_END,
+        "{:#x} is not a user address",
+        vma.start
+    );
+
+    AREAS.obtain().entry(level4).or_default().insert(vma)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    /// The access is not allowed
+    Invalid,
+    /// The fault happened while the memory manager was locked
+    Locked,
+}
+
+/// Backs the page containing `addr` if it lies in an area of the active address space,
+/// stacks grow down to the page if they can
+pub fn handle_page_fault(addr: usize) -> Result<(), FaultError> {
+    let mut areas = AREAS.try_obtain().ok_or(FaultError::Locked)?;
+    let vma = areas
+        .get_mut(&tables::active_level4())
+        .and_then(|areas| areas.find_or_grow(addr).copied())
+        .ok_or(FaultError::Invalid)?;
+    drop(areas);
+
+    let mut allocator = ALLOCATOR.try_obtain().ok_or(FaultError::Locked)?;
+    let frame = allocator.allocate_frame().ok_or(FaultError::Invalid)?;
+    zero_frame(frame);
+
+    let page = addr / PAGE_SIZE * PAGE_SIZE;
+    if tables::map_to(page, frame.base_addr, vma.flags, &mut allocator).is_err() {
+        allocator.deallocate_frame(frame);
+        return Err(FaultError::Invalid);
+    }
+    Ok(())
+}
+
+/// Gives the active address space its own copy of the copy-on-write page containing `addr`
+pub fn handle_write_fault(addr: usize) -> Result<(), FaultError> {
+    let shared = tables::copy_on_write_frame(addr).ok_or(FaultError::Invalid)?;
+
+    let mut allocator = ALLOCATOR.try_obtain().ok_or(FaultError::Locked)?;
+
+    // The last owner keeps the frame
+    if allocator.owners(shared) == 1 {
+        return tables::break_copy_on_write(addr, shared).map_err(|_| FaultError::Invalid);
+    }
+
+    let frame = allocator.allocate_frame().ok_or(FaultError::Invalid)?;
+    // SAFETY: All physical memory is mapped, the new frame is not used yet
+    unsafe {
+        ptr::copy_nonoverlapping(
+            phys_to_virt(shared.base_addr) as *const u8,
+            phys_to_virt(frame.base_addr) as *mut u8,
+            PAGE_SIZE,
+        );
+    }
+
+    tables::break_copy_on_write(addr, frame).expect("Copy-on-write page disappeared");
+    allocator.deallocate_frame(shared);
+    Ok(())
+}
+
+/// Returns the table stored in `frame` through the physical memory map
+fn table<L: TableLevel>(frame: Frame) -> &'static mut Table<L> {
+    // SAFETY: All physical memory is mapped, the frame holds a table
+    unsafe { &mut *(phys_to_virt(frame.base_addr) as *mut Table<L>) }
+}
+
+fn zero_frame(frame: Frame) {
+    // SAFETY: All physical memory is mapped, the frame is owned by the caller
+    unsafe {
+        ptr::write_bytes(phys_to_virt(frame.base_addr) as *mut u8, 0, PAGE_SIZE);
+    }
+}
+
+/// Releases every 4KiB frame of a page of size `size`
+fn release_frames(frame: Frame, size: PageSize) {
+    let mut allocator = ALLOCATOR.obtain();
+    for offset in (0..size.bytes()).step_by(PAGE_SIZE) {
+        allocator.deallocate_frame(Frame::from_address(frame.base_addr + offset));
+    }
+}
+
+type PageFn<'a> = dyn FnMut(usize, &mut Entry, PageSize) -> Result<(), MapError> + 'a;
+
+/// Calls `page` on every leaf entry of the user half of `level4` with the address it maps,
+/// and `table` on every table frame once its entries are visited
+fn walk_user(
+    level4: Frame,
+    page: &mut PageFn,
+    table: &mut dyn FnMut(Frame),
+) -> Result<(), MapError> {
+    let level4 = self::table::<Level4>(level4);
+    for index in 0..KERNEL_HALF_INDEX {
+        if let Some(frame) = level4[index].address() {
+            walk_level3(
+                frame,
+                index * ENTRY_COUNT * PageSize::Size1GiB.bytes(),
+                page,
+                table,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn walk_level3(
+    frame: Frame,
+    base: usize,
+    page: &mut PageFn,
+    table: &mut dyn FnMut(Frame),
+) -> Result<(), MapError> {
+    let level3 = self::table::<Level3>(frame);
+    for index in 0..ENTRY_COUNT {
+        let addr = base + index * PageSize::Size1GiB.bytes();
+        match level3[index].address() {
+            Some(_) if level3[index].is_huge() => {
+                page(addr, &mut level3[index], PageSize::Size1GiB)?
+            }
+            Some(next) => walk_level2(next, addr, page, table)?,
+            None => {}
+        }
+    }
+    table(frame);
+    Ok(())
+}
+
+fn walk_level2(
+    frame: Frame,
+    base: usize,
+    page: &mut PageFn,
+    table: &mut dyn FnMut(Frame),
+) -> Result<(), MapError> {
+    let level2 = self::table::<Level2>(frame);
+    for index in 0..ENTRY_COUNT {
+        let addr = base + index * PageSize::Size2MiB.bytes();
+        match level2[index].address() {
+            Some(_) if level2[index].is_huge() => {
+                page(addr, &mut level2[index], PageSize::Size2MiB)?
+            }
+            Some(next) => walk_level1(next, addr, page, table)?,
+            None => {}
+        }
+    }
+    table(frame);
+    Ok(())
+}
+
+fn walk_level1(
+    frame: Frame,
+    base: usize,
+    page: &mut PageFn,
+    table: &mut dyn FnMut(Frame),
+) -> Result<(), MapError> {
+    let level1 = self::table::<Level1>(frame);
+    for index in 0..ENTRY_COUNT {
+        if !level1[index].is_unused() {
+            page(
+                base + index * PAGE_SIZE,
+                &mut level1[index],
+                PageSize::Size4KiB,
+            )?;
+        }
+    }
+    table(frame);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const USER_ADDR: usize = 0x40_0000;
+
+    #[test_case]
+    fn map_inactive() {
+        let mut space = AddressSpace::new();
+        let frame = space
+            .allocate(USER_ADDR, EntryFlag::Writable as u64)
+            .unwrap();
+
+        assert_eq!(space.translate(USER_ADDR + 8), Some(frame.base_addr + 8));
+        assert_eq!(tables::translate_addr(USER_ADDR), None);
+
+        space.unmap(USER_ADDR).unwrap();
+        assert_eq!(space.translate(USER_ADDR), None);
+    }
+
+    #[test_case]
+    fn switch_address_space() {
+        let kernel_space = tables::active_level4();
+        let mut space = AddressSpace::new();
+        let frame = space
+            .allocate(USER_ADDR, EntryFlag::Writable as u64)
+            .unwrap();
+
+        space.activate();
+        assert!(space.is_active());
+        unsafe {
+            *(USER_ADDR as *mut u64) = 0xDEAD_BEEF;
+            tables::set_active_level4(kernel_space);
+        }
+
+        let value = unsafe { *(phys_to_virt(frame.base_addr) as *const u64) };
+        assert_eq!(value, 0xDEAD_BEEF);
+    }
+
+    #[test_case]
+    fn demand_paging() {
+        let kernel_space = tables::active_level4();
+        let mut space = AddressSpace::new();
+        space
+            .reserve(USER_ADDR, 2 * PAGE_SIZE, EntryFlag::Writable as u64)
+            .unwrap();
+        assert_eq!(space.translate(USER_ADDR), None);
+
+        space.activate();
+        unsafe {
+            let page = (USER_ADDR + PAGE_SIZE) as *mut u64;
+            assert_eq!(*page, 0);
+            *page = 42;
+            assert_eq!(*page, 42);
+            tables::set_active_level4(kernel_space);
+        }
+
+        assert_eq!(space.translate(USER_ADDR), None);
+        assert!(space.translate(USER_ADDR + PAGE_SIZE).is_some());
+    }
+
+    #[test_case]
+    fn heap_and_stack_growth() {
+        const STACK_TOP: usize = 0x80_0000;
+        let kernel_space = tables::active_level4();
+        let mut space = AddressSpace::new();
+        let flags = EntryFlag::Writable as u64;
+        space.reserve_heap(USER_ADDR, flags).unwrap();
+        space
+            .reserve_stack(STACK_TOP, PAGE_SIZE, 4 * PAGE_SIZE, flags)
+            .unwrap();
+        assert_eq!(space.brk(USER_ADDR + 2 * PAGE_SIZE), Ok(USER_ADDR));
+
+        space.activate();
+        unsafe {
+            *((USER_ADDR + PAGE_SIZE) as *mut u64) = 42;
+            *((STACK_TOP - 3 * PAGE_SIZE) as *mut u64) = 42;
+            tables::set_active_level4(kernel_space);
+        }
+        assert!(space.translate(USER_ADDR + PAGE_SIZE).is_some());
+        assert!(space.translate(STACK_TOP - 3 * PAGE_SIZE).is_some());
+
+        assert_eq!(space.brk(USER_ADDR), Ok(USER_ADDR + 2 * PAGE_SIZE));
+        assert_eq!(space.translate(USER_ADDR + PAGE_SIZE), None);
+    }
+
+    #[test_case]
+    fn copy_on_write() {
+        let kernel_space = tables::active_level4();
+        let mut parent = AddressSpace::new();
+        let frame = parent
+            .allocate(USER_ADDR, EntryFlag::Writable as u64)
+            .unwrap();
+        let shared = phys_to_virt(frame.base_addr) as *mut u64;
+        unsafe {
+            *shared = 1;
+        }
+
+        let child = parent.clone_cow().unwrap();
+        assert_eq!(child.translate(USER_ADDR), Some(frame.base_addr));
+        assert_eq!(ALLOCATOR.obtain().owners(frame), 2);
+
+        child.activate();
+        unsafe {
+            assert_eq!(*(USER_ADDR as *const u64), 1);
+            *(USER_ADDR as *mut u64) = 2;
+            tables::set_active_level4(kernel_space);
+        }
+
+        let copy = child.translate(USER_ADDR).unwrap();
+        assert_ne!(copy, frame.base_addr);
+        assert_eq!(ALLOCATOR.obtain().owners(frame), 1);
+        unsafe {
+            assert_eq!(*shared, 1);
+            assert_eq!(*(phys_to_virt(copy) as *const u64), 2);
+        }
+    }
+
+    #[test_case]
+    fn drop_frees_frames() {
+        let space = AddressSpace::new();
+        let level4 = space.level4;
+        drop(space);
+
+        let frame = ALLOCATOR.obtain().allocate_frame().unwrap();
+        assert_eq!(frame, level4);
+        ALLOCATOR.obtain().deallocate_frame(frame);
+    }
+}
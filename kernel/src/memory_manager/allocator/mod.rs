@@ -1,5 +1,6 @@
 mod slab;
 use crate::arch::paging::tables::EntryFlag;
+use crate::memory_manager::vmalloc::{vfree, vmalloc};
 use crate::memory_manager::{mmap, munmap, PAGE_SIZE};
 use crate::utils::lazy_static::LazyStatic;
 use core::alloc::{GlobalAlloc, Layout};
@@ -90,10 +91,18 @@ impl Default for Allocator {
 pub struct LockedAllocator(LazyStatic<Allocator>);
 unsafe impl GlobalAlloc for LockedAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // vmalloc allocates its bookkeeping from the heap, the lock must not be held
+        if layout.size() > PAGE_SIZE && layout.align() <= PAGE_SIZE {
+            return vmalloc(layout.size(), EntryFlag::Writable as u64)
+                .map_or(null_mut(), |addr| addr as *mut u8);
+        }
         self.0.obtain().alloc(layout)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if layout.size() > PAGE_SIZE && layout.align() <= PAGE_SIZE {
+            return vfree(ptr as usize);
+        }
         self.0.obtain().dealloc(ptr, layout)
     }
 }
@@ -184,4 +193,12 @@ mod test {
         allocator.dealloc(ptr2 as *mut u8, layout);
         allocator.dealloc(ptr3 as *mut u8, layout);
     }
+
+    #[test_case]
+    fn multiple_pages() {
+        let mut buffer = alloc::vec![0u8; 3 * PAGE_SIZE];
+        buffer[3 * PAGE_SIZE - 1] = 42;
+        assert!(buffer.iter().take(3 * PAGE_SIZE - 1).all(|&byte| byte == 0));
+        assert_eq!(buffer[3 * PAGE_SIZE - 1], 42);
+    }
 }
@@ -1,6 +1,6 @@
 use super::PAGE_SIZE;
 
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub struct Frame {
     pub base_addr: usize,
 }
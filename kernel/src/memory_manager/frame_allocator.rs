@@ -1,63 +1,152 @@
-use crate::arch::paging::memory_map;
-use crate::memory_manager::{frame::Frame, PAGE_SIZE};
+use crate::memory_manager::physical::{self, RegionKind};
+use crate::memory_manager::{frame::Frame, phys_to_virt, PAGE_SIZE};
 use core::cmp::max;
-
-pub const KERNEL_END: usize = 0x200000;
+use core::{ptr, slice};
 
 pub struct FrameAllocator {
-    cur_frame: Frame,
-    cur_region: memory_map::Region,
-    region_index: u32,
+    /// Next frame to hand out in the current RAM region
+    next_frame: usize,
+    region_index: usize,
+    /// Deallocated frames, each one stores the address of the next one
+    free_list: Option<Frame>,
+    /// Owners beyond the first of every frame of RAM, indexed by frame number
+    /// Allocated once a frame is first shared
+    shared: Option<&'static mut [u16]>,
 }
 
 impl FrameAllocator {
-    /// Creates a FrameAllocator that allocates frames located after the end of the kernel
+    /// Creates a FrameAllocator that allocates frames from RAM nothing else claims,
+    /// the kernel and the bootloader included
     pub fn new() -> FrameAllocator {
-        let mut index = 0;
-        while memory_map::get_region(index).end() <= KERNEL_END {
-            index += 1;
-        }
-
-        let start_addr = max(
-            memory_map::get_region(index).base_addr + PAGE_SIZE - 1,
-            KERNEL_END,
-        );
-
         FrameAllocator {
-            cur_frame: Frame::from_address(start_addr),
-            cur_region: memory_map::get_region(index),
-            region_index: index,
+            next_frame: 0,
+            region_index: 0,
+            free_list: None,
+            shared: None,
         }
     }
 
     /// Returns a valid Page sized frame
     pub fn allocate_frame(&mut self) -> Option<Frame> {
-        if self.cur_frame.base_addr + PAGE_SIZE > self.cur_region.end() {
-            if !self.next_region() {
-                return None;
+        if let Some(frame) = self.free_list {
+            // SAFETY: Frames of the free list are unused and store the next free frame
+            let next = unsafe { *(phys_to_virt(frame.base_addr) as *const usize) };
+            // Frame 0 is never handed out as it is below the kernel
+            self.free_list = match next {
+                0 => None,
+                next => Some(Frame::from_address(next)),
+            };
+            return Some(frame);
+        }
+
+        loop {
+            let region = physical::ram_region(self.region_index)?;
+            let frame = max(self.next_frame, region.start + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+
+            if frame + PAGE_SIZE > region.end {
+                self.region_index += 1;
+                self.next_frame = 0;
+                continue;
+            }
+            self.next_frame = frame + PAGE_SIZE;
+
+            if physical::is_free_ram(frame, frame + PAGE_SIZE) {
+                return Some(Frame::from_address(frame));
+            }
+        }
+    }
+
+    /// Returns the first of `count` physically contiguous frames
+    /// They come from RAM never handed out before, the free list is not searched
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<Frame> {
+        loop {
+            let region = physical::ram_region(self.region_index)?;
+            let start = max(self.next_frame, region.start + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+            let end = start + count * PAGE_SIZE;
+
+            if end > region.end {
+                self.keep_free_frames(start, region.end);
+                self.region_index += 1;
+                self.next_frame = 0;
+                continue;
             }
-            self.set_start_frame();
+            if physical::is_free_ram(start, end) {
+                self.next_frame = end;
+                return Some(Frame::from_address(start));
+            }
+
+            // Keep the first frame if only the following ones are in use
+            self.next_frame = start + PAGE_SIZE;
+            self.keep_free_frames(start, start + PAGE_SIZE);
         }
-        let frame = self.cur_frame;
+    }
+
+    /// Adds the free frames of [start, end[ skipped by `allocate_contiguous` to the free list
+    fn keep_free_frames(&mut self, start: usize, end: usize) {
+        for frame in (start..end).step_by(PAGE_SIZE) {
+            if frame + PAGE_SIZE <= end && physical::is_free_ram(frame, frame + PAGE_SIZE) {
+                self.deallocate_frame(Frame::from_address(frame));
+            }
+        }
+    }
 
-        self.cur_frame.base_addr += PAGE_SIZE;
-        Some(frame)
+    /// Adds an owner to `frame`, it is only freed once every owner deallocated it
+    pub fn share(&mut self, frame: Frame) {
+        let count = &mut self.shared_counts()[frame.base_addr / PAGE_SIZE];
+        *count = count.checked_add(1).expect("Too many owners of a frame");
     }
 
-    /// sets the first frame to the start of the current region
-    fn set_start_frame(&mut self) {
-        self.cur_frame = Frame::from_address(self.cur_region.base_addr + PAGE_SIZE - 1);
+    /// Returns the number of owners of `frame`
+    pub fn owners(&self, frame: Frame) -> usize {
+        self.shared
+            .as_ref()
+            .and_then(|shared| shared.get(frame.base_addr / PAGE_SIZE))
+            .map_or(1, |&count| count as usize + 1)
     }
 
-    /// Go to next region, returns false if not possible
-    fn next_region(&mut self) -> bool {
-        self.region_index += 1;
-        if self.region_index == memory_map::region_count() {
-            return false;
+    /// Returns the owner counts, allocating them on first use
+    fn shared_counts(&mut self) -> &mut [u16] {
+        if self.shared.is_none() {
+            let end = physical::regions()
+                .filter(|region| region.kind == RegionKind::Ram)
+                .map(|region| region.end)
+                .max()
+                .unwrap_or(0);
+            let len = end / PAGE_SIZE;
+            let frames = (len * 2 + PAGE_SIZE - 1) / PAGE_SIZE;
+            let frame = self.allocate_contiguous(frames).expect("Out of memory");
+
+            // SAFETY: All physical memory is mapped, the frames are never freed
+            unsafe {
+                let counts = phys_to_virt(frame.base_addr) as *mut u16;
+                ptr::write_bytes(counts, 0, len);
+                self.shared = Some(slice::from_raw_parts_mut(counts, len));
+            }
         }
+        self.shared.as_mut().unwrap()
+    }
 
-        self.cur_region = memory_map::get_region(self.region_index);
-        true
+    /// Removes an owner from `frame`, the last one gives it back to the allocator
+    /// A frame given back must not be used anymore
+    pub fn deallocate_frame(&mut self, frame: Frame) {
+        let shared = self
+            .shared
+            .as_mut()
+            .and_then(|shared| shared.get_mut(frame.base_addr / PAGE_SIZE));
+        if let Some(count) = shared {
+            if *count > 0 {
+                *count -= 1;
+                return;
+            }
+        }
+
+        let next = self.free_list.map_or(0, |frame| frame.base_addr);
+        // SAFETY: The caller does not use the frame anymore,
+        // all physical memory is mapped
+        unsafe {
+            *(phys_to_virt(frame.base_addr) as *mut usize) = next;
+        }
+        self.free_list = Some(frame);
     }
 }
 
@@ -0,0 +1,59 @@
+//! Where the linker and bootloader placed the kernel image
+
+use core::ops::Range;
+
+/// The kernel image and the first 2MiB of physical memory are mapped at this offset
+pub const KERNEL_OFFSET: usize = 0xFFFF_FFFF_8000_0000;
+
+extern "C" {
+    static _kernel_start: usize;
+    static _kernel_end: usize;
+    static _text_start: usize;
+    static _text_end: usize;
+    static _rodata_start: usize;
+    static _rodata_end: usize;
+    static _data_start: usize;
+    static _data_end: usize;
+}
+
+/// Returns the virtual address of the start of the kernel image
+pub fn kernel_start() -> usize {
+    // SAFETY: Symbol is defined by the linker, only its address is used
+    unsafe { &_kernel_start as *const _ as usize }
+}
+
+/// Returns the virtual address right after the kernel image
+pub fn kernel_end() -> usize {
+    // SAFETY: Symbol is defined by the linker, only its address is used
+    unsafe { &_kernel_end as *const _ as usize }
+}
+
+/// Returns the size of the kernel image in bytes
+pub fn kernel_size() -> usize {
+    kernel_end() - kernel_start()
+}
+
+/// Returns the page aligned virtual addresses of the code
+pub fn text() -> Range<usize> {
+    // SAFETY: Symbols are defined by the linker, only their address is used
+    unsafe { &_text_start as *const _ as usize..&_text_end as *const _ as usize }
+}
+
+/// Returns the page aligned virtual addresses of the read-only data
+pub fn rodata() -> Range<usize> {
+    // SAFETY: Symbols are defined by the linker, only their address is used
+    unsafe { &_rodata_start as *const _ as usize..&_rodata_end as *const _ as usize }
+}
+
+/// Returns the page aligned virtual addresses of the writable data, .bss included
+pub fn data() -> Range<usize> {
+    // SAFETY: Symbols are defined by the linker, only their address is used
+    unsafe { &_data_start as *const _ as usize..&_data_end as *const _ as usize }
+}
+
+/// Returns the address of `phys_addr` in the kernel mapping of the memory below the kernel
+/// Unlike the physical memory map, it is available as soon as the kernel starts
+pub fn low_memory(phys_addr: usize) -> usize {
+    assert!(phys_addr < kernel_start() - KERNEL_OFFSET);
+    phys_addr + KERNEL_OFFSET
+}
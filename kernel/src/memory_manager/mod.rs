@@ -1,47 +1,247 @@
+pub mod address_space;
 pub mod allocator;
 pub mod frame;
 pub mod frame_allocator;
+pub mod layout;
+pub mod physical;
+pub mod stack;
+pub mod vma;
+pub mod vmalloc;
 
 pub const PAGE_SIZE: usize = 4096;
 
+/// All physical memory is mapped starting at this address
+pub const PHYS_MAP_OFFSET: usize = 0xFFFF_8000_0000_0000;
+/// Size of the virtual window reserved for the physical memory map
+pub const PHYS_MAP_SIZE: usize = 1 << 45;
+
+/// Software mmap flag, the page is only backed by a frame once accessed
+pub const MAP_LAZY: u64 = 1 << 52;
+/// Software mmap flag, the page can be executed
+pub const MAP_EXECUTABLE: u64 = 1 << 53;
+
+use crate::arch::paging::pat::CacheType;
 use crate::arch::paging::tables;
 use crate::utils::lazy_static::LazyStatic;
+use frame::Frame;
 use frame_allocator::FrameAllocator;
-use tables::EntryFlag;
+use physical::{RegionError, RegionKind};
+use tables::{EntryFlag, MapError, PageSize};
+
+pub(crate) static ALLOCATOR: LazyStatic<FrameAllocator> = LazyStatic::new(FrameAllocator::new);
+
+/// Maps RAM and firmware tables at PHYS_MAP_OFFSET
+/// and prepares the kernel half to be shared by address spaces
+pub fn init() {
+    let flags = EntryFlag::Writable as u64 | EntryFlag::Global as u64 | EntryFlag::NoExecute as u64;
+    let mut allocator = ALLOCATOR.obtain();
+
+    // Neighbouring regions might share a page, they are merged so it is mapped once
+    let mut ranges = [(0, 0); physical::MAX_REGIONS];
+    let mut count = 0;
+    let memory = physical::regions()
+        .filter(|region| matches!(region.kind, RegionKind::Ram | RegionKind::Acpi));
+    for region in memory {
+        let start = region.start / PAGE_SIZE * PAGE_SIZE;
+        let end = (region.end + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        ranges[count] = (start, end);
+        count += 1;
+    }
+    let count = merge_ranges(&mut ranges[..count]);
 
-static ALLOCATOR: LazyStatic<FrameAllocator> = LazyStatic::new(FrameAllocator::new);
+    for &(start, end) in ranges[..count].iter() {
+        // Use the largest pages that fit
+        let mut addr = start;
+        while addr < end {
+            let size = [PageSize::Size1GiB, PageSize::Size2MiB, PageSize::Size4KiB]
+                .iter()
+                .copied()
+                .filter(|&size| size != PageSize::Size1GiB || tables::supports_1gib_pages())
+                .find(|size| addr % size.bytes() == 0 && addr + size.bytes() <= end)
+                .unwrap();
+
+            tables::map_range(
+                phys_to_virt(addr),
+                addr,
+                size.bytes(),
+                size,
+                flags,
+                &mut allocator,
+            )
+            .expect("Could not map physical memory");
+            addr += size.bytes();
+        }
+    }
+
+    // The kernel no longer needs the bootloader's identity mapping,
+    // the lower half is left to user space
+    tables::get_level4()[0].set_unused();
+    tables::flush_all();
+    protect_kernel();
+
+    drop(allocator);
+    address_space::init();
+}
+
+/// Sorts the ranges [start, end[ and merges the ones that overlap or touch
+/// Returns the number of ranges left at the start of `ranges`
+fn merge_ranges(ranges: &mut [(usize, usize)]) -> usize {
+    ranges.sort_unstable();
+    let mut count = 0;
+    for index in 0..ranges.len() {
+        let (start, end) = ranges[index];
+        if count > 0 && start <= ranges[count - 1].1 {
+            ranges[count - 1].1 = ranges[count - 1].1.max(end);
+        } else {
+            ranges[count] = (start, end);
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Replaces the bootloader's writable and executable kernel mapping,
+/// only code is executable and it is read-only
+/// The bootloader's mapping goes on after the kernel, those pages are unmapped
+/// since the frame allocator hands out the frames they map
+fn protect_kernel() {
+    let global = EntryFlag::Global as u64;
+    let writable = EntryFlag::Writable as u64;
+    let no_execute = EntryFlag::NoExecute as u64;
+
+    let sections = [
+        (
+            layout::KERNEL_OFFSET..layout::kernel_start(),
+            writable | no_execute,
+        ),
+        (layout::text(), 0),
+        (layout::rodata(), no_execute),
+        (layout::data(), writable | no_execute),
+    ];
+    for (range, flags) in sections.iter().cloned() {
+        tables::protect(range.start, range.len(), flags | global).expect("Kernel is not mapped");
+    }
+
+    let mut page = layout::kernel_end();
+    while tables::translate_addr(page).is_some() {
+        tables::unmap(page).expect("Kernel mapping changed");
+        page += PAGE_SIZE;
+    }
+}
+
+/// Returns the address `phys_addr` is mapped to in the physical memory map
+pub fn phys_to_virt(phys_addr: usize) -> usize {
+    assert!(phys_addr < PHYS_MAP_SIZE);
+    phys_addr + PHYS_MAP_OFFSET
+}
+
+/// Returns the physical address `virt_addr` points to
+/// Addresses of the physical memory map are translated without walking the page tables
+pub fn virt_to_phys(virt_addr: usize) -> Option<usize> {
+    match virt_addr.checked_sub(PHYS_MAP_OFFSET) {
+        Some(phys_addr) if phys_addr < PHYS_MAP_SIZE => Some(phys_addr),
+        _ => tables::translate_addr(virt_addr),
+    }
+}
 
 /// Map a page of memory
-/// An address is provided if none are given
+/// The page is accessed through the physical memory map if no address is given
+/// Pages mapped with MAP_LAZY at a given address are backed on first access
+/// Pages are not executable unless mapped with MAP_EXECUTABLE at a given address
 /// This syscall is prone to data races
-#[allow(dead_code)]
 pub fn mmap(addr: Option<usize>, flags: u64) -> *mut u8 {
-    let frame = ALLOCATOR.obtain().allocate_frame().expect("Out of memory");
+    let page_flags = page_flags(flags);
 
-    // Identity map if no address is given, use address otherwise
-    let addr = addr.unwrap_or(frame.base_addr);
+    if flags & MAP_LAZY != 0 {
+        let addr = addr.expect("Lazy mappings need an address");
+        address_space::reserve_active(addr, PAGE_SIZE, page_flags).expect("Could not reserve page");
+        return addr as *mut u8;
+    }
 
-    tables::map_to(addr, frame.base_addr, flags, &mut ALLOCATOR.obtain());
+    let frame = ALLOCATOR.obtain().allocate_frame().expect("Out of memory");
+
+    let addr = match addr {
+        Some(addr) => {
+            tables::map_to(addr, frame.base_addr, page_flags, &mut ALLOCATOR.obtain())
+                .expect("Could not map page");
+            addr
+        }
+        None => {
+            assert!(
+                flags & MAP_EXECUTABLE == 0,
+                "The physical memory map is not executable"
+            );
+            phys_to_virt(frame.base_addr)
+        }
+    };
     addr as *mut u8
 }
 
-pub fn munmap(_addr: *mut u8, _length: usize) {}
-
-/// Direct maps virtual address to physical address
-pub fn mmio_map(addr: usize, size: usize) {
-    // TODO make pages unavailable to Frame Allocator
-    // TODO fail if memory already allocated
-    let page_align = |val| val / PAGE_SIZE * PAGE_SIZE;
-    for i in page_align(addr)..page_align(addr + size + PAGE_SIZE - 1) {
-        tables::map_to(
-            i,
-            i,
-            EntryFlag::Writable as u64 + EntryFlag::WriteThrough as u64 + EntryFlag::NoCache as u64,
-            &mut ALLOCATOR.obtain(),
-        );
+/// Converts mmap flags to page flags
+fn page_flags(flags: u64) -> u64 {
+    let flags = match flags & MAP_EXECUTABLE {
+        0 => flags | EntryFlag::NoExecute as u64,
+        _ => flags,
+    };
+    flags & !(MAP_LAZY | MAP_EXECUTABLE)
+}
+
+/// Unmap pages of memory and free their frames
+/// Pages of the physical memory map are only freed
+pub fn munmap(addr: *mut u8, length: usize) {
+    let start = addr as usize / PAGE_SIZE * PAGE_SIZE;
+    let end = addr as usize + length;
+    let released = address_space::release_active(start, end);
+
+    for page in (start..end).step_by(PAGE_SIZE) {
+        let frame = match page.checked_sub(PHYS_MAP_OFFSET) {
+            Some(phys_addr) if phys_addr < PHYS_MAP_SIZE => Frame::from_address(phys_addr),
+            _ => match tables::unmap(page) {
+                Ok((frame, _)) => frame,
+                // Lazy pages that were never accessed have no frame
+                Err(MapError::NotMapped(_)) if released => continue,
+                Err(err) => panic!("Could not unmap page: {:?}", err),
+            },
+        };
+        ALLOCATOR.obtain().deallocate_frame(frame);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmioError {
+    /// The physical range is used by something else
+    Region(RegionError),
+    /// The virtual range could not be mapped
+    Map(MapError),
+}
+
+impl From<RegionError> for MmioError {
+    fn from(err: RegionError) -> Self {
+        MmioError::Region(err)
+    }
+}
+
+impl From<MapError> for MmioError {
+    fn from(err: MapError) -> Self {
+        MmioError::Map(err)
     }
 }
 
+/// Maps device registers located at the physical address `addr` with the caching policy `cache`
+/// Returns the virtual address they are mapped to
+/// The range is reserved so that the frame allocator never hands it out
+pub fn mmio_map(addr: usize, size: usize, cache: CacheType) -> Result<usize, MmioError> {
+    let start = addr / PAGE_SIZE * PAGE_SIZE;
+    let size = (addr + size + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE - start;
+    physical::reserve(start, size, RegionKind::Mmio)?;
+
+    let flags = EntryFlag::Writable as u64;
+    vmalloc::vmap_phys(addr, size - (addr - start), flags, cache).map_err(|err| {
+        physical::release(start, size, RegionKind::Mmio);
+        err.into()
+    })
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -61,9 +261,142 @@ mod test {
         }
     }
 
+    #[test_case]
+    fn merge_shared_pages() {
+        let mut ranges = [
+            (0x3000, 0x5000),
+            (0, 0x1000),
+            (0x1000, 0x2000),
+            (0x4000, 0x9000),
+        ];
+        let count = merge_ranges(&mut ranges);
+        assert_eq!(ranges[..count], [(0, 0x2000), (0x3000, 0x9000)]);
+    }
+
+    #[test_case]
+    fn physical_memory_map() {
+        // The bootloader's signature is still in memory
+        let signature = phys_to_virt(0x7DFE) as *const u16;
+        assert_eq!(unsafe { *signature }, 0xAA55);
+        assert_eq!(virt_to_phys(signature as usize), Some(0x7DFE));
+
+        let page = vmalloc::vmalloc(PAGE_SIZE, EntryFlag::Writable as u64).unwrap();
+        let phys_addr = virt_to_phys(page).unwrap();
+        unsafe {
+            *(page as *mut u8) = 42;
+            assert_eq!(*(phys_to_virt(phys_addr) as *const u8), 42);
+        }
+        vmalloc::vfree(page);
+    }
+
+    #[test_case]
+    fn lazy_allocation() {
+        // Lazy mappings are only available in the lower half
+        let page = mmap(Some(0xCAFE0000), EntryFlag::Writable as u64 | MAP_LAZY);
+        assert_eq!(virt_to_phys(page as usize), None);
+        unsafe {
+            assert_eq!(*page.offset(42), 0);
+            *page.offset(42) = 42;
+            assert_eq!(*page.offset(42), 42);
+        }
+        assert!(virt_to_phys(page as usize).is_some());
+
+        munmap(page, PAGE_SIZE);
+        assert_eq!(virt_to_phys(page as usize), None);
+    }
+
+    /// Returns the flags of the page containing `addr`
+    fn page_flags(addr: usize) -> u64 {
+        tables::mapped_regions()
+            .find(|region| (region.virt_addr..region.virt_addr + region.size).contains(&addr))
+            .expect("Page is not mapped")
+            .flags
+    }
+
+    #[test_case]
+    fn write_xor_execute() {
+        let writable = EntryFlag::Writable as u64;
+        let no_execute = EntryFlag::NoExecute as u64;
+
+        assert_eq!(
+            page_flags(layout::text().start) & (writable | no_execute),
+            0
+        );
+        assert_eq!(
+            page_flags(layout::rodata().start) & (writable | no_execute),
+            no_execute
+        );
+        assert_eq!(
+            page_flags(layout::data().start) & (writable | no_execute),
+            writable | no_execute
+        );
+
+        let addr = vmalloc::reserve(2 * PAGE_SIZE);
+        let page = mmap(Some(addr), writable);
+        assert_eq!(page_flags(page as usize) & no_execute, no_execute);
+        let page = mmap(Some(addr + PAGE_SIZE), writable | MAP_EXECUTABLE);
+        assert_eq!(
+            page_flags(page as usize) & (writable | no_execute),
+            writable
+        );
+        munmap(addr as *mut u8, 2 * PAGE_SIZE);
+        vmalloc::vfree(addr);
+    }
+
+    #[test_case]
+    fn nothing_mapped_after_kernel() {
+        assert!(tables::translate_addr(layout::kernel_end() - 1).is_some());
+        assert_eq!(tables::translate_addr(layout::kernel_end()), None);
+    }
+
+    #[test_case]
+    fn mmio_overlap() {
+        let frame = ALLOCATOR.obtain().allocate_frame().unwrap();
+        assert!(matches!(
+            mmio_map(frame.base_addr, PAGE_SIZE, CacheType::Uncached),
+            Err(MmioError::Region(RegionError::Overlap(_)))
+        ));
+        ALLOCATOR.obtain().deallocate_frame(frame);
+    }
+
+    #[test_case]
+    fn shared_frames() {
+        let frame = ALLOCATOR.obtain().allocate_frame().unwrap();
+        let mut allocator = ALLOCATOR.obtain();
+        allocator.share(frame);
+        allocator.share(frame);
+        assert_eq!(allocator.owners(frame), 3);
+
+        allocator.deallocate_frame(frame);
+        allocator.deallocate_frame(frame);
+        assert_eq!(allocator.owners(frame), 1);
+        allocator.deallocate_frame(frame);
+        assert_eq!(allocator.allocate_frame(), Some(frame));
+        allocator.deallocate_frame(frame);
+    }
+
+    #[test_case]
+    fn contiguous_frames() {
+        let first = ALLOCATOR.obtain().allocate_contiguous(3).unwrap();
+        let next = ALLOCATOR.obtain().allocate_contiguous(1).unwrap();
+        assert!(next.base_addr >= first.base_addr + 3 * PAGE_SIZE);
+        assert!(
+            physical::regions().any(|region| region.kind == RegionKind::Ram
+                && region.start <= first.base_addr
+                && first.base_addr + 3 * PAGE_SIZE <= region.end)
+        );
+
+        let mut allocator = ALLOCATOR.obtain();
+        for index in 0..3 {
+            allocator.deallocate_frame(Frame::from_address(first.base_addr + index * PAGE_SIZE));
+        }
+        allocator.deallocate_frame(next);
+    }
+
     #[test_case]
     fn fixed_allocation() {
-        let page = mmap(Some(0xBEEF0), EntryFlag::Writable as u64);
+        let addr = vmalloc::reserve(PAGE_SIZE);
+        let page = mmap(Some(addr), EntryFlag::Writable as u64);
         unsafe {
             *page.offset(0) = 204;
             *page.offset(423) = 203;
@@ -73,5 +406,7 @@ mod test {
             assert!(*page.offset(423) == 203);
             assert!(*page.offset(4095) == 96);
         }
+        munmap(page, PAGE_SIZE);
+        vmalloc::vfree(addr);
     }
 }
@@ -0,0 +1,195 @@
+//! Registry of the physical address space, it tracks what every range is used for
+//! It does not allocate since the frame allocator relies on it
+
+use super::{layout, PAGE_SIZE};
+use crate::arch::paging::memory_map::{self, RegionType};
+use crate::utils::lazy_static::LazyStatic;
+
+/// Most regions the registry holds
+pub const MAX_REGIONS: usize = 64;
+
+static REGISTRY: LazyStatic<Registry> = LazyStatic::new(Registry::from_memory_map);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Memory the frame allocator can hand out
+    Ram,
+    /// The bootloader, its page tables and the kernel image
+    Kernel,
+    /// Firmware tables
+    Acpi,
+    /// Memory reserved by the firmware or unusable
+    Reserved,
+    /// Device registers
+    Mmio,
+}
+
+/// The physical range [start, end[
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysRegion {
+    pub start: usize,
+    pub end: usize,
+    pub kind: RegionKind,
+}
+
+impl PhysRegion {
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    /// The range overlaps a region in use
+    Overlap(PhysRegion),
+    /// No more regions can be registered
+    Full,
+}
+
+#[derive(Clone, Copy)]
+struct Registry {
+    regions: [PhysRegion; MAX_REGIONS],
+    count: usize,
+}
+
+impl Registry {
+    /// Registers the regions of the bootloader's memory map and the kernel
+    fn from_memory_map() -> Registry {
+        let mut registry = Registry {
+            regions: [PhysRegion {
+                start: 0,
+                end: 0,
+                kind: RegionKind::Reserved,
+            }; MAX_REGIONS],
+            count: 0,
+        };
+
+        for region in memory_map::regions() {
+            let kind = match region.region_type() {
+                RegionType::Usable => RegionKind::Ram,
+                RegionType::AcpiReclaimable | RegionType::AcpiNvs => RegionKind::Acpi,
+                _ => RegionKind::Reserved,
+            };
+            registry
+                .push(region.base_addr, region.end() + 1, kind)
+                .expect("Too many regions in the memory map");
+        }
+
+        let kernel_end = layout::kernel_end() - layout::KERNEL_OFFSET;
+        let kernel_end = (kernel_end + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        registry
+            .push(0, kernel_end, RegionKind::Kernel)
+            .expect("Too many regions in the memory map");
+        registry
+    }
+
+    fn regions(&self) -> &[PhysRegion] {
+        &self.regions[..self.count]
+    }
+
+    fn push(&mut self, start: usize, end: usize, kind: RegionKind) -> Result<(), RegionError> {
+        if self.count == MAX_REGIONS {
+            return Err(RegionError::Full);
+        }
+        self.regions[self.count] = PhysRegion { start, end, kind };
+        self.count += 1;
+        Ok(())
+    }
+}
+
+/// Returns a copy of every registered region, regions may overlap
+pub fn regions() -> impl Iterator<Item = PhysRegion> {
+    let registry = *REGISTRY.obtain();
+    (0..registry.count).map(move |index| registry.regions[index])
+}
+
+/// Returns the RAM region at `index` among the RAM regions, in the memory map's order
+/// The frame allocator calls it for every frame, so the registry is not copied
+pub fn ram_region(index: usize) -> Option<PhysRegion> {
+    REGISTRY
+        .obtain()
+        .regions()
+        .iter()
+        .filter(|region| region.kind == RegionKind::Ram)
+        .nth(index)
+        .copied()
+}
+
+/// Returns true if [start, end[ is RAM that nothing else claims
+pub fn is_free_ram(start: usize, end: usize) -> bool {
+    let registry = REGISTRY.obtain();
+    let regions = registry.regions();
+
+    regions
+        .iter()
+        .any(|region| region.kind == RegionKind::Ram && region.start <= start && end <= region.end)
+        && !regions
+            .iter()
+            .any(|region| region.kind != RegionKind::Ram && region.overlaps(start, end))
+}
+
+/// Registers [start, start + size[ as used by `kind`
+/// Only ranges the firmware reserved or did not report can be claimed
+pub fn reserve(start: usize, size: usize, kind: RegionKind) -> Result<(), RegionError> {
+    let mut registry = REGISTRY.obtain();
+    let end = start + size;
+
+    let overlap = registry
+        .regions()
+        .iter()
+        .find(|region| region.kind != RegionKind::Reserved && region.overlaps(start, end));
+    if let Some(region) = overlap {
+        return Err(RegionError::Overlap(*region));
+    }
+    registry.push(start, end, kind)
+}
+
+/// Unregisters a range registered with `reserve`
+pub fn release(start: usize, size: usize, kind: RegionKind) {
+    let mut registry = REGISTRY.obtain();
+    let region = PhysRegion {
+        start,
+        end: start + size,
+        kind,
+    };
+
+    let index = registry
+        .regions()
+        .iter()
+        .position(|other| *other == region)
+        .expect("Region was never reserved");
+    registry.count -= 1;
+    registry.regions[index] = registry.regions[registry.count];
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory_manager::ALLOCATOR;
+
+    const DEVICE_ADDR: usize = 0xF000_0000_0000;
+
+    #[test_case]
+    fn reserve_release() {
+        reserve(DEVICE_ADDR, PAGE_SIZE, RegionKind::Mmio).unwrap();
+        assert!(matches!(
+            reserve(DEVICE_ADDR + 0x800, PAGE_SIZE, RegionKind::Mmio),
+            Err(RegionError::Overlap(PhysRegion {
+                kind: RegionKind::Mmio,
+                ..
+            }))
+        ));
+        release(DEVICE_ADDR, PAGE_SIZE, RegionKind::Mmio);
+        reserve(DEVICE_ADDR, PAGE_SIZE, RegionKind::Mmio).unwrap();
+        release(DEVICE_ADDR, PAGE_SIZE, RegionKind::Mmio);
+    }
+
+    #[test_case]
+    fn ram_is_not_claimable() {
+        let frame = ALLOCATOR.obtain().allocate_frame().unwrap();
+        assert!(is_free_ram(frame.base_addr, frame.base_addr + PAGE_SIZE));
+        assert!(reserve(frame.base_addr, PAGE_SIZE, RegionKind::Mmio).is_err());
+        assert!(!is_free_ram(0x100000, 0x101000));
+        ALLOCATOR.obtain().deallocate_frame(frame);
+    }
+}
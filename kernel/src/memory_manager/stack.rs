@@ -0,0 +1,116 @@
+//! Kernel stacks, each one sits right above an unmapped guard page
+//! so that overflowing a stack faults instead of corrupting memory
+
+use super::{ALLOCATOR, PAGE_SIZE};
+use crate::arch::paging::tables::{self, EntryFlag};
+use crate::utils::lazy_static::LazyStatic;
+use alloc::vec::Vec;
+
+/// Kernel stacks are allocated in this window of the kernel half
+const STACKS_START: usize = 0xFFFF_FE80_0000_0000;
+const STACKS_END: usize = STACKS_START + (1 << 39);
+
+pub const STACK_PAGES: usize = 16;
+const STACK_SIZE: usize = STACK_PAGES * PAGE_SIZE;
+/// A stack and its guard page
+const SLOT_SIZE: usize = STACK_SIZE + PAGE_SIZE;
+
+static SLOTS: LazyStatic<Slots> = LazyStatic::new(Slots::new);
+
+/// Slots of the window that are not used by a stack
+struct Slots {
+    next: usize,
+    free: Vec<usize>,
+}
+
+impl Slots {
+    fn new() -> Slots {
+        Slots {
+            next: STACKS_START,
+            free: Vec::new(),
+        }
+    }
+
+    fn allocate(&mut self) -> usize {
+        self.free.pop().unwrap_or_else(|| {
+            assert!(self.next + SLOT_SIZE <= STACKS_END, "Out of kernel stacks");
+            self.next += SLOT_SIZE;
+            self.next - SLOT_SIZE
+        })
+    }
+}
+
+pub struct KernelStack {
+    /// Lowest address of the stack, the guard page sits right below
+    bottom: usize,
+}
+
+impl KernelStack {
+    /// Allocates and maps a stack of STACK_PAGES pages
+    /// Only the bootloader's mappings are needed, so it works before `arch::init`
+    pub fn new() -> KernelStack {
+        let bottom = SLOTS.obtain().allocate() + PAGE_SIZE;
+        let flags =
+            EntryFlag::Writable as u64 | EntryFlag::Global as u64 | EntryFlag::NoExecute as u64;
+        let mut allocator = ALLOCATOR.obtain();
+
+        for page in (bottom..bottom + STACK_SIZE).step_by(PAGE_SIZE) {
+            let frame = allocator.allocate_frame().expect("Out of memory");
+            tables::map_to(page, frame.base_addr, flags, &mut allocator)
+                .expect("Kernel stack already mapped");
+        }
+        KernelStack { bottom }
+    }
+
+    /// Returns the address the stack grows down from
+    pub fn top(&self) -> usize {
+        self.bottom + STACK_SIZE
+    }
+}
+
+impl Default for KernelStack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        for page in (self.bottom..self.top()).step_by(PAGE_SIZE) {
+            let (frame, _) = tables::unmap(page).expect("Kernel stack was unmapped");
+            ALLOCATOR.obtain().deallocate_frame(frame);
+        }
+        SLOTS.obtain().free.push(self.bottom - PAGE_SIZE);
+    }
+}
+
+/// Returns true if `addr` lies in the guard page of a kernel stack
+pub fn is_guard_page(addr: usize) -> bool {
+    (STACKS_START..STACKS_END).contains(&addr) && (addr - STACKS_START) % SLOT_SIZE < PAGE_SIZE
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn guard_page() {
+        let stack = KernelStack::new();
+        let top = stack.top();
+        let bottom = top - STACK_SIZE;
+
+        unsafe {
+            *((top - 8) as *mut u64) = 42;
+            *(bottom as *mut u64) = 42;
+        }
+        assert!(tables::translate_addr(bottom).is_some());
+        assert_eq!(tables::translate_addr(bottom - 1), None);
+        assert!(is_guard_page(bottom - 1));
+        assert!(!is_guard_page(bottom));
+        assert!(!is_guard_page(top - 1));
+
+        drop(stack);
+        assert_eq!(tables::translate_addr(bottom), None);
+        assert_eq!(KernelStack::new().top(), top);
+    }
+}
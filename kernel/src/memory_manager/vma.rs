@@ -0,0 +1,206 @@
+//! Virtual memory areas are ranges of an address space reserved for later use
+//! Their pages are only backed by frames once accessed
+
+use super::PAGE_SIZE;
+use crate::arch::paging::tables::MapError;
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    /// Flags of the pages mapped inside the area
+    pub flags: u64,
+    /// Lowest address the area can grow down to, `start` if it cannot grow
+    pub limit: usize,
+}
+
+impl Vma {
+    pub fn new(start: usize, size: usize, flags: u64) -> Vma {
+        Vma {
+            start,
+            end: start + size,
+            flags,
+            limit: start,
+        }
+    }
+
+    /// Creates a stack area of `size` bytes below `top`,
+    /// accesses right below it grow it down to at most `max_size` bytes
+    pub fn stack(top: usize, size: usize, max_size: usize, flags: u64) -> Vma {
+        Vma {
+            start: top - size,
+            end: top,
+            flags,
+            limit: top - max_size,
+        }
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// Areas also overlap when one would grow into the other
+    /// Empty areas still reserve their start, the heap stays the only area there until it grows
+    fn overlaps(&self, other: &Vma) -> bool {
+        let end = |area: &Vma| area.end.max(area.start + 1);
+        self.limit < end(other) && other.limit < end(self)
+    }
+}
+
+/// Areas of an address space, sorted by address
+#[derive(Default, Clone)]
+pub struct Areas {
+    areas: Vec<Vma>,
+    /// Start of the heap area
+    heap: Option<usize>,
+}
+
+impl Areas {
+    /// Adds `vma`, failing if it overlaps an existing area
+    pub fn insert(&mut self, vma: Vma) -> Result<(), MapError> {
+        if let Some(area) = self.areas.iter().find(|area| area.overlaps(&vma)) {
+            return Err(MapError::AlreadyMapped(area.limit.max(vma.limit)));
+        }
+        let index = self.areas.partition_point(|area| area.start < vma.start);
+        self.areas.insert(index, vma);
+        Ok(())
+    }
+
+    /// Returns the area containing `addr`
+    pub fn find(&self, addr: usize) -> Option<&Vma> {
+        self.areas.iter().find(|area| area.contains(addr))
+    }
+
+    /// Returns the area containing `addr`, the area right above grows down to it if it can
+    pub fn find_or_grow(&mut self, addr: usize) -> Option<&Vma> {
+        let index = self.areas.partition_point(|area| area.end <= addr);
+        let area = self.areas.get_mut(index)?;
+        if addr < area.start {
+            let page = addr / PAGE_SIZE * PAGE_SIZE;
+            if page < area.limit {
+                return None;
+            }
+            area.start = page;
+        }
+        Some(area)
+    }
+
+    /// Adds the heap area, empty until resized
+    pub fn insert_heap(&mut self, start: usize, flags: u64) -> Result<(), MapError> {
+        if self.heap.is_some() {
+            return Err(MapError::AlreadyMapped(start));
+        }
+        self.insert(Vma::new(start, 0, flags))?;
+        self.heap = Some(start);
+        Ok(())
+    }
+
+    /// Moves the end of the heap area to `end`, failing if it would overlap another area
+    /// Returns the previous end
+    pub fn resize_heap(&mut self, end: usize) -> Result<usize, MapError> {
+        let start = self.heap.ok_or(MapError::NotMapped(end))?;
+        let index = self.areas.partition_point(|area| area.start < start);
+        if end < start {
+            return Err(MapError::NotMapped(end));
+        }
+        if let Some(next) = self.areas[index + 1..].first() {
+            if next.limit < end {
+                return Err(MapError::AlreadyMapped(next.limit));
+            }
+        }
+
+        let heap = &mut self.areas[index];
+        let previous = heap.end;
+        heap.end = end;
+        Ok(previous)
+    }
+
+    /// Removes the areas located between `start` and `end`
+    /// Returns true if any area was removed
+    pub fn remove(&mut self, start: usize, end: usize) -> bool {
+        let count = self.areas.len();
+        let mut heap = self.heap;
+        self.areas.retain(|area| {
+            let removed = start <= area.start && area.end <= end;
+            if removed && heap == Some(area.start) {
+                heap = None;
+            }
+            !removed
+        });
+        self.heap = heap;
+        self.areas.len() != count
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn insert_find_remove() {
+        let mut areas = Areas::default();
+        areas.insert(Vma::new(0x3000, 0x1000, 0)).unwrap();
+        areas.insert(Vma::new(0x1000, 0x1000, 0)).unwrap();
+        assert_eq!(
+            areas.insert(Vma::new(0x1800, 0x2000, 0)),
+            Err(MapError::AlreadyMapped(0x1800))
+        );
+
+        assert_eq!(areas.find(0x3FFF).map(|area| area.start), Some(0x3000));
+        assert_eq!(areas.find(0x2000), None);
+        assert_eq!(areas.iter().next().map(|area| area.start), Some(0x1000));
+
+        assert!(areas.remove(0x3000, 0x4000));
+        assert!(!areas.remove(0x1000, 0x1800));
+        assert_eq!(areas.find(0x3000), None);
+    }
+
+    #[test_case]
+    fn grow_stack_and_heap() {
+        let mut areas = Areas::default();
+        areas.insert(Vma::stack(0x8000, 0x1000, 0x4000, 0)).unwrap();
+        assert_eq!(
+            areas.insert(Vma::new(0x4000, 0x1000, 0)),
+            Err(MapError::AlreadyMapped(0x4000))
+        );
+
+        assert_eq!(areas.find(0x5008), None);
+        assert_eq!(
+            areas.find_or_grow(0x5008).map(|area| area.start),
+            Some(0x5000)
+        );
+        assert_eq!(areas.find(0x5008).map(|area| area.start), Some(0x5000));
+        assert_eq!(areas.find_or_grow(0x3FFF), None);
+
+        areas.insert_heap(0x1000, 0).unwrap();
+        assert_eq!(areas.resize_heap(0x3000), Ok(0x1000));
+        assert_eq!(areas.find(0x2FFF).map(|area| area.start), Some(0x1000));
+        assert_eq!(
+            areas.resize_heap(0x5000),
+            Err(MapError::AlreadyMapped(0x4000))
+        );
+        assert_eq!(areas.resize_heap(0x2000), Ok(0x3000));
+    }
+
+    #[test_case]
+    fn area_at_empty_heap() {
+        let mut areas = Areas::default();
+        areas.insert_heap(0x1000, 0).unwrap();
+        assert_eq!(
+            areas.insert(Vma::new(0x1000, 0x1000, 0)),
+            Err(MapError::AlreadyMapped(0x1000))
+        );
+        areas.insert(Vma::new(0, 0x1000, 0)).unwrap();
+        areas.insert(Vma::new(0x3000, 0x1000, 0)).unwrap();
+
+        assert_eq!(areas.resize_heap(0x2000), Ok(0x1000));
+        assert_eq!(areas.find(0x1800).map(|area| area.start), Some(0x1000));
+        assert_eq!(areas.find(0x800).map(|area| area.end), Some(0x1000));
+        assert_eq!(areas.find(0x3000).map(|area| area.end), Some(0x4000));
+    }
+}
@@ -0,0 +1,244 @@
+//! Kernel virtual memory allocator, it hands out ranges of a window of the kernel half
+//! Every range is followed by an unmapped guard page
+
+use super::{page_flags, ALLOCATOR, PAGE_SIZE};
+use crate::arch::paging::pat::{self, CacheType};
+use crate::arch::paging::tables::{self, MapError};
+use crate::utils::lazy_static::LazyStatic;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::mem;
+use core::ops::Range;
+
+const VMALLOC_START: usize = 0xFFFF_C000_0000_0000;
+const VMALLOC_END: usize = VMALLOC_START + (1 << 39);
+
+/// Most free ranges tracked, their storage fits in a page so growing it never re-enters vmalloc
+const MAX_FREE_RANGES: usize = PAGE_SIZE / mem::size_of::<Range<usize>>();
+
+static RANGES: LazyStatic<Ranges> = LazyStatic::new(Ranges::new);
+
+/// What the pages of a range are mapped to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backing {
+    /// Pages are mapped by the caller
+    Reserved,
+    /// Frames owned by the range
+    Frames,
+    /// Physical memory owned by someone else
+    Physical,
+}
+
+struct Allocation {
+    size: usize,
+    backing: Backing,
+}
+
+/// The heap serves allocations larger than a page with vmalloc while it holds the lock of
+/// `RANGES`, so its storage must never need more than a page
+struct Ranges {
+    /// Free ranges, sorted by address, allocated once with room for `MAX_FREE_RANGES`
+    free: Vec<Range<usize>>,
+    /// Allocated ranges, indexed by their start
+    used: BTreeMap<usize, Allocation>,
+}
+
+impl Ranges {
+    fn new() -> Ranges {
+        let mut free = Vec::with_capacity(MAX_FREE_RANGES);
+        free.push(VMALLOC_START..VMALLOC_END);
+        Ranges {
+            free,
+            used: BTreeMap::new(),
+        }
+    }
+
+    fn allocate(&mut self, size: usize, backing: Backing) -> usize {
+        let needed = size + PAGE_SIZE;
+        let index = self
+            .free
+            .iter()
+            .position(|range| range.len() >= needed)
+            .expect("Out of kernel virtual memory");
+
+        let start = self.free[index].start;
+        self.free[index].start += needed;
+        if self.free[index].is_empty() {
+            self.free.remove(index);
+        }
+        self.used.insert(start, Allocation { size, backing });
+        start
+    }
+
+    /// Returns the allocation containing `addr` to the free ranges
+    fn deallocate(&mut self, addr: usize) -> (usize, Allocation) {
+        let start = match self.used.range(..=addr).next_back() {
+            Some((&start, allocation)) if addr < start + allocation.size => start,
+            _ => panic!("{:#x} was not allocated by vmalloc", addr),
+        };
+        let allocation = self.used.remove(&start).unwrap();
+
+        let end = start + allocation.size + PAGE_SIZE;
+        let index = self.free.partition_point(|range| range.start < start);
+        let joins_previous = index > 0 && self.free[index - 1].end == start;
+        let joins_next = index < self.free.len() && self.free[index].start == end;
+        match (joins_previous, joins_next) {
+            (true, true) => self.free[index - 1].end = self.free.remove(index).end,
+            (true, false) => self.free[index - 1].end = end,
+            (false, true) => self.free[index].start = start,
+            // The range is leaked rather than growing the storage, the window is huge
+            (false, false) if self.free.len() == MAX_FREE_RANGES => {}
+            (false, false) => self.free.insert(index, start..end),
+        }
+        (start, allocation)
+    }
+}
+
+fn page_align(size: usize) -> usize {
+    (size + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+}
+
+/// Reserves `size` bytes of kernel virtual memory, the caller maps them
+pub fn reserve(size: usize) -> usize {
+    RANGES
+        .obtain()
+        .allocate(page_align(size), Backing::Reserved)
+}
+
+/// Maps `size` bytes of kernel virtual memory to fresh frames
+/// `flags` are the same as mmap's
+pub fn vmalloc(size: usize, flags: u64) -> Result<usize, MapError> {
+    let size = page_align(size);
+    let start = RANGES.obtain().allocate(size, Backing::Frames);
+    let flags = page_flags(flags);
+
+    for page in (start..start + size).step_by(PAGE_SIZE) {
+        if let Err(err) = map_frame(page, flags) {
+            vfree(start);
+            return Err(err);
+        }
+    }
+    Ok(start)
+}
+
+/// Maps `page` to a fresh frame
+fn map_frame(page: usize, flags: u64) -> Result<(), MapError> {
+    let mut allocator = ALLOCATOR.obtain();
+    let frame = allocator.allocate_frame().ok_or(MapError::OutOfFrames)?;
+
+    let res = tables::map_to(page, frame.base_addr, flags, &mut allocator);
+    if res.is_err() {
+        allocator.deallocate_frame(frame);
+    }
+    res
+}
+
+/// Maps `size` bytes of physical memory starting at `phys_addr` to kernel virtual memory
+/// `flags` are the same as mmap's, `cache` replaces their caching attributes
+pub fn vmap_phys(
+    phys_addr: usize,
+    size: usize,
+    flags: u64,
+    cache: CacheType,
+) -> Result<usize, MapError> {
+    let phys_start = phys_addr / PAGE_SIZE * PAGE_SIZE;
+    let size = page_align(phys_addr + size) - phys_start;
+    let start = RANGES.obtain().allocate(size, Backing::Physical);
+
+    let res = tables::map_range(
+        start,
+        phys_start,
+        size,
+        tables::PageSize::Size4KiB,
+        page_flags(flags) & !pat::CACHE_FLAGS_4KIB | cache.flags(),
+        &mut ALLOCATOR.obtain(),
+    );
+    if let Err(err) = res {
+        RANGES.obtain().deallocate(start);
+        return Err(err);
+    }
+    Ok(start + phys_addr - phys_start)
+}
+
+/// Unmaps the range containing `addr`, its frames are freed if vmalloc allocated them
+pub fn vfree(addr: usize) {
+    let (start, allocation) = RANGES.obtain().deallocate(addr);
+
+    for page in (start..start + allocation.size).step_by(PAGE_SIZE) {
+        match tables::unmap(page) {
+            Ok((frame, _)) if allocation.backing == Backing::Frames => {
+                ALLOCATOR.obtain().deallocate_frame(frame)
+            }
+            Ok(_) | Err(MapError::NotMapped(_)) => {}
+            Err(err) => panic!("Could not unmap page: {:?}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::arch::paging::tables::EntryFlag;
+
+    #[test_case]
+    fn allocate_free() {
+        let first = vmalloc(PAGE_SIZE, EntryFlag::Writable as u64).unwrap();
+        let second = vmalloc(3 * PAGE_SIZE, EntryFlag::Writable as u64).unwrap();
+        assert_eq!(second, first + 2 * PAGE_SIZE);
+        assert_eq!(tables::translate_addr(first + PAGE_SIZE), None);
+
+        unsafe {
+            *((second + 3 * PAGE_SIZE - 1) as *mut u8) = 42;
+        }
+        vfree(second + PAGE_SIZE);
+        assert_eq!(tables::translate_addr(second), None);
+        vfree(first);
+        assert_eq!(vmalloc(PAGE_SIZE, 0).unwrap(), first);
+        vfree(first);
+    }
+
+    #[test_case]
+    fn many_free_ranges() {
+        // Freeing every other range leaves more holes than the free list holds
+        let ranges: Vec<usize> = (0..2 * MAX_FREE_RANGES)
+            .map(|_| reserve(PAGE_SIZE))
+            .collect();
+        for &range in ranges.iter().step_by(2) {
+            vfree(range);
+        }
+        assert!(RANGES.obtain().free.len() <= MAX_FREE_RANGES);
+
+        let buffer = alloc::vec![1u8; 2 * PAGE_SIZE];
+        assert_eq!(buffer[2 * PAGE_SIZE - 1], 1);
+        for &range in ranges.iter().skip(1).step_by(2) {
+            vfree(range);
+        }
+    }
+
+    #[test_case]
+    fn map_physical() {
+        // The bootloader's signature is still in memory
+        let signature = vmap_phys(0x7DFE, 2, 0, CacheType::WriteBack).unwrap();
+        assert_eq!(signature % PAGE_SIZE, 0xDFE);
+        assert_eq!(unsafe { *(signature as *const u16) }, 0xAA55);
+        vfree(signature);
+        assert_eq!(tables::translate_addr(signature), None);
+    }
+
+    #[test_case]
+    fn write_combining() {
+        // MMIO registers of the I/O APIC, the kernel uses the PIC so nothing else maps them
+        const IO_APIC: usize = 0xFEC0_0000;
+        let addr = vmap_phys(IO_APIC, PAGE_SIZE, 0, CacheType::WriteCombining).unwrap();
+        let flags = tables::mapped_regions()
+            .find(|region| region.virt_addr == addr)
+            .unwrap()
+            .flags;
+        assert_eq!(
+            flags & CacheType::WriteCombining.flags(),
+            CacheType::WriteCombining.flags()
+        );
+        assert_eq!(tables::translate_addr(addr), Some(IO_APIC));
+        vfree(addr);
+    }
+}
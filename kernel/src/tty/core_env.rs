@@ -1,6 +1,6 @@
 use super::env::RcEnv;
 use super::types::MalType;
-use crate::file_system::{read_dir, File};
+use crate::file_system::{self, read_dir, File};
 use crate::{exit_qemu, println, QemuExitCode};
 use alloc::rc::Rc;
 use alloc::string::String;
@@ -142,9 +142,12 @@ fn init_builtins(env: &RcEnv) {
             ".listFiles",
             MalType::new_builtin(list_files, &["file"], env),
         ),
+        ("mkdir", MalType::new_builtin(mkdir, &["dirname"], env)),
+        ("rmdir", MalType::new_builtin(rmdir, &["dirname"], env)),
         // Misc
         ("eval", MalType::new_builtin(eval, &["exp"], env)),
         ("shutdown", MalType::new_builtin(shutdown, &[], env)),
+        ("sync", MalType::new_builtin(sync, &[], env)),
     ];
 
     let mut env_mut = env.borrow_mut();
@@ -353,9 +356,15 @@ fn core_nth(env: &RcEnv) -> MalType {
 }
 
 fn shutdown(_: &RcEnv) -> MalType {
+    file_system::sync().expect("Could not sync the file system");
     exit_qemu(QemuExitCode::Success)
 }
 
+fn sync(_: &RcEnv) -> MalType {
+    file_system::sync().expect("Could not sync the file system");
+    MalType::Nil
+}
+
 fn read_string(env: &RcEnv) -> MalType {
     if let MalType::String(str) = get_arg(env, "a") {
         super::read_str(&str)
@@ -434,12 +443,31 @@ fn file(env: &RcEnv) -> MalType {
     }
 }
 
+fn mkdir(env: &RcEnv) -> MalType {
+    if let MalType::String(dirname) = get_arg(env, "dirname") {
+        file_system::mkdir(&dirname).expect("Could not create directory");
+        MalType::Nil
+    } else {
+        panic!("mkdir: Expected a string argument");
+    }
+}
+
+fn rmdir(env: &RcEnv) -> MalType {
+    if let MalType::String(dirname) = get_arg(env, "dirname") {
+        file_system::rmdir(&dirname).expect("Could not remove directory");
+        MalType::Nil
+    } else {
+        panic!("rmdir: Expected a string argument");
+    }
+}
+
 fn list_files(env: &RcEnv) -> MalType {
     if let MalType::File(file) = get_arg(env, "file") {
-        let entries = read_dir(file.borrow().get_path()).unwrap();
+        let files = read_dir(file.borrow().get_path()).unwrap();
         MalType::List(
-            entries
-                .map(|entry| MalType::File(Rc::new(RefCell::new(File::new(entry)))))
+            files
+                .into_iter()
+                .map(|file| MalType::File(Rc::new(RefCell::new(file))))
                 .collect(),
         )
     } else {
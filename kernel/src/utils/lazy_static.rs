@@ -27,6 +27,20 @@ impl<T, F> LazyStatic<T, F> {
 impl<T, F: FnOnce() -> T> LazyStatic<T, F> {
     pub fn obtain(&self) -> LazyGuard<T> {
         self.lock.obtain();
+        self.guard()
+    }
+
+    /// Returns None instead of spinning if the lock is held
+    pub fn try_obtain(&self) -> Option<LazyGuard<T>> {
+        if self.lock.try_obtain() {
+            Some(self.guard())
+        } else {
+            None
+        }
+    }
+
+    /// Builds the value if needed, the lock must be held
+    fn guard(&self) -> LazyGuard<T> {
         if let Some(f) = replace(unsafe { &mut *self.builder.get() }, None) {
             unsafe {
                 (*self.data.get()).as_mut_ptr().write((f)());
@@ -72,4 +86,13 @@ mod test {
         assert!(lazy.obtain().eq(&10));
         assert!(lazy.obtain().eq(&10));
     }
+
+    #[test_case]
+    fn try_lock() {
+        let lazy: LazyStatic<u32> = LazyStatic::new(|| 10);
+        let guard = lazy.try_obtain().unwrap();
+        assert!(lazy.try_obtain().is_none());
+        drop(guard);
+        assert!(lazy.try_obtain().unwrap().eq(&10));
+    }
 }
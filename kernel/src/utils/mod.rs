@@ -2,4 +2,5 @@
 
 pub mod lazy_static;
 pub mod libc;
+pub mod mutex;
 pub mod spinlock;
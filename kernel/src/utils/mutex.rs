@@ -0,0 +1,66 @@
+use super::spinlock::Spinlock;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+/// Data only accessible while holding its lock
+pub struct Mutex<T> {
+    lock: Spinlock,
+    data: UnsafeCell<T>,
+}
+
+pub struct MutexGuard<'a, T> {
+    data: &'a mut T,
+    lock: &'a Spinlock,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Mutex<T> {
+        Mutex {
+            lock: Spinlock::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        self.lock.obtain();
+        MutexGuard {
+            // SAFETY: The lock is held until the guard is dropped
+            data: unsafe { &mut *self.data.get() },
+            lock: &self.lock,
+        }
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &*self.data
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut *self.data
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.release();
+    }
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn lock_unlock() {
+        let mutex = Mutex::new(10);
+        *mutex.lock() += 1;
+        assert_eq!(*mutex.lock(), 11);
+    }
+}
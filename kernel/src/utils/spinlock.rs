@@ -9,9 +9,7 @@ impl Spinlock {
 
     #[allow(dead_code)]
     pub fn once(&self) -> bool {
-        self.0
-            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
-            .is_ok()
+        self.try_obtain()
     }
 
     pub fn obtain(&self) {
@@ -22,6 +20,13 @@ impl Spinlock {
         {}
     }
 
+    /// Obtains the lock only if it is free, returns true if it was obtained
+    pub fn try_obtain(&self) -> bool {
+        self.0
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
     pub fn release(&self) {
         self.0.store(false, Ordering::SeqCst);
     }
@@ -0,0 +1,52 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+extern crate kernel;
+
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use kernel::driver::ahci::{self, AhciDisk};
+use kernel::file_system::File;
+use kernel::*;
+
+/// kernel_runner boots a q35 machine and attaches a blank 1MiB disk after the boot disk
+const MIB_SECTORS: u64 = 2048;
+
+#[no_mangle]
+#[link_section = ".kernel_start"]
+extern "C" fn _start() -> ! {
+    arch::init();
+
+    // Without an IDE controller, both disks are on AHCI ports
+    assert_eq!(ata::drives().count(), 0);
+    let disks: Vec<AhciDisk> = ahci::disks().collect();
+    assert_eq!(disks.len(), 2);
+    assert_eq!(disks[1].identity().sectors, MIB_SECTORS);
+
+    let mut buffer = [0; 512];
+    disks[0].read_sectors(0, 1, &mut buffer).unwrap();
+    assert_eq!(buffer[510..], [0x55, 0xAA]);
+
+    // Unaligned buffers go through a bounce buffer
+    let expected: Vec<u8> = (0..3 * 512 + 1).map(|val| val as u8).collect();
+    disks[1].write_sectors(5, 3, &expected[1..]).unwrap();
+    let mut actual = alloc::vec![0; 3 * 512 + 1];
+    disks[1].read_sectors(5, 3, &mut actual[1..]).unwrap();
+    assert_eq!(actual[1..], expected[1..]);
+
+    // The file system is mounted from the AHCI boot disk
+    let mut file = File::create("ahci").unwrap();
+    assert_eq!(file.write(b"sata"), Some(4));
+
+    serial_println!("ahci: [OK]");
+    exit_qemu(QemuExitCode::Success)
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("ahci: [KO]");
+    exit_qemu(QemuExitCode::Failure)
+}
@@ -0,0 +1,57 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+extern crate kernel;
+
+use core::panic::PanicInfo;
+use kernel::arch::interrupt::{self, TIMER_FREQUENCY};
+use kernel::ata::{self, AtaDrive, Bus, Drive};
+use kernel::*;
+
+/// kernel_runner attaches a blank 64MiB disk as primary slave
+const DISK: AtaDrive = AtaDrive::new(Bus::Primary, Drive::Slave);
+/// Sectors read by each command, 1MiB
+const SECTORS: usize = 2048;
+
+#[no_mangle]
+#[link_section = ".kernel_start"]
+extern "C" fn _start() -> ! {
+    arch::init();
+
+    let dma = read_disk();
+    ata::set_dma_enabled(false);
+    let pio = read_disk();
+    ata::set_dma_enabled(true);
+
+    let size = DISK.get_storage() / 2048;
+    serial_println!(
+        "ata_benchmark: {}MiB in {} ticks with DMA, {} ticks with PIO ({} ticks per second)",
+        size,
+        dma,
+        pio,
+        TIMER_FREQUENCY
+    );
+    assert!(dma <= pio);
+
+    serial_println!("ata_benchmark: [OK]");
+    exit_qemu(QemuExitCode::Success)
+}
+
+/// Reads the whole disk, returns the number of ticks it took
+fn read_disk() -> u64 {
+    let mut buffer = alloc::vec![0; SECTORS * 512];
+    let start = interrupt::ticks();
+    for lba in (0..DISK.get_storage() as usize).step_by(SECTORS) {
+        DISK.read_sectors(lba, SECTORS, &mut buffer).unwrap();
+    }
+    interrupt::ticks() - start
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("ata_benchmark: [KO]");
+    exit_qemu(QemuExitCode::Failure)
+}
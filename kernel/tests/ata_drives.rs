@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+
+extern crate kernel;
+
+use core::panic::PanicInfo;
+use kernel::ata::{self, AtaDrive};
+use kernel::*;
+
+/// kernel_runner attaches a blank disk of `index` MiB after the boot disk
+const MIB_SECTORS: u64 = 2048;
+
+#[no_mangle]
+#[link_section = ".kernel_start"]
+extern "C" fn _start() -> ! {
+    arch::init();
+
+    // Every position holds a drive
+    assert_eq!(ata::drives().count(), 4);
+    for (index, drive) in AtaDrive::ALL.iter().enumerate().skip(1) {
+        assert_eq!(drive.get_storage(), index as u64 * MIB_SECTORS);
+    }
+
+    // Write a different value on each blank disk, then check they did not overwrite each other
+    for (index, drive) in AtaDrive::ALL.iter().enumerate().skip(1) {
+        drive.write_sectors(1, 1, &[index as u8; 512]).unwrap();
+    }
+    for (index, drive) in AtaDrive::ALL.iter().enumerate().skip(1) {
+        let mut buffer = [0; 512];
+        drive.read_sectors(1, 1, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|&byte| byte == index as u8));
+    }
+
+    // The boot disk was left untouched
+    let mut buffer = [0; 512];
+    AtaDrive::ALL[0].read_sectors(0, 1, &mut buffer).unwrap();
+    assert_eq!(buffer[510..], [0x55, 0xAA]);
+
+    serial_println!("ata_drives: [OK]");
+    exit_qemu(QemuExitCode::Success)
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("ata_drives: [KO]");
+    exit_qemu(QemuExitCode::Failure)
+}
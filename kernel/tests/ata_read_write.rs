@@ -6,8 +6,11 @@
 extern crate kernel;
 
 use core::panic::PanicInfo;
+use kernel::ata::{AtaDrive, Bus, Drive};
 use kernel::*;
 
+const DISK: AtaDrive = AtaDrive::new(Bus::Primary, Drive::Master);
+
 #[no_mangle]
 #[link_section = ".kernel_start"]
 extern "C" fn _start() -> ! {
@@ -17,7 +20,7 @@ extern "C" fn _start() -> ! {
     let mut buffer = [0u8; 2048];
 
     // Read 4 sectors and check if first contains bootloader
-    ata::read_sectors(0, 4, &mut buffer);
+    DISK.read_sectors(0, 4, &mut buffer).unwrap();
     assert_eq!(buffer[510], 0x55);
     assert_eq!(buffer[511], 0xAA);
 
@@ -39,11 +42,11 @@ extern "C" fn _start() -> ! {
 
 fn write_and_check(expected: &[u8], start_sector: usize, default_value: u8) {
     // Write buffer
-    ata::write_sectors(start_sector, 1, &expected);
+    DISK.write_sectors(start_sector, 1, expected).unwrap();
 
     // Read what was written
     let mut actual = [default_value; 512];
-    ata::read_sectors(start_sector, 1, &mut actual);
+    DISK.read_sectors(start_sector, 1, &mut actual).unwrap();
 
     // Check if both buffer are the same
     for i in 0..512 {
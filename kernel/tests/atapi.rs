@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+extern crate kernel;
+
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use kernel::ata::{self, AtapiDrive, ATAPI_SECTOR_SIZE};
+use kernel::file_system::{self, File};
+use kernel::*;
+
+#[no_mangle]
+#[link_section = ".kernel_start"]
+extern "C" fn _start() -> ! {
+    arch::init();
+
+    // kernel_runner attaches an ISO 9660 image of CDROM_FILES as a CD-ROM
+    let drives: Vec<AtapiDrive> = ata::atapi_drives().map(|(drive, _)| drive).collect();
+    assert_eq!(drives.len(), 1);
+    let (_, block_size) = drives[0].capacity().unwrap();
+    assert_eq!(block_size, ATAPI_SECTOR_SIZE);
+
+    // The primary volume descriptor follows the 16 sectors of the system area
+    let mut buffer = [0; ATAPI_SECTOR_SIZE];
+    drives[0].read_sectors(16, 1, &mut buffer).unwrap();
+    assert_eq!(buffer[..6], *b"\x01CD001");
+
+    let mut file = File::open("/cdrom/readme.txt").unwrap();
+    let mut content = [0; 64];
+    let len = file.read(&mut content).unwrap();
+    assert_eq!(content[..len], *b"RustOS reads CD-ROMs\n");
+
+    // Rock Ridge names are not limited to 8.3 uppercase
+    let files = file_system::read_dir("/cdrom").unwrap();
+    assert!(files
+        .iter()
+        .any(|file| file.get_path() == "/cdrom/a name longer than ISO 9660 allows.lisp"));
+
+    // The CD-ROM is read-only
+    assert!(File::create("/cdrom/new").is_none());
+    assert_eq!(file.write(b"rewritable"), None);
+
+    serial_println!("atapi: [OK]");
+    exit_qemu(QemuExitCode::Success)
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("atapi: [KO]");
+    exit_qemu(QemuExitCode::Failure)
+}
@@ -0,0 +1,46 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+extern crate kernel;
+
+use core::panic::PanicInfo;
+use kernel::file_system::{self, File};
+use kernel::*;
+
+const HELLO: &[u8] = b"Hello from the host\n";
+
+#[no_mangle]
+#[link_section = ".kernel_start"]
+extern "C" fn _start() -> ! {
+    arch::init();
+
+    // kernel_runner packs tests/fixtures/sample_files into the data partition
+    let mut file = File::open("hello.txt").unwrap();
+    let mut buffer = [0; 64];
+    assert_eq!(file.read(&mut buffer), Some(HELLO.len()));
+    assert_eq!(buffer[..HELLO.len()], *HELLO);
+    assert!(File::open("scripts/double.lisp").is_some());
+    let scripts = file_system::read_dir("/scripts").unwrap();
+    assert_eq!(scripts[0].get_path(), "scripts/double.lisp");
+
+    // The rest of the partition is free space
+    let mut file = File::create("new").unwrap();
+    assert_eq!(file.write(&[1; 1024]), Some(1024));
+
+    // Directories are packed too
+    let files = file_system::read_dir("/").unwrap();
+    assert_eq!(files.len(), 3);
+    assert!(files.iter().any(|file| file.is_directory()));
+
+    serial_println!("fixtures: [OK]");
+    exit_qemu(QemuExitCode::Success)
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("fixtures: [KO]");
+    exit_qemu(QemuExitCode::Failure)
+}
@@ -0,0 +1 @@
+(def! double (fn* (x) (* 2 x)))
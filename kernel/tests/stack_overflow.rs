@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+
+extern crate kernel;
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::ptr::read_volatile;
+use kernel::memory_manager::stack::KernelStack;
+use kernel::*;
+
+#[no_mangle]
+#[link_section = ".kernel_start"]
+extern "C" fn _start() -> ! {
+    arch::switch_stack(KernelStack::new(), overflow)
+}
+
+fn overflow() -> ! {
+    arch::init();
+    recurse(0);
+
+    serial_println!("Stack overflow: [KO]");
+    exit_qemu(QemuExitCode::Failure)
+}
+
+#[allow(unconditional_recursion)]
+fn recurse(depth: u64) -> u64 {
+    // SAFETY: depth is a valid local
+    let depth = unsafe { read_volatile(&depth) };
+    recurse(depth + 1) + 1
+}
+
+/// Keeps the beginning of the panic message
+struct Message {
+    buffer: [u8; 128],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(self.buffer.len() - self.len);
+        self.buffer[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = Message {
+        buffer: [0; 128],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info);
+
+    let reported = message.buffer[..message.len]
+        .windows(b"stack overflow".len())
+        .any(|window| window == b"stack overflow");
+    if reported {
+        serial_println!("Stack overflow: [OK]");
+        exit_qemu(QemuExitCode::Success)
+    } else {
+        serial_println!("Stack overflow: [KO]");
+        exit_qemu(QemuExitCode::Failure)
+    }
+}
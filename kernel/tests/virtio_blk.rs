@@ -0,0 +1,58 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+extern crate kernel;
+
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use kernel::block_device::{self, BlockDevice, BlockError};
+use kernel::driver::virtio_blk::{self, VirtioDisk};
+use kernel::*;
+
+/// kernel_runner attaches a blank 1MiB legacy disk and a blank 2MiB modern disk
+const MIB_SECTORS: u64 = 2048;
+
+#[no_mangle]
+#[link_section = ".kernel_start"]
+extern "C" fn _start() -> ! {
+    arch::init();
+
+    let disks: Vec<VirtioDisk> = virtio_blk::disks().collect();
+    assert_eq!(disks.len(), 2);
+    assert_eq!(disks[0].sectors(), MIB_SECTORS);
+    assert_eq!(disks[1].sectors(), 2 * MIB_SECTORS);
+    let count = block_device::disks()
+        .iter()
+        .filter(|disk| disk.driver() == "virtio")
+        .count();
+    assert_eq!(count, 2);
+
+    for disk in &disks {
+        // Unaligned buffers spanning several pages and requests
+        let expected: Vec<u8> = (0..300 * 512 + 1).map(|val| (val % 251) as u8).collect();
+        disk.write_blocks(7, 300, &expected[1..]).unwrap();
+        disk.flush().unwrap();
+        let mut actual = alloc::vec![0; 300 * 512 + 1];
+        disk.read_blocks(7, 300, &mut actual[1..]).unwrap();
+        assert_eq!(actual[1..], expected[1..]);
+
+        let last = disk.block_count() as usize - 1;
+        disk.write_blocks(last, 1, &[42; 512]).unwrap();
+        assert_eq!(
+            disk.read_blocks(last, 2, &mut [0; 1024]),
+            Err(BlockError::OutOfRange)
+        );
+    }
+
+    serial_println!("virtio_blk: [OK]");
+    exit_qemu(QemuExitCode::Success)
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("virtio_blk: [KO]");
+    exit_qemu(QemuExitCode::Failure)
+}
@@ -1,21 +1,90 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{prelude::*, Seek, SeekFrom, Write};
 use std::ops::Add;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::process::{exit, Command};
+use std::time::UNIX_EPOCH;
 use std::{env, process};
 
+/// Header layout of the archives the kernel reads
+#[path = "../../kernel/src/file_system/ustar_layout.rs"]
+mod ustar_layout;
+
 const GDB: bool = false;
 const SECTOR_SIZE: usize = 512;
-const FS_SPACE: &[u8] = &[0; 100 * 512];
+/// Size of the data partition in KiB, unless given with `--fs-size`
+const FS_SIZE: u64 = 50;
 const QEMU_SUCCESS: i32 = 33;
+/// Offset of the partition entries in the MBR
+const PARTITION_TABLE: u64 = 446;
+/// Partition ID of the bootloader and kernel, "non-FS data"
+const BOOT_PARTITION_ID: u8 = 0xDA;
+/// Partition ID the kernel looks for to mount its file system
+const DATA_PARTITION_ID: u8 = 0x7F;
+/// Partition ID of the initrd, the kernel mounts it instead of the data partition
+const INITRD_PARTITION_ID: u8 = 0x7E;
+const MIB: u64 = 1024 * 1024;
+/// Size in MiB of the blank disks attached after the boot disk, by test name
+/// The `n`th size is the one of the disk attached at IDE index `n`
+const EXTRA_DISKS: &[(&str, &[u64])] = &[
+    ("ata_drives", &[1, 2, 3]),
+    ("ata_benchmark", &[64]),
+    ("ahci", &[1]),
+];
+/// Tests run on a q35 machine, its disks are attached to an AHCI controller instead of IDE
+const Q35_TESTS: &[&str] = &["ahci"];
+/// Size in MiB and interface of the blank virtio-blk disks attached, by test name
+const VIRTIO_DISKS: &[(&str, &[(u64, VirtioInterface)])] = &[(
+    "virtio_blk",
+    &[(1, VirtioInterface::Legacy), (2, VirtioInterface::Modern)],
+)];
+
+/// Files of the CD-ROM image attached to a test, by test name
+const CDROM_FILES: &[(&str, &[(&str, &str)])] = &[(
+    "atapi",
+    &[
+        ("readme.txt", "RustOS reads CD-ROMs\n"),
+        ("a name longer than ISO 9660 allows.lisp", "(+ 1 2)"),
+    ],
+)];
+/// Environment variable holding the path of an ISO image to attach as a CD-ROM
+const CDROM_VAR: &str = "RUSTOS_CDROM";
+const ISO_SECTOR_SIZE: usize = 2048;
+/// Sectors of the CD-ROM images, after the 16 sectors of the system area
+const ISO_DESCRIPTOR: usize = 16;
+const ISO_L_PATH_TABLE: usize = 18;
+const ISO_M_PATH_TABLE: usize = 19;
+const ISO_ROOT: usize = 20;
+
+/// virtio-pci interface a virtio-blk disk exposes
+#[derive(Clone, Copy)]
+enum VirtioInterface {
+    Legacy,
+    Modern,
+}
+
+impl VirtioInterface {
+    /// Returns the properties of the virtio-blk-pci device enabling only this interface
+    fn qemu_properties(self) -> &'static str {
+        match self {
+            VirtioInterface::Legacy => "disable-legacy=off,disable-modern=on",
+            VirtioInterface::Modern => "disable-legacy=on,disable-modern=off",
+        }
+    }
+}
+
+/// Directory holding the fixtures packed in the data partition of tests
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../kernel/tests/fixtures");
+/// Fixtures packed in the data partition, by test name
+const FS_FIXTURES: &[(&str, &str)] = &[("fixtures", "sample_files")];
 
 fn main() {
-    let kernel = env::args().nth(1).expect("Not enough arguments");
-    let config = BuildConfig::new(kernel);
+    let config = BuildConfig::new(Args::parse());
 
     config.create_kernel_bin(&objcopy_path());
     config.create_image();
+    config.create_extra_disks();
 
     let status = config.run_qemu();
     if status != QEMU_SUCCESS {
@@ -23,23 +92,130 @@ fn main() {
     }
 }
 
+/// Arguments of the runner, the ones after the kernel are given after `--` to cargo run
+struct Args {
+    kernel: String,
+    /// Directory to pack into the initrd
+    initrd: Option<PathBuf>,
+    /// Directory to pack into the data partition
+    fs: Option<PathBuf>,
+    /// Size of the data partition in KiB
+    fs_size: Option<u64>,
+}
+
+impl Args {
+    fn parse() -> Args {
+        let mut args = env::args().skip(1);
+        let mut parsed = Args {
+            kernel: args.next().unwrap_or_else(|| usage()),
+            initrd: None,
+            fs: None,
+            fs_size: None,
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--fs" => parsed.fs = Some(args.next().unwrap_or_else(|| usage()).into()),
+                "--fs-size" => {
+                    let size = args.next().and_then(|size| size.parse().ok());
+                    parsed.fs_size = Some(size.unwrap_or_else(|| usage()));
+                }
+                _ if parsed.initrd.is_none() && !arg.starts_with("--") => {
+                    parsed.initrd = Some(arg.into())
+                }
+                _ => usage(),
+            }
+        }
+        parsed
+    }
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: kernel_runner <kernel> [initrd directory] [--fs <directory>] [--fs-size <KiB>]"
+    );
+    process::exit(1);
+}
+
 struct BuildConfig {
     kernel: String,
     kernel_bin: String,
     image: String,
+    /// Path and size in MiB of every extra disk
+    extra_disks: Vec<(String, u64)>,
+    /// Path, size in MiB and interface of every virtio-blk disk
+    virtio_disks: Vec<(String, u64, VirtioInterface)>,
+    /// Path of the image attached as a CD-ROM
+    cdrom: Option<String>,
+    /// Files packed in the CD-ROM image, if the runner creates it
+    cdrom_files: &'static [(&'static str, &'static str)],
+    /// Directory packed into the initrd
+    initrd: Option<PathBuf>,
+    /// Directory packed into the data partition, tests get the one of `FS_FIXTURES`
+    fs: Option<PathBuf>,
+    fs_sectors: u64,
+    q35: bool,
     is_test: bool,
 }
 
 impl BuildConfig {
-    fn new(kernel: String) -> BuildConfig {
+    fn new(args: Args) -> BuildConfig {
+        let kernel = args.kernel;
         let kernel_bin = kernel.clone().add(".bin");
         let image = kernel.clone().add(".img");
-        let is_test = Path::new(&kernel).parent().unwrap().ends_with("deps");
+        let path = Path::new(&kernel);
+        let is_test = path.parent().unwrap().ends_with("deps");
+
+        // Test binaries are named after the test followed by a hash
+        let name = path.file_name().unwrap().to_string_lossy();
+        let is_named = |test: &str| is_test && name.starts_with(&format!("{}-", test));
+        let sizes = EXTRA_DISKS
+            .iter()
+            .find(|(test, _)| is_named(test))
+            .map_or(&[][..], |(_, sizes)| *sizes);
+        let extra_disks = sizes
+            .iter()
+            .enumerate()
+            .map(|(index, size)| (format!("{}-{}.img", kernel, index + 1), *size))
+            .collect();
+        let virtio_disks = VIRTIO_DISKS
+            .iter()
+            .find(|(test, _)| is_named(test))
+            .map_or(&[][..], |(_, disks)| *disks)
+            .iter()
+            .enumerate()
+            .map(|(index, &(size, interface))| {
+                (format!("{}-virtio{}.img", kernel, index), size, interface)
+            })
+            .collect();
+        let cdrom_files = CDROM_FILES
+            .iter()
+            .find(|(test, _)| is_named(test))
+            .map_or(&[][..], |(_, files)| *files);
+        let cdrom = if cdrom_files.is_empty() {
+            env::var(CDROM_VAR).ok()
+        } else {
+            Some(format!("{}.iso", kernel))
+        };
+        let q35 = Q35_TESTS.iter().any(|test| is_named(test));
+        let fixtures = FS_FIXTURES
+            .iter()
+            .find(|(test, _)| is_named(test))
+            .map(|(_, fixtures)| Path::new(FIXTURES).join(fixtures));
+        let fs = args.fs.or(fixtures);
+        let fs_sectors = args.fs_size.unwrap_or(FS_SIZE) * 1024 / SECTOR_SIZE as u64;
 
         BuildConfig {
             kernel,
             kernel_bin,
             image,
+            extra_disks,
+            virtio_disks,
+            cdrom,
+            cdrom_files,
+            initrd: args.initrd,
+            fs,
+            fs_sectors,
+            q35,
             is_test,
         }
     }
@@ -79,23 +255,97 @@ impl BuildConfig {
         image_file.write_all(&bootloader).unwrap();
         image_file.write_all(&kernel).unwrap();
         pad_to_sector(&mut image_file);
+        let data_start = image_file.stream_position().unwrap() / SECTOR_SIZE as u64;
 
-        // Add space for files to be written to
+        // The data partition holds the packed files followed by free space to write to
+        let mut fs = self.fs.as_deref().map_or_else(Vec::new, pack_ustar);
+        let fs_len = self.fs_sectors as usize * SECTOR_SIZE;
+        if fs.len() > fs_len {
+            eprintln!(
+                "The files take {} KiB, more than the {} KiB of the data partition, see --fs-size",
+                fs.len() / 1024,
+                fs_len / 1024
+            );
+            process::exit(1);
+        }
+        fs.resize(fs_len, 0);
         image_file
-            .write_all(FS_SPACE)
+            .write_all(&fs)
             .expect("Could not add space for FS");
+
+        // The boot area starts after the MBR, the data partition right after the kernel
+        let fs_sectors = self.fs_sectors;
+        let mut partition_table = Vec::new();
+        partition_table.extend(partition_entry(true, BOOT_PARTITION_ID, 1, data_start - 1));
+        partition_table.extend(partition_entry(
+            false,
+            DATA_PARTITION_ID,
+            data_start,
+            fs_sectors,
+        ));
+        if let Some(dir) = &self.initrd {
+            let initrd = pack_ustar(dir);
+            image_file.write_all(&initrd).expect("Could not add initrd");
+            partition_table.extend(partition_entry(
+                false,
+                INITRD_PARTITION_ID,
+                data_start + fs_sectors,
+                (initrd.len() / SECTOR_SIZE) as u64,
+            ));
+        }
+        image_file
+            .seek(SeekFrom::Start(PARTITION_TABLE))
+            .and_then(|_| image_file.write_all(&partition_table))
+            .expect("Could not write partition table");
+    }
+
+    fn create_extra_disks(&self) {
+        let extra_disks = self.extra_disks.iter().map(|(disk, size)| (disk, size));
+        let virtio_disks = self.virtio_disks.iter().map(|(disk, size, _)| (disk, size));
+        for (disk, size) in extra_disks.chain(virtio_disks) {
+            File::create(disk)
+                .and_then(|file| file.set_len(size * MIB))
+                .expect("Could not create extra disk");
+        }
+        if !self.cdrom_files.is_empty() {
+            let cdrom = self.cdrom.as_ref().unwrap();
+            fs::write(cdrom, pack_iso(self.cdrom_files)).expect("Could not create CD-ROM image");
+        }
     }
 
     fn run_qemu(&self) -> i32 {
         let mut cmd = Command::new("qemu-system-x86_64");
         cmd.arg("-drive")
-            .arg(format!("file={},format=raw", &self.image))
+            .arg(format!("file={},format=raw,index=0", &self.image))
             .arg("-boot")
             .arg("c")
             .arg("-device")
             .arg("isa-debug-exit,iobase=0xf4,iosize=0x04")
             .arg("-serial")
             .arg("stdio");
+        for (index, (disk, _)) in self.extra_disks.iter().enumerate() {
+            cmd.arg("-drive")
+                .arg(format!("file={},format=raw,index={}", disk, index + 1));
+        }
+        for (index, (disk, _, interface)) in self.virtio_disks.iter().enumerate() {
+            cmd.arg("-drive")
+                .arg(format!(
+                    "file={},format=raw,if=none,id=virtio{}",
+                    disk, index
+                ))
+                .arg("-device")
+                .arg(format!(
+                    "virtio-blk-pci,drive=virtio{},{}",
+                    index,
+                    interface.qemu_properties()
+                ));
+        }
+        if let Some(cdrom) = &self.cdrom {
+            cmd.arg("-cdrom").arg(cdrom);
+        }
+        if self.q35 {
+            cmd.arg("-machine").arg("q35");
+        }
         if self.is_test {
             cmd.arg("-display").arg("none");
         }
@@ -127,6 +377,241 @@ fn objcopy_path() -> PathBuf {
     objcopy
 }
 
+/// Returns an MBR partition entry addressed by LBA only
+fn partition_entry(bootable: bool, id: u8, start: u64, sectors: u64) -> [u8; 16] {
+    let mut entry = [0; 16];
+    entry[0] = if bootable { 0x80 } else { 0 };
+    // CHS addresses are out of range, so they are set to their maximum
+    entry[1..4].copy_from_slice(&[0xFF; 3]);
+    entry[4] = id;
+    entry[5..8].copy_from_slice(&[0xFF; 3]);
+    entry[8..12].copy_from_slice(&(start as u32).to_le_bytes());
+    entry[12..16].copy_from_slice(&(sectors as u32).to_le_bytes());
+    entry
+}
+
+/// Packs the directories and regular files under `dir` in a POSIX USTAR archive,
+/// the initrd and file system format
+/// Files are named after their path relative to `dir`
+fn pack_ustar(dir: &Path) -> Vec<u8> {
+    let mut files = Vec::new();
+    find_files(dir, &mut files);
+    files.sort();
+
+    let mut archive = Vec::new();
+    for path in files {
+        let name = path.strip_prefix(dir).unwrap().to_string_lossy();
+        // Directories have no data and their name ends with a '/'
+        let (name, type_flag, data) = if path.is_dir() {
+            (format!("{}/", name), ustar_layout::DIRECTORY, Vec::new())
+        } else {
+            let data = fs::read(&path).expect("Could not read file to pack");
+            (name.into_owned(), ustar_layout::FILE, data)
+        };
+        let metadata = fs::metadata(&path).unwrap();
+        let last_modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |duration| duration.as_secs());
+
+        archive.extend_from_slice(&ustar_header(
+            &name,
+            type_flag,
+            metadata.permissions().mode() as u64 & 0o777,
+            data.len() as u64,
+            last_modified,
+        ));
+        archive.extend_from_slice(&data);
+        let padding = (ustar_layout::BLOCK_SIZE - archive.len() % ustar_layout::BLOCK_SIZE)
+            % ustar_layout::BLOCK_SIZE;
+        archive.resize(archive.len() + padding, 0);
+    }
+    // Archives end with two empty blocks
+    archive.resize(archive.len() + 2 * ustar_layout::BLOCK_SIZE, 0);
+    archive
+}
+
+/// Returns the USTAR header of an entry, paths longer than a name are split on a '/'
+fn ustar_header(
+    path: &str,
+    type_flag: u8,
+    mode: u64,
+    size: u64,
+    last_modified: u64,
+) -> [u8; ustar_layout::BLOCK_SIZE] {
+    use ustar_layout::*;
+
+    let (prefix, name) = if path.len() <= NAME.len {
+        ("", path)
+    } else {
+        let index = path
+            .match_indices('/')
+            .map(|(index, _)| index)
+            .find(|&index| index <= PREFIX.len && path.len() - index - 1 <= NAME.len)
+            .unwrap_or_else(|| panic!("{} is too long for a USTAR archive", path));
+        (&path[..index], &path[index + 1..])
+    };
+
+    let mut header = [0; BLOCK_SIZE];
+    let set = |header: &mut [u8], field: &Field, bytes: &[u8]| {
+        assert!(bytes.len() <= field.len);
+        header[field.offset..field.offset + bytes.len()].copy_from_slice(bytes)
+    };
+    // Numbers are zero padded octal, ended by a NUL
+    let octal = |len: usize, value: u64| {
+        let digits = format!("{:0width$o}\0", value, width = len - 1);
+        assert_eq!(
+            digits.len(),
+            len,
+            "{:o} does not fit in a USTAR field",
+            value
+        );
+        digits.into_bytes()
+    };
+    set(&mut header, &NAME, name.as_bytes());
+    set(&mut header, &PREFIX, prefix.as_bytes());
+    set(&mut header, &MODE, &octal(MODE.len, mode));
+    set(&mut header, &OWNER_ID, &octal(OWNER_ID.len, 0));
+    set(&mut header, &GROUP_ID, &octal(GROUP_ID.len, 0));
+    set(&mut header, &SIZE, &octal(SIZE.len, size));
+    set(
+        &mut header,
+        &LAST_MODIFIED,
+        &octal(LAST_MODIFIED.len, last_modified),
+    );
+    set(&mut header, &TYPE_FLAG, &[type_flag]);
+    set(&mut header, &MAGIC, POSIX_MAGIC);
+    set(&mut header, &VERSION, POSIX_VERSION);
+
+    // The checksum is computed with its own field filled with spaces,
+    // it is then written as 6 digits and a NUL, leaving the last space
+    set(&mut header, &CHECKSUM, &[b' '; 8]);
+    let checksum: u64 = header.iter().map(|&byte| byte as u64).sum();
+    set(&mut header, &CHECKSUM, &octal(CHECKSUM.len - 1, checksum));
+    header
+}
+
+/// Returns an ISO 9660 image holding `files` in its root directory
+/// The ISO 9660 names are made up, the real ones are stored in Rock Ridge NM entries
+fn pack_iso(files: &[(&str, &str)]) -> Vec<u8> {
+    let mut image = vec![0; (ISO_ROOT + 1) * ISO_SECTOR_SIZE];
+
+    // The SP entry tells the System Use Sharing Protocol is used, the ER entry that it is Rock Ridge
+    let mut extensions = vec![b'S', b'P', 7, 1, 0xBE, 0xEF, 0];
+    extensions.extend_from_slice(&[b'E', b'R', 8 + 10, 1, 10, 0, 0, 1]);
+    extensions.extend_from_slice(b"RRIP_1991A");
+    let mut root = iso_record(ISO_ROOT, ISO_SECTOR_SIZE, true, &[0], &extensions);
+    root.extend(iso_record(ISO_ROOT, ISO_SECTOR_SIZE, true, &[1], &[]));
+
+    for (index, (name, content)) in files.iter().enumerate() {
+        assert!(name.len() < 200, "{} is too long for a CD-ROM", name);
+        let mut nm = vec![b'N', b'M', 5 + name.len() as u8, 1, 0];
+        nm.extend_from_slice(name.as_bytes());
+        let iso_name = format!("F{}.;1", index);
+        let extent = image.len() / ISO_SECTOR_SIZE;
+        root.extend(iso_record(
+            extent,
+            content.len(),
+            false,
+            iso_name.as_bytes(),
+            &nm,
+        ));
+
+        image.extend_from_slice(content.as_bytes());
+        let padding = (ISO_SECTOR_SIZE - image.len() % ISO_SECTOR_SIZE) % ISO_SECTOR_SIZE;
+        image.resize(image.len() + padding, 0);
+    }
+    assert!(root.len() <= ISO_SECTOR_SIZE, "Too many files for a CD-ROM");
+    let root_start = ISO_ROOT * ISO_SECTOR_SIZE;
+    image[root_start..root_start + root.len()].copy_from_slice(&root);
+
+    let sectors = image.len() / ISO_SECTOR_SIZE;
+    let descriptor = &mut image[ISO_DESCRIPTOR * ISO_SECTOR_SIZE..][..ISO_SECTOR_SIZE];
+    descriptor[0..7].copy_from_slice(b"\x01CD001\x01");
+    // Identifiers are padded with spaces, dates left unspecified
+    descriptor[8..72].fill(b' ');
+    descriptor[40..46].copy_from_slice(b"RUSTOS");
+    descriptor[80..88].copy_from_slice(&both_endian_u32(sectors as u32));
+    descriptor[120..124].copy_from_slice(&both_endian_u16(1));
+    descriptor[124..128].copy_from_slice(&both_endian_u16(1));
+    descriptor[128..132].copy_from_slice(&both_endian_u16(ISO_SECTOR_SIZE as u16));
+    descriptor[132..140].copy_from_slice(&both_endian_u32(10));
+    descriptor[140..144].copy_from_slice(&(ISO_L_PATH_TABLE as u32).to_le_bytes());
+    descriptor[148..152].copy_from_slice(&(ISO_M_PATH_TABLE as u32).to_be_bytes());
+    descriptor[156..190].copy_from_slice(&iso_record(ISO_ROOT, ISO_SECTOR_SIZE, true, &[0], &[]));
+    descriptor[190..813].fill(b' ');
+    for date in descriptor[813..881].chunks_mut(17) {
+        date[..16].fill(b'0');
+    }
+    descriptor[881] = 1;
+
+    let terminator = (ISO_DESCRIPTOR + 1) * ISO_SECTOR_SIZE;
+    image[terminator..terminator + 7].copy_from_slice(b"\xFFCD001\x01");
+
+    // The path tables only hold the root directory, in little and big endian
+    let root_sector = ISO_ROOT as u32;
+    let l_path_table = ISO_L_PATH_TABLE * ISO_SECTOR_SIZE;
+    image[l_path_table] = 1;
+    image[l_path_table + 2..l_path_table + 6].copy_from_slice(&root_sector.to_le_bytes());
+    image[l_path_table + 6..l_path_table + 8].copy_from_slice(&1u16.to_le_bytes());
+    let m_path_table = ISO_M_PATH_TABLE * ISO_SECTOR_SIZE;
+    image[m_path_table] = 1;
+    image[m_path_table + 2..m_path_table + 6].copy_from_slice(&root_sector.to_be_bytes());
+    image[m_path_table + 6..m_path_table + 8].copy_from_slice(&1u16.to_be_bytes());
+    image
+}
+
+/// Returns an ISO 9660 directory record followed by the system use entries `system_use`
+fn iso_record(
+    extent: usize,
+    size: usize,
+    directory: bool,
+    name: &[u8],
+    system_use: &[u8],
+) -> Vec<u8> {
+    // A padding byte keeps the system use area at an even offset
+    let mut record = vec![0; 33 + name.len() + (1 - name.len() % 2)];
+    record[2..10].copy_from_slice(&both_endian_u32(extent as u32));
+    record[10..18].copy_from_slice(&both_endian_u32(size as u32));
+    record[25] = if directory { 2 } else { 0 };
+    record[28..32].copy_from_slice(&both_endian_u16(1));
+    record[32] = name.len() as u8;
+    record[33..33 + name.len()].copy_from_slice(name);
+    record.extend_from_slice(system_use);
+    record.resize(record.len() + record.len() % 2, 0);
+    record[0] = record.len() as u8;
+    record
+}
+
+/// Returns `value` in little endian followed by big endian, as ISO 9660 stores numbers
+fn both_endian_u32(value: u32) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    bytes[..4].copy_from_slice(&value.to_le_bytes());
+    bytes[4..].copy_from_slice(&value.to_be_bytes());
+    bytes
+}
+
+fn both_endian_u16(value: u16) -> [u8; 4] {
+    let mut bytes = [0; 4];
+    bytes[..2].copy_from_slice(&value.to_le_bytes());
+    bytes[2..].copy_from_slice(&value.to_be_bytes());
+    bytes
+}
+
+/// Adds the directories and regular files under `dir` to `files`
+fn find_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).expect("Could not read directory to pack") {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.push(path.clone());
+            find_files(&path, files);
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+}
+
 fn pad_to_sector(target: &mut File) {
     let bytes_written = target.seek(SeekFrom::Current(0)).unwrap() as usize;
     let bytes_to_pad = SECTOR_SIZE - (bytes_written % SECTOR_SIZE);
@@ -134,3 +619,138 @@ fn pad_to_sector(target: &mut File) {
         .write_all(vec![0; bytes_to_pad].as_slice())
         .expect("Could not pad remaining bytes");
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::OsStr;
+
+    fn u32_le(bytes: &[u8]) -> usize {
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize
+    }
+
+    /// Returns the name, extent, size and system use area of the records of the root directory
+    fn root_records(image: &[u8]) -> Vec<(Vec<u8>, usize, usize, Vec<u8>)> {
+        let root = &image[ISO_ROOT * ISO_SECTOR_SIZE..][..ISO_SECTOR_SIZE];
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while root[offset] != 0 {
+            let record = &root[offset..offset + root[offset] as usize];
+            let name_len = record[32] as usize;
+            let system_use = 33 + name_len + (1 - name_len % 2);
+            records.push((
+                record[33..33 + name_len].to_vec(),
+                u32_le(&record[2..]),
+                u32_le(&record[10..]),
+                record[system_use..].to_vec(),
+            ));
+            offset += record.len();
+        }
+        records
+    }
+
+    #[test]
+    fn iso_descriptors() {
+        let image = pack_iso(&[("file", "content")]);
+        assert_eq!(image.len() % ISO_SECTOR_SIZE, 0);
+
+        let descriptor = &image[ISO_DESCRIPTOR * ISO_SECTOR_SIZE..][..ISO_SECTOR_SIZE];
+        assert_eq!(descriptor[..7], *b"\x01CD001\x01");
+        assert_eq!(u32_le(&descriptor[80..]), image.len() / ISO_SECTOR_SIZE);
+        assert_eq!(
+            descriptor[128..132],
+            both_endian_u16(ISO_SECTOR_SIZE as u16)
+        );
+        // The root record of the descriptor points to the root directory
+        assert_eq!(descriptor[156], 34);
+        assert_eq!(u32_le(&descriptor[158..]), ISO_ROOT);
+
+        let terminator = &image[(ISO_DESCRIPTOR + 1) * ISO_SECTOR_SIZE..][..ISO_SECTOR_SIZE];
+        assert_eq!(terminator[..7], *b"\xFFCD001\x01");
+        assert!(terminator[7..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn iso_root_directory() {
+        let long_name = "a".repeat(150);
+        let files = [
+            ("hello world.txt", "Hello\n"),
+            (&*long_name, ""),
+            ("b", "x"),
+        ];
+        let image = pack_iso(&files);
+        let records = root_records(&image);
+        assert_eq!(records.len(), 2 + files.len());
+
+        // The current directory starts the system use area of the root with the SP entry
+        let (ref name, extent, _, ref system_use) = records[0];
+        assert_eq!(*name, [0]);
+        assert_eq!(extent, ISO_ROOT);
+        assert_eq!(system_use[..7], [b'S', b'P', 7, 1, 0xBE, 0xEF, 0]);
+        assert_eq!(records[1].0, [1]);
+
+        for (&(name, content), &(_, extent, size, ref system_use)) in
+            files.iter().zip(&records[2..])
+        {
+            assert_eq!(system_use[..2], *b"NM");
+            assert_eq!(system_use[5..system_use[2] as usize], *name.as_bytes());
+            assert_eq!(size, content.len());
+            assert!(extent > ISO_ROOT);
+            assert_eq!(
+                image[extent * ISO_SECTOR_SIZE..][..size],
+                *content.as_bytes()
+            );
+        }
+    }
+
+    /// Runs tar with `args`, returns its output
+    fn tar(args: &[&OsStr]) -> String {
+        let output = Command::new("tar")
+            .args(args)
+            .output()
+            .expect("Could not run tar");
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn ustar_is_read_by_tar() {
+        let dir = env::temp_dir().join(format!("kernel_runner_ustar_{}", process::id()));
+        // The path of the nested file does not fit in a name and goes in the prefix too
+        let long_dir = format!("{}/{}", "a".repeat(60), "b".repeat(60));
+        fs::create_dir_all(dir.join(&long_dir)).unwrap();
+        fs::write(dir.join("file"), "content").unwrap();
+        fs::set_permissions(dir.join("file"), fs::Permissions::from_mode(0o640)).unwrap();
+        fs::write(dir.join(&long_dir).join("nested"), vec![7; 1000]).unwrap();
+        let archive = dir.with_extension("tar");
+        fs::write(&archive, pack_ustar(&dir)).unwrap();
+
+        let listing = tar(&["-tvf".as_ref(), archive.as_ref()]);
+        let content = tar(&["-xOf".as_ref(), archive.as_ref(), "file".as_ref()]);
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_file(&archive).unwrap();
+
+        let lines: Vec<&str> = listing.lines().collect();
+        let names: Vec<&str> = lines
+            .iter()
+            .map(|line| line.rsplit(' ').next().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                format!("{}/", "a".repeat(60)),
+                format!("{}/", long_dir),
+                format!("{}/nested", long_dir),
+                "file".into(),
+            ]
+        );
+        assert!(lines[0].starts_with('d'));
+        assert!(lines[2].contains(" 1000 "));
+        assert!(lines[3].starts_with("-rw-r-----"));
+        assert_eq!(content, "content");
+    }
+}